@@ -1,91 +1,48 @@
+// The hot-reload of the library uses the rest of the module, the dead code lints stay on there
+#[allow(dead_code)]
+#[path = "src/template.rs"]
+mod template;
+
 use std::borrow::Borrow;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io;
-use std::io::{BufReader, BufWriter};
+use std::fs::File;
+use std::io::BufWriter;
 use std::io::prelude::*;
-use std::os::unix;
-use std::path::{ Path, PathBuf};
+use std::path::Path;
 use std::process::exit;
-use thiserror::{Error};
-use serde_json::{from_reader, StreamDeserializer, Value};
-use serde::{Deserialize};
-use crate::BuildError::IOError;
+use crate::template::{ComponentCache, Template, TemplateError, TemplateTarget};
 
 const SHADERS_PATH: &'static str = "./src/shaders";
 
-fn write_section_title(file: &mut BufWriter<File>, title:&String) -> std::io::Result<()> {
-    return write!(file, "\n\
-        \n\
-        /////////////////////////////////////////////\n\
-        // {} \n\
-        /////////////////////////////////////////////\n\
-        \n\
-        ",title)
-}
-
-#[derive(Clone, Debug, Deserialize)]
-struct Template{
-    target: String,
-    components:Vec<Component>
-}
-
-#[derive(Clone, Debug, Deserialize)]
-struct Component{
-    title: String,
-    path: String
-}
-
-#[derive(Debug, Error)]
-enum BuildError {
-    #[error("Fail to open a file : {0} \n {1}")]
-    IOError(String, std::io::Error),
-    #[error("Error parsing the template \"{0}\": \n {1}")]
-    ParseError(PathBuf, serde_json::Error),
+fn build_shader(path:&Path, cache:&mut ComponentCache)-> Result<(),TemplateError>{
+    let template = Template::load(path)?;
+    for target in template.targets() {
+        build_target(&template, &target, cache)?;
+    }
+    Ok(())
 }
 
-fn build_shader(path:&Path)-> Result<(),BuildError>{
-    let template_file = match File::open(path) {
-        Ok(ok) => ok,
-        Err(err) => {
-            return Err(BuildError::IOError("".into(),err))
-        }
-    };
-    let buf_read = BufReader::new(template_file);
-    let template: Template = match from_reader(buf_read) {
-        Ok(ok) =>ok,
-        Err(err) => {
-            return Err(BuildError::ParseError(path.to_path_buf(), err));
-        }
-    };
+fn build_target(template:&Template, target:&TemplateTarget, cache:&mut ComponentCache)-> Result<(),TemplateError>{
+    let shader = template.assemble_target(Path::new("."), cache, target)?;
 
-    let target_path = Path::new(&template.target);
-    let mut target = match File::create(target_path) {
+    let target_path = Path::new(&target.target);
+    let target_file = match File::create(target_path) {
         Ok(ok) => {ok}
-        Err(err) => {return Err(BuildError::IOError(template.target,err))}
+        Err(err) => {return Err(TemplateError::IOError(target_path.to_path_buf(),err))}
     };
-    let mut target = BufWriter::new(target);
-    for component in template.components {
-        let path = component.path;
-        if let Err(err) = write_section_title(&mut target, &component.title) {
-            return Err(IOError(path.into(),err));
-        }
-        let component_file = match File::open(path.clone()) {
-            Ok(ok) => {ok}
-            Err(err) => {
-                return Err(BuildError::IOError(path,err))
-            }
-        };
-        let mut reader = BufReader::new(component_file);
-        if let Err(err)= io::copy(&mut reader, &mut target){
-            return Err(IOError("".into(),err));
-        }
+    let mut target_file = BufWriter::new(target_file);
+    if let Err(err) = target_file.write_all(shader.source.as_bytes()) {
+        return Err(TemplateError::IOError(target_path.to_path_buf(),err));
     }
-    Ok(())
+    shader.write_line_map(Path::new(&format!("{}.map", target.target)))?;
+
+    // Report shader errors at build time rather than at create_shader_module
+    shader.validate()
 }
 
 fn main() {
     println!("cargo:rerun-if-changed=src/shaders/");
+    println!("cargo:rerun-if-changed=src/template.rs");
 
     let shader_path = Path::new(SHADERS_PATH);
     if !shader_path.is_dir() || !shader_path.exists(){
@@ -94,6 +51,7 @@ fn main() {
     }
 
     let mut failed = false;
+    let mut cache = ComponentCache::default();
     for entry in  fs::read_dir(shader_path).unwrap(){
         let entry = match entry {
             Ok(ok) => ok,
@@ -109,7 +67,10 @@ fn main() {
             continue;
         }
 
-        if let Err(err) = build_shader(entry.borrow()) {
+        if let Err(err) = build_shader(entry.borrow(), &mut cache) {
+            for line in err.to_string().lines() {
+                println!("cargo:warning={}", line);
+            }
             eprintln!("{}", err);
             failed = true;
         }
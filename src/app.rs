@@ -1,22 +1,20 @@
 use std::f32::consts::PI;
 use std::time::Duration;
 use pollster::block_on;
-use wgpu::{AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindingResource, Buffer, BufferUsages, ComputePassDescriptor, ComputePipeline, Device, Extent3d, FilterMode, IndexFormat, PipelineLayoutDescriptor, Queue, RenderPassDescriptor, RenderPipeline, RenderPipelineDescriptor, SamplerDescriptor, ShaderModuleDescriptor, Surface, SurfaceConfiguration, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor, TextureViewDimension, VertexBufferLayout};
+use wgpu::{AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor, BindingResource, Buffer, BufferUsages, ComputePassDescriptor, ComputePipeline, Device, ErrorFilter, Extent3d, FilterMode, IndexFormat, PipelineLayout, PipelineLayoutDescriptor, Queue, RenderPassDescriptor, RenderPipeline, RenderPipelineDescriptor, SamplerDescriptor, ShaderModuleDescriptor, Surface, SurfaceConfiguration, TextureDescriptor, Texture, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor, TextureViewDimension, VertexBufferLayout};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use winit::dpi::PhysicalSize;
 use winit::event::WindowEvent;
 use winit::window::Window;
 use crate::camera::CameraManager;
-use crate::color::Color;
-use crate::shapes::{cuboid, ShapeCollection, ShapeProperties};
-use crate::shapes::sphere::Sphere;
-use rand::SeedableRng;
-use rand::distributions::{Distribution, Uniform};
-use crate::shapes::composit::CompositDescriptor;
-use crate::shapes::cuboid::Cuboid;
-
-const WORKGROUP_SIZE_X: u32 = 16;
-const WORKGROUP_SIZE_Y: u32 = 16;
+use crate::light::LightManager;
+use crate::scene::Scene;
+use crate::shader::{RAYMARCHER_SHADER, ShaderError, ShaderReloader};
+use crate::shapes::{cuboid, ShapeCollection};
+use crate::shapes::registry::PrimitiveRegistry;
+
+pub(crate) const WORKGROUP_SIZE_X: u32 = 16;
+pub(crate) const WORKGROUP_SIZE_Y: u32 = 16;
 const TARGET_TEXTURE_X: u32 = 512;
 const TARGET_TEXTURE_Y: u32 = 512;
 
@@ -25,9 +23,10 @@ pub struct AppState {
     device: Device,
     queue: Queue,
     config: SurfaceConfiguration,
-    pub(crate) size: PhysicalSize<u32>,
+    pub size: PhysicalSize<u32>,
 
     render_pipeline: ComputePipeline,
+    render_pipeline_layout: PipelineLayout,
     copy_pipeline: RenderPipeline,
 
     indices_buffer:Buffer,
@@ -37,11 +36,21 @@ pub struct AppState {
     copied_texture_bind_group: BindGroup,
 
     shape_collection: ShapeCollection,
-    camera_manager: CameraManager
+    camera_manager: CameraManager,
+    light_manager: LightManager,
+    camera_distance: f32,
+    primitive_registry: PrimitiveRegistry,
+    // Only in dev mode
+    shader_reloader: Option<ShaderReloader>
 }
 
 impl AppState {
-    pub async fn new(window: &Window) -> Self {
+    pub async fn new(window: &Window, scene: &Scene, dev: bool) -> Self {
+        Self::with_registry(window, scene, dev, PrimitiveRegistry::builtin()).await
+    }
+
+    /// Viewer able to draw the primitives of `registry`, the shader reloaded in dev mode included
+    pub async fn with_registry(window: &Window, scene: &Scene, dev: bool, primitive_registry: PrimitiveRegistry) -> Self {
         println!("Start");
         // Getting the size
         let size = window.inner_size();
@@ -51,7 +60,8 @@ impl AppState {
         println!("WGPU Initiated");
 
         // Defining and setting up the render pipeline
-        let (render_pipeline, target_texture_bind_group_layout) = Self::init_render_pipeline(&device);
+        let source = primitive_registry.shader_source(RAYMARCHER_SHADER).expect("The ray marcher has no primitive_distance function");
+        let (render_pipeline, render_pipeline_layout, target_texture_bind_group_layout) = Self::init_render_pipeline(&device, &source);
         println!("Render pipeline created");
 
         // Defining and setting up the pipeline that display the result of the render pipeline
@@ -63,19 +73,7 @@ impl AppState {
         println!("Copy buffer created");
 
         // Create texture to render to
-        let target_texture = device.create_texture(&TextureDescriptor{
-            label: Some("Target texture"),
-            size: Extent3d{
-                width: TARGET_TEXTURE_X,
-                height: TARGET_TEXTURE_Y,
-                depth_or_array_layers: 1
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: TextureDimension::D2,
-            format: TextureFormat::Rgba8Unorm,
-            usage: TextureUsages::TEXTURE_BINDING|TextureUsages::STORAGE_BINDING
-        });
+        let target_texture = Self::init_target_texture(&device, TARGET_TEXTURE_X, TARGET_TEXTURE_Y, TextureUsages::TEXTURE_BINDING);
 
         let write_view = target_texture.create_view(&TextureViewDescriptor{
             label: Some("Target Write View"),
@@ -131,52 +129,23 @@ impl AppState {
         });
 
 
-        let mut rng = rand_pcg::Pcg64::seed_from_u64(42);
         let mut shape_collection = ShapeCollection::new(&device);
-        let uniform = Uniform::new(0.0, 0.95);
-        for _ in 0..0 {
-            shape_collection.add_sphere(Sphere::new_rand([-20.0, -20.0, -20.0], [20.0, 20.0, 20.0], 0.1, 2.0), ShapeProperties{
-                color:Color::random(),
-                visible:true,
-                reflectivity:uniform.sample(&mut rng)
-            });
+        // Scenes are checked when they are parsed, only the shapes that fit in the shader are added otherwise
+        if let Err(err) = scene.build_shapes(&mut shape_collection) {
+            eprintln!("{}", err);
         }
-
-        let props = ShapeProperties{
-            color:Color(1.0,1.0,1.0),
-            visible:false,
-            reflectivity:0.0
-        };
-        let desc = CompositDescriptor::UNION(
-            Box::new(CompositDescriptor::DIFFERENCE(
-                Box::new(CompositDescriptor::SPHERE(Sphere::new([0.0,0.0,0.0],1.0), props.clone())),
-                Box::new(CompositDescriptor::CUBOID(Cuboid::new([0.0,0.0,0.0],[1.5,1.5,1.5], [0.0,0.0,0.0]),props.clone()))
-            )),
-            Box::new(CompositDescriptor::SPHERE(Sphere::new([0.0,0.0,0.0],0.5), props.clone()))
-        );/*
-        let desc = CompositDescriptor::DIFFERENCE(
-            Box::new(CompositDescriptor::SPHERE(Sphere::new([0.0,0.0,0.0],1.0), props.clone())),
-            Box::new(CompositDescriptor::CUBOID(Cuboid::new([0.0,0.0,0.0],[1.5,1.5,1.5], [0.0,0.0,0.0]),props.clone()))
-        );*//*
-        let desc = CompositDescriptor::BLEND(
-            Box::new(CompositDescriptor::SPHERE(Sphere::new([1.7,0.0,0.0],2.0), props.clone())),
-            Box::new(CompositDescriptor::SPHERE(Sphere::new([-1.7,0.0,0.0],2.0), props.clone())),
-            2.0
-        );*/
-
-        shape_collection.create_composite(&desc);
-        /*shape_collection.add_cube(Cuboid::new([0.0,0.0,0.0], [1.0,1.0,1.0], [0.0,0.0,0.0]), ShapeProperties{
-            color: Color::random(),
-            reflectivity: 0.0,
-            visible: true
-        });*/
-        shape_collection.update_buffers(&queue);
+        shape_collection.update_buffers(&device, &queue);
 
         let mut camera_manager = CameraManager::new(&device,size.clone());
-        camera_manager.set_angle(PI / 6.0);
-        camera_manager.set_position(-camera_manager.forward() * -1000.0);
+        scene.apply_camera(&mut camera_manager);
         camera_manager.update_buffers(&queue);
 
+        let mut light_manager = LightManager::new(&device);
+        if let Err(err) = light_manager.set_lights(&scene.lights()) {
+            eprintln!("{}", err);
+        }
+        light_manager.update_buffers(&queue);
+
         Self {
             surface,
             device,
@@ -185,6 +154,7 @@ impl AppState {
             size,
 
             render_pipeline,
+            render_pipeline_layout,
             copy_pipeline,
 
             indices_buffer,
@@ -194,11 +164,57 @@ impl AppState {
             copied_texture_bind_group,
 
             shape_collection,
-            camera_manager
+            camera_manager,
+            light_manager,
+            camera_distance: scene.camera.distance,
+            primitive_registry,
+            shader_reloader: if dev { Some(ShaderReloader::new()) } else { None }
+        }
+    }
+
+    /// Replace the shapes and the lights, the camera keeps orbiting from its current angle
+    pub fn set_scene(&mut self, scene: &Scene) {
+        self.shape_collection.clear();
+        if let Err(err) = scene.build_shapes(&mut self.shape_collection) {
+            eprintln!("{}", err);
+        }
+        self.shape_collection.update_buffers(&self.device, &self.queue);
+        if let Err(err) = self.light_manager.set_lights(&scene.lights()) {
+            eprintln!("{}", err);
         }
+        self.camera_manager.set_screen_depth(scene.camera.screen_depth);
+        self.camera_distance = scene.camera.distance;
     }
 
-    pub(crate) fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+    /// In dev mode, rebuild the ray marcher pipeline when its shader components change.
+    /// The last working pipeline is kept if the new shader is invalid.
+    pub(crate) fn reload_shader(&mut self) {
+        let source = match self.shader_reloader.as_mut().and_then(|reloader| reloader.poll()) {
+            Some(Ok(source)) => match self.primitive_registry.shader_source(&source) {
+                Ok(ok) => ok,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    return;
+                }
+            },
+            Some(Err(err)) => {
+                eprintln!("{}", err);
+                return;
+            }
+            None => return
+        };
+
+        self.device.push_error_scope(ErrorFilter::Validation);
+        let render_pipeline = Self::create_render_pipeline(&self.device, &self.render_pipeline_layout, &source);
+        if let Some(err) = block_on(self.device.pop_error_scope()) {
+            eprintln!("{}", ShaderError::PipelineError(err.to_string()));
+            return;
+        }
+        self.render_pipeline = render_pipeline;
+        println!("Shader reloaded");
+    }
+
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
@@ -209,23 +225,23 @@ impl AppState {
         }
     }
 
-    pub(crate) fn input(&mut self, _event: &WindowEvent) -> bool {
+    pub fn input(&mut self, _event: &WindowEvent) -> bool {
         false
     }
 
 
-    pub(crate) fn update(&mut self, delta_t:Duration) {
+    pub fn update(&mut self, delta_t:Duration) {
+        self.reload_shader();
         println!("delta t : {}",delta_t.as_millis());
 
         //rotate the camera
         let angle = self.camera_manager.angle();
         let rotation_speed = 0.2*PI;
-        self.camera_manager.set_angle(angle+rotation_speed*delta_t.as_secs_f32() + if angle > 2.0 * PI { -2.0 * PI } else { 0.0 });
-        self.camera_manager.set_position(self.camera_manager.forward() * -12.0);
+        self.camera_manager.orbit(angle+rotation_speed*delta_t.as_secs_f32() + if angle > 2.0 * PI { -2.0 * PI } else { 0.0 }, self.camera_distance);
         println!("Forward : {}; Up : {}; Right : {}", self.camera_manager.forward(), self.camera_manager.up(), self.camera_manager.right());
     }
 
-    pub(crate) fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+    pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         //Surface texture
         let output =  match self.surface.get_current_texture() {
             Ok(frame) => frame,
@@ -237,7 +253,9 @@ impl AppState {
             }
         };
 
+        self.shape_collection.update_buffers(&self.device, &self.queue);
         self.camera_manager.update_buffers(&self.queue);
+        self.light_manager.update_buffers(&self.queue);
 
         //Setup
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
@@ -261,6 +279,7 @@ impl AppState {
             compute_pass.set_bind_group(0,&self.target_texture_bind_group,&[]);
             compute_pass.set_bind_group(1, self.shape_collection.bind_group(),&[]);
             compute_pass.set_bind_group(2, self.camera_manager.bind_group(),&[]);
+            compute_pass.set_bind_group(3, self.light_manager.bind_group(),&[]);
             compute_pass.dispatch(TARGET_TEXTURE_X/WORKGROUP_SIZE_X, TARGET_TEXTURE_Y/WORKGROUP_SIZE_Y, 1)
 
         }
@@ -319,7 +338,8 @@ impl AppState {
         (surface, device, queue, config)
     }
 
-    fn init_render_pipeline(device:&Device) -> (ComputePipeline, BindGroupLayout) {
+    /// Ray marcher pipeline compiled from `source`, along with its layout and the layout of the target texture
+    pub(crate) fn init_render_pipeline(device:&Device, source:&str) -> (ComputePipeline, PipelineLayout, BindGroupLayout) {
         let target_texture_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor{
                 label: Some("Target Texture Group Layout"),
                 entries: &[
@@ -339,26 +359,50 @@ impl AppState {
 
         let shapes_bind_group = ShapeCollection::bind_group_layout(&device);
         let camera_bind_group = CameraManager::bind_group_layout(&device);
+        let lights_bind_group = LightManager::bind_group_layout(&device);
 
         let compute_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor{
             label: Some("Ray Marcher Layout"),
-            bind_group_layouts: &[&target_texture_bind_group_layout, &shapes_bind_group, &camera_bind_group],
+            bind_group_layouts: &[&target_texture_bind_group_layout, &shapes_bind_group, &camera_bind_group, &lights_bind_group],
             push_constant_ranges: &[]
         });
 
+        let render_pipeline = Self::create_render_pipeline(device, &compute_pipeline_layout, source);
+
+        (render_pipeline, compute_pipeline_layout, target_texture_bind_group_layout)
+    }
+
+    /// Compile the ray marcher shader into a pipeline using `layout`
+    pub(crate) fn create_render_pipeline(device:&Device, layout:&PipelineLayout, source:&str) -> ComputePipeline {
         let compute_shader = device.create_shader_module(&ShaderModuleDescriptor{
             label: Some("Ray Marcher Shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("shaders/raymarcher2.wgsl").into())
+            source: wgpu::ShaderSource::Wgsl(source.into())
         });
 
-        let render_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor{
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor{
             label: Some("Ray Marcher Pipeline"),
-            layout: Some(&compute_pipeline_layout),
+            layout: Some(layout),
             module: &compute_shader,
             entry_point: "render"
-        });
+        })
+    }
 
-        (render_pipeline, target_texture_bind_group_layout)
+    /// Create the storage texture the ray marcher writes to.
+    /// `usage` is added to the storage binding usage so the texture can be sampled or copied out.
+    pub(crate) fn init_target_texture(device:&Device, width:u32, height:u32, usage:TextureUsages) -> Texture {
+        device.create_texture(&TextureDescriptor{
+            label: Some("Target texture"),
+            size: Extent3d{
+                width,
+                height,
+                depth_or_array_layers: 1
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::STORAGE_BINDING|usage
+        })
     }
 
     fn init_copy_pipeline(device:&Device, config: &SurfaceConfiguration)->(RenderPipeline, BindGroupLayout) {
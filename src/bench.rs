@@ -0,0 +1,131 @@
+//! Benchmarks of the `bench` subcommand.
+//!
+//! Every upload case modifies the shape collection the same way before each iteration and times
+//! [`HeadlessRenderer::upload_shapes`], the writes it makes come from [`ShapeCollection::pending_upload`].
+//! With `--render` it times the frames of a scene instead, interpreting the shape buffers and then
+//! with the shader specialized for the scene by [`HeadlessRenderer::specialize`].
+
+use std::time::{Duration, Instant};
+use crate::cli::{BenchArgs, RenderError};
+use crate::color::Color;
+use crate::headless::{HeadlessError, HeadlessRenderer};
+use crate::scene::Scene;
+use crate::shapes::{ShapeCollection, ShapeHandle, ShapeProperties};
+use crate::shapes::buffer::PendingUpload;
+use crate::shapes::sphere::Sphere;
+
+// Step between the spheres of the scattered cases, prime so that it visits every sphere
+const SCATTER_STRIDE: usize = 7919;
+// Width and height of the frames of the render cases
+const RENDER_SIZE: u32 = 512;
+
+fn random_sphere() -> Sphere {
+    Sphere::new_rand([-10.0;3], [10.0;3], 0.1, 0.5)
+}
+
+fn print_case(name: &str, pending: PendingUpload, time: Duration) {
+    println!("{:<36} {:>8} {:>12} {:>10.3}", name, pending.writes, pending.bytes, time.as_secs_f64() * 1000.0);
+}
+
+/// Run `modify` then time the upload, `iterations` times, and print the mean
+fn measure(renderer: &mut HeadlessRenderer, name: &str, iterations: u32, mut modify: impl FnMut(&mut ShapeCollection, usize)) {
+    let mut pending = PendingUpload::default();
+    let mut total = Duration::ZERO;
+    for iteration in 0..iterations as usize {
+        modify(renderer.shape_collection(), iteration);
+        pending = renderer.shape_collection().pending_upload();
+        let start = Instant::now();
+        renderer.upload_shapes();
+        total += start.elapsed();
+    }
+    print_case(name, pending, total / iterations);
+}
+
+fn print_frame_case(name: &str, time: Duration) {
+    println!("{:<36} {:>10.3}", name, time.as_secs_f64() * 1000.0);
+}
+
+/// Mean time of `iterations` frames, after a first frame that isn't measured
+async fn measure_frames(renderer: &mut HeadlessRenderer, iterations: u32) -> Result<Duration, HeadlessError> {
+    renderer.render().await?;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        renderer.render().await?;
+    }
+    Ok(start.elapsed() / iterations)
+}
+
+async fn bench_render(args: &BenchArgs) -> Result<(), RenderError> {
+    let scene = match &args.scene {
+        Some(path) => Scene::load(path)?,
+        None => Scene::demo()
+    };
+    let mut renderer = HeadlessRenderer::new(RENDER_SIZE, RENDER_SIZE, args.fallback).await?;
+    scene.build_shapes(renderer.shape_collection())?;
+    scene.apply_camera(renderer.camera_manager());
+    renderer.light_manager().set_lights(&scene.lights())?;
+
+    println!("{} shapes, {}x{} frames, mean of {} frames", renderer.shape_collection().len(), RENDER_SIZE, RENDER_SIZE, args.iterations);
+    println!("{:<36} {:>10}", "case", "ms");
+    print_frame_case("interpreter", measure_frames(&mut renderer, args.iterations).await?);
+
+    // Drivers may only compile the shader when it is first dispatched
+    let start = Instant::now();
+    renderer.specialize().await?;
+    renderer.render().await?;
+    print_frame_case("specialization and first frame", start.elapsed());
+    print_frame_case("specialized", measure_frames(&mut renderer, args.iterations).await?);
+    Ok(())
+}
+
+pub async fn bench(args: &BenchArgs) -> Result<(), RenderError> {
+    if args.render {
+        return bench_render(args).await;
+    }
+    let mut renderer = HeadlessRenderer::new(1, 1, args.fallback).await?;
+    let props = ShapeProperties{ color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: true };
+    let shapes = args.shapes as usize;
+    let updates = args.updates as usize;
+
+    let mut handles: Vec<ShapeHandle> = (0..shapes)
+        .map(|_| renderer.shape_collection().add_sphere(random_sphere(), props))
+        .collect();
+
+    println!("{} spheres, mean of {} uploads", shapes, args.iterations);
+    println!("{:<36} {:>8} {:>12} {:>10}", "case", "writes", "bytes", "ms");
+
+    // Only measured once, the buffers are allocated on the first upload
+    let pending = renderer.shape_collection().pending_upload();
+    let start = Instant::now();
+    renderer.upload_shapes();
+    print_case("first upload", pending, start.elapsed());
+
+    measure(&mut renderer, "nothing changed", args.iterations, |_, _| {});
+    measure(&mut renderer, "whole buffers", args.iterations, |collection, _| collection.mark_all_dirty());
+    measure(&mut renderer, &format!("{} scattered spheres", updates), args.iterations, |collection, iteration| {
+        for k in 0..updates {
+            let handle = handles[(iteration + k * SCATTER_STRIDE) % shapes];
+            collection.update_sphere(handle, random_sphere()).unwrap();
+        }
+    });
+    measure(&mut renderer, &format!("{} contiguous spheres", updates), args.iterations, |collection, iteration| {
+        for k in 0..updates {
+            let handle = handles[(iteration * updates + k) % shapes];
+            collection.update_sphere(handle, random_sphere()).unwrap();
+        }
+    });
+    measure(&mut renderer, &format!("{} scattered visibilities", updates), args.iterations, |collection, iteration| {
+        for k in 0..updates {
+            let handle = handles[(iteration + k * SCATTER_STRIDE) % shapes];
+            collection.set_visible(handle, iteration % 2 == 1).unwrap();
+        }
+    });
+    measure(&mut renderer, &format!("{} spheres removed and added", updates), args.iterations, |collection, iteration| {
+        for k in 0..updates {
+            let slot = (iteration + k * SCATTER_STRIDE) % shapes;
+            collection.remove(handles[slot]).unwrap();
+            handles[slot] = collection.add_sphere(random_sphere(), props);
+        }
+    });
+    Ok(())
+}
@@ -13,6 +13,16 @@ struct CameraUniform {
     _pad:[f32;3]
 }
 
+/// Forward, right and up directions of a camera turned by `angle` around the y axis,
+/// looking along x at 0
+pub fn camera_axes(angle: f32) -> [[f32;3];3] {
+    [
+        [angle.cos(), 0.0, angle.sin()],
+        [(angle-PI/2.0).cos(), 0.0, (angle-PI/2.0).sin()],
+        [0.0, 1.0, 0.0]
+    ]
+}
+
 pub struct CameraManager{
     dirty:bool,
     size:PhysicalSize<u32>,
@@ -113,15 +123,15 @@ impl CameraManager {
     }
 
     pub fn forward(&self) ->ndarray::Array1<f32>{
-        ndarray::arr1(&[self.angle.cos(), 0.0, self.angle.sin()])
+        ndarray::arr1(&camera_axes(self.angle)[0])
     }
 
     pub fn right(&self) -> ndarray::Array1<f32>{
-        ndarray::arr1(&[(self.angle-PI/2.0).cos(), 0.0, (self.angle-PI/2.0).sin()])
+        ndarray::arr1(&camera_axes(self.angle)[1])
     }
 
     pub fn up(&self)-> ndarray::Array1<f32>{
-        ndarray::arr1(&[0.0, 1.0, 0.0])
+        ndarray::arr1(&camera_axes(self.angle)[2])
     }
 
     pub fn aspect_ratio(&self) -> f32{
@@ -138,6 +148,12 @@ impl CameraManager {
         self.dirty = true;
     }
 
+    /// Place the camera at `distance` from the origin looking at it along `angle`
+    pub fn orbit(&mut self, angle:f32, distance:f32){
+        self.set_angle(angle);
+        self.set_position(self.forward() * -distance);
+    }
+
     pub fn set_position(&mut self, pos:ndarray::Array1<f32>){
         if pos.dim() == 3 {
             self.position = pos;
@@ -0,0 +1,308 @@
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use crate::headless::{write_png, HeadlessError, HeadlessRenderer};
+use crate::light::LightError;
+use crate::reference::ReferenceRenderer;
+use crate::scene::{Scene, SceneError};
+use crate::shader::Shading;
+use crate::shapes::ShapeError;
+use crate::shapes::registry::PrimitiveRegistry;
+
+pub const USAGE: &str = "\
+Usage :
+    raymarcher [OPTIONS]            Open the viewer window
+    raymarcher render [OPTIONS]     Render frames to image files and exit
+    raymarcher bench [OPTIONS]      Measure the cost of uploading the shapes to the GPU, or of rendering with --render
+
+Viewer options :
+    --scene <PATH>       Scene file to show, reloaded when it changes (default the demo scene)
+    --dev                Reload the ray marcher shader when its components change
+
+Render options :
+    --scene <PATH>       Scene file to render (default the demo scene)
+    --width <PX>         Width of the image (default 512)
+    --height <PX>        Height of the image (default 512)
+    --out <PATH>         Output png, frames are suffixed with their index when rendering several (default frame.png)
+    --frames <N>         Number of frames spread over a full orbit of the camera (default 1)
+    --angle <RAD>        Angle of the camera for the first frame (default from the scene)
+    --distance <UNITS>   Distance from the camera to the origin (default from the scene)
+    --shading <MODE>     full, flat without shadows nor reflections, or normals as colors (default full)
+    --specialize         Generate a shader from the scene instead of interpreting the shape buffers, full shading only
+    --fallback           Force the software/fallback adapter
+    --cpu                Render with the CPU reference of the shader, without any adapter
+    --trace <X,Y>        Print every ray the CPU reference sends for the pixel of the first frame instead of rendering
+
+Bench options :
+    --shapes <N>         Number of spheres in the collection (default 10000)
+    --updates <N>        Number of spheres modified between two uploads (default 100)
+    --iterations <N>     Number of uploads or frames measured per case (default 100)
+    --render             Compare the frame time of the interpreter with the one of the shader specialized for the scene
+    --scene <PATH>       Scene rendered by --render (default the demo scene)
+    --fallback           Force the software/fallback adapter";
+
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Unknown argument \"{0}\"")]
+    UnknownArgument(String),
+    #[error("Missing value for \"{0}\"")]
+    MissingValue(String),
+    #[error("Invalid value \"{1}\" for \"{0}\"")]
+    InvalidValue(String, String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ViewerArgs {
+    pub scene: Option<PathBuf>,
+    pub dev: bool
+}
+
+impl ViewerArgs {
+    pub fn parse(mut args: impl Iterator<Item=String>) -> Result<Self, CliError> {
+        let mut res = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => res.scene = Some(parse_value(&arg, args.next())?),
+                "--dev" => res.dev = true,
+                _ => return Err(CliError::UnknownArgument(arg))
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderArgs {
+    pub scene: Option<PathBuf>,
+    pub width: u32,
+    pub height: u32,
+    pub out: PathBuf,
+    pub frames: u32,
+    pub angle: Option<f32>,
+    pub distance: Option<f32>,
+    pub shading: Shading,
+    pub specialize: bool,
+    pub fallback: bool,
+    pub cpu: bool,
+    pub trace: Option<Pixel>
+}
+
+impl Default for RenderArgs {
+    fn default() -> Self {
+        Self{
+            scene: None,
+            width: 512,
+            height: 512,
+            out: PathBuf::from("frame.png"),
+            frames: 1,
+            angle: None,
+            distance: None,
+            shading: Shading::Full,
+            specialize: false,
+            fallback: false,
+            cpu: false,
+            trace: None
+        }
+    }
+}
+
+impl RenderArgs {
+    pub fn parse(mut args: impl Iterator<Item=String>) -> Result<Self, CliError> {
+        let mut res = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => res.scene = Some(parse_value(&arg, args.next())?),
+                "--width" => res.width = parse_value(&arg, args.next())?,
+                "--height" => res.height = parse_value(&arg, args.next())?,
+                "--out" => res.out = parse_value(&arg, args.next())?,
+                "--frames" => res.frames = parse_value(&arg, args.next())?,
+                "--angle" => res.angle = Some(parse_value(&arg, args.next())?),
+                "--distance" => res.distance = Some(parse_value(&arg, args.next())?),
+                "--shading" => res.shading = parse_value(&arg, args.next())?,
+                "--specialize" => res.specialize = true,
+                "--fallback" => res.fallback = true,
+                "--cpu" => res.cpu = true,
+                "--trace" => res.trace = Some(parse_value(&arg, args.next())?),
+                _ => return Err(CliError::UnknownArgument(arg))
+            }
+        }
+        if res.width == 0 || res.height == 0 {
+            return Err(CliError::InvalidValue("--width/--height".into(), "0".into()));
+        }
+        if let Some(pixel) = res.trace {
+            if pixel.x >= res.width || pixel.y >= res.height {
+                return Err(CliError::InvalidValue("--trace".into(), format!("{},{}", pixel.x, pixel.y)));
+            }
+        }
+        if res.frames == 0 {
+            return Err(CliError::InvalidValue("--frames".into(), "0".into()));
+        }
+        // The specialized variant has the full shading
+        if res.specialize && res.shading != Shading::Full {
+            return Err(CliError::InvalidValue("--shading".into(), format!("{:?} with --specialize", res.shading).to_lowercase()));
+        }
+        Ok(res)
+    }
+
+    /// Path of the `index`th frame, the index is only added when there are several frames
+    pub fn frame_path(&self, index: u32) -> PathBuf {
+        if self.frames == 1 {
+            return self.out.clone();
+        }
+        let stem = self.out.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let ext = self.out.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "png".into());
+        self.out.with_file_name(format!("{}_{:04}.{}", stem, index, ext))
+    }
+}
+
+/// Pixel of the image, written `X,Y`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Pixel {
+    pub x: u32,
+    pub y: u32
+}
+
+impl FromStr for Pixel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s.split_once(',').ok_or(())?;
+        match (x.trim().parse(), y.trim().parse()) {
+            (Ok(x), Ok(y)) => Ok(Self{ x, y }),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchArgs {
+    pub shapes: u32,
+    pub updates: u32,
+    pub iterations: u32,
+    pub render: bool,
+    pub scene: Option<PathBuf>,
+    pub fallback: bool
+}
+
+impl Default for BenchArgs {
+    fn default() -> Self {
+        Self{
+            shapes: 10000,
+            updates: 100,
+            iterations: 100,
+            render: false,
+            scene: None,
+            fallback: false
+        }
+    }
+}
+
+impl BenchArgs {
+    pub fn parse(mut args: impl Iterator<Item=String>) -> Result<Self, CliError> {
+        let mut res = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--shapes" => res.shapes = parse_value(&arg, args.next())?,
+                "--updates" => res.updates = parse_value(&arg, args.next())?,
+                "--iterations" => res.iterations = parse_value(&arg, args.next())?,
+                "--render" => res.render = true,
+                "--scene" => res.scene = Some(parse_value(&arg, args.next())?),
+                "--fallback" => res.fallback = true,
+                _ => return Err(CliError::UnknownArgument(arg))
+            }
+        }
+        if res.shapes == 0 {
+            return Err(CliError::InvalidValue("--shapes".into(), "0".into()));
+        }
+        if res.updates > res.shapes {
+            return Err(CliError::InvalidValue("--updates".into(), res.updates.to_string()));
+        }
+        if res.iterations == 0 {
+            return Err(CliError::InvalidValue("--iterations".into(), "0".into()));
+        }
+        Ok(res)
+    }
+}
+
+fn parse_value<T: FromStr>(arg: &str, value: Option<String>) -> Result<T, CliError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Err(CliError::MissingValue(arg.into()))
+    };
+    match value.parse() {
+        Ok(ok) => Ok(ok),
+        Err(_) => Err(CliError::InvalidValue(arg.into(), value))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error(transparent)]
+    SceneError(#[from] SceneError),
+    #[error(transparent)]
+    HeadlessError(#[from] HeadlessError),
+    #[error(transparent)]
+    ShapeError(#[from] ShapeError),
+    #[error(transparent)]
+    LightError(#[from] LightError),
+}
+
+/// Render every requested frame to disk
+pub async fn render(args: &RenderArgs) -> Result<(), RenderError> {
+    render_with_registry(args, &PrimitiveRegistry::builtin()).await
+}
+
+/// [`render`] with a renderer able to draw the primitives of `registry`, on the GPU or the CPU
+pub async fn render_with_registry(args: &RenderArgs, registry: &PrimitiveRegistry) -> Result<(), RenderError> {
+    let scene = match &args.scene {
+        Some(path) => Scene::load(path)?,
+        None => Scene::demo()
+    };
+    if args.cpu || args.trace.is_some() {
+        return render_reference(args, &scene, registry);
+    }
+    let mut renderer = HeadlessRenderer::with_shading(args.width, args.height, args.fallback, registry, args.shading).await?;
+    scene.build_shapes(renderer.shape_collection())?;
+    scene.apply_camera(renderer.camera_manager());
+    renderer.light_manager().set_lights(&scene.lights())?;
+    if args.specialize {
+        renderer.specialize().await?;
+    }
+
+    let start_angle = args.angle.unwrap_or(scene.camera.angle);
+    let distance = args.distance.unwrap_or(scene.camera.distance);
+    for frame in 0..args.frames {
+        let angle = start_angle + 2.0 * PI * frame as f32 / args.frames as f32;
+        renderer.camera_manager().orbit(angle, distance);
+        let path = args.frame_path(frame);
+        renderer.render_to_png(Path::new(&path)).await?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
+
+/// Render the frames with the CPU [`ReferenceRenderer`], or print the trace of `args.trace` in the first one
+fn render_reference(args: &RenderArgs, scene: &Scene, registry: &PrimitiveRegistry) -> Result<(), RenderError> {
+    let mut renderer = ReferenceRenderer::with_registry(args.width, args.height, registry.clone());
+    *renderer.options() = args.shading.into();
+    scene.build_shapes(renderer.shape_collection())?;
+    renderer.camera().screen_depth = scene.camera.screen_depth;
+    renderer.set_lights(&scene.lights())?;
+
+    let start_angle = args.angle.unwrap_or(scene.camera.angle);
+    let distance = args.distance.unwrap_or(scene.camera.distance);
+    if let Some(pixel) = args.trace {
+        renderer.camera().orbit(start_angle, distance);
+        println!("{:#?}", renderer.trace_pixel(pixel.x, pixel.y));
+        return Ok(());
+    }
+    for frame in 0..args.frames {
+        let angle = start_angle + 2.0 * PI * frame as f32 / args.frames as f32;
+        renderer.camera().orbit(angle, distance);
+        let path = args.frame_path(frame);
+        write_png(&path, args.width, args.height, &renderer.render())?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,292 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroU32;
+use std::path::Path;
+use thiserror::Error;
+use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindingResource, Buffer, BufferDescriptor, BufferUsages, ComputePassDescriptor, ComputePipeline, Device, ErrorFilter, Extent3d, ImageCopyBuffer, ImageCopyTexture, ImageDataLayout, MapMode, Origin3d, PipelineLayout, Queue, Texture, TextureAspect, TextureFormat, TextureUsages, TextureViewDescriptor, TextureViewDimension};
+use winit::dpi::PhysicalSize;
+use crate::app::{AppState, WORKGROUP_SIZE_X, WORKGROUP_SIZE_Y};
+use crate::camera::CameraManager;
+use crate::light::LightManager;
+use crate::shader::{Shading, RAYMARCHER_SPECIALIZED_SHADER};
+use crate::shapes::ShapeCollection;
+use crate::shapes::registry::{PrimitiveRegistry, RegistryError};
+use crate::shapes::specialize::{self, SpecializeError};
+
+#[derive(Debug, Error)]
+pub enum HeadlessError {
+    #[error("No adapter available (fallback adapter forced : {0})")]
+    NoAdapter(bool),
+    #[error("Failed to request a device : {0}")]
+    DeviceError(#[from] wgpu::RequestDeviceError),
+    #[error("Failed to create the ray marcher pipeline : \n {0}")]
+    ShaderError(String),
+    #[error(transparent)]
+    RegistryError(#[from] RegistryError),
+    #[error(transparent)]
+    SpecializeError(#[from] SpecializeError),
+    #[error("Failed to read back the rendered frame : {0}")]
+    MapError(#[from] wgpu::BufferAsyncError),
+    #[error("Fail to write the image \"{0}\" : \n {1}")]
+    IOError(String, std::io::Error),
+    #[error("Fail to encode the image \"{0}\" : \n {1}")]
+    EncodingError(String, png::EncodingError),
+}
+
+/// Renders the scene into an offscreen texture and reads it back on the CPU.
+///
+/// Shares the compute pipeline, the shape collection and the camera with the windowed [`AppState`]
+/// but has no surface and no copy pipeline.
+pub struct HeadlessRenderer {
+    device: Device,
+    queue: Queue,
+    width: u32,
+    height: u32,
+
+    render_pipeline: ComputePipeline,
+    pipeline_layout: PipelineLayout,
+    // Pipeline generated from the shapes by `specialize`, used instead of the render pipeline when set
+    specialized_pipeline: Option<ComputePipeline>,
+    registry: PrimitiveRegistry,
+
+    target_texture: Texture,
+    target_texture_bind_group: BindGroup,
+    // Mappable buffer the target texture is copied into, rows are padded to COPY_BYTES_PER_ROW_ALIGNMENT
+    output_buffer: Buffer,
+    padded_bytes_per_row: u32,
+
+    shape_collection: ShapeCollection,
+    camera_manager: CameraManager,
+    light_manager: LightManager
+}
+
+impl HeadlessRenderer {
+    pub async fn new(width: u32, height: u32, force_fallback_adapter: bool) -> Result<Self, HeadlessError> {
+        Self::with_registry(width, height, force_fallback_adapter, &PrimitiveRegistry::builtin()).await
+    }
+
+    /// Renderer able to draw the primitives of `registry`
+    pub async fn with_registry(width: u32, height: u32, force_fallback_adapter: bool, registry: &PrimitiveRegistry) -> Result<Self, HeadlessError> {
+        Self::with_shading(width, height, force_fallback_adapter, registry, Shading::Full).await
+    }
+
+    /// Renderer drawing the primitives of `registry` with the variant of the ray marcher of `shading`.
+    /// [`HeadlessRenderer::specialize`] always goes back to the full shading.
+    pub async fn with_shading(width: u32, height: u32, force_fallback_adapter: bool, registry: &PrimitiveRegistry, shading: Shading) -> Result<Self, HeadlessError> {
+        let source = registry.shader_source(shading.source())?;
+        let (device, queue) = Self::wgpu_init(force_fallback_adapter).await?;
+
+        // Shader errors are reported through the error scope instead of the panicking default handler
+        device.push_error_scope(ErrorFilter::Validation);
+        let (render_pipeline, pipeline_layout, target_texture_bind_group_layout) = AppState::init_render_pipeline(&device, &source);
+        if let Some(err) = device.pop_error_scope().await {
+            return Err(HeadlessError::ShaderError(err.to_string()));
+        }
+
+        let target_texture = AppState::init_target_texture(&device, width, height, TextureUsages::COPY_SRC);
+        let write_view = target_texture.create_view(&TextureViewDescriptor{
+            label: Some("Target Write View"),
+            format: Some(TextureFormat::Rgba8Unorm),
+            dimension: Some(TextureViewDimension::D2),
+            aspect: Default::default(),
+            base_mip_level: 0,
+            mip_level_count: None,
+            base_array_layer: 0,
+            array_layer_count: None
+        });
+        let target_texture_bind_group = device.create_bind_group(&BindGroupDescriptor{
+            label: Some("Target Texture Bind Group"),
+            layout: &target_texture_bind_group_layout,
+            entries: &[BindGroupEntry{ binding: 0, resource: BindingResource::TextureView(&write_view) }]
+        });
+
+        let unpadded_bytes_per_row = width * 4;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (unpadded_bytes_per_row + align - 1) / align * align;
+        let output_buffer = device.create_buffer(&BufferDescriptor{
+            label: Some("Output Buffer"),
+            size: (padded_bytes_per_row * height) as u64,
+            usage: BufferUsages::MAP_READ|BufferUsages::COPY_DST,
+            mapped_at_creation: false
+        });
+
+        let shape_collection = ShapeCollection::new(&device);
+        let camera_manager = CameraManager::new(&device, PhysicalSize::new(width, height));
+        let light_manager = LightManager::new(&device);
+
+        Ok(Self {
+            device,
+            queue,
+            width,
+            height,
+
+            render_pipeline,
+            pipeline_layout,
+            specialized_pipeline: None,
+            registry: registry.clone(),
+
+            target_texture,
+            target_texture_bind_group,
+            output_buffer,
+            padded_bytes_per_row,
+
+            shape_collection,
+            camera_manager,
+            light_manager
+        })
+    }
+
+    async fn wgpu_init(force_fallback_adapter: bool) -> Result<(Device, Queue), HeadlessError> {
+        let instance = wgpu::Instance::new(wgpu::Backends::all());
+        let request = |force_fallback_adapter| instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter,
+            compatible_surface: None,
+        });
+        let adapter = match request(force_fallback_adapter).await {
+            Some(adapter) => adapter,
+            // Without a GPU, the software adapter is only found when asked for
+            None if !force_fallback_adapter => match request(true).await {
+                Some(adapter) => adapter,
+                None => return Err(HeadlessError::NoAdapter(true))
+            },
+            None => return Err(HeadlessError::NoAdapter(force_fallback_adapter))
+        };
+        log::info!("Adapter : {:?}", adapter.get_info());
+        let (device, queue) = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                features: wgpu::Features::empty(),
+                limits: wgpu::Limits::default()
+                    .using_resolution(adapter.limits()),
+                label: None,
+            },
+            None, // Trace path
+        ).await?;
+        Ok((device, queue))
+    }
+
+    pub fn shape_collection(&mut self) -> &mut ShapeCollection {
+        &mut self.shape_collection
+    }
+
+    pub fn camera_manager(&mut self) -> &mut CameraManager {
+        &mut self.camera_manager
+    }
+
+    pub fn light_manager(&mut self) -> &mut LightManager {
+        &mut self.light_manager
+    }
+
+    /// Render with a shader whose distance function is generated from the current shapes (see [`specialize`]).
+    /// The shapes are still read for the surfaces, but changing them has no effect on the marching until the next call.
+    pub async fn specialize(&mut self) -> Result<(), HeadlessError> {
+        let source = self.registry.shader_source(RAYMARCHER_SPECIALIZED_SHADER)?;
+        let source = specialize::shader_source(&source, &self.shape_collection, &self.registry)?;
+        self.device.push_error_scope(ErrorFilter::Validation);
+        let pipeline = AppState::create_render_pipeline(&self.device, &self.pipeline_layout, &source);
+        if let Some(err) = self.device.pop_error_scope().await {
+            return Err(HeadlessError::ShaderError(err.to_string()));
+        }
+        self.specialized_pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    /// Go back to the shader interpreting the shape buffers
+    pub fn interpret(&mut self) {
+        self.specialized_pipeline = None;
+    }
+
+    /// Upload the shapes that changed and wait for the GPU to receive them
+    pub fn upload_shapes(&mut self) {
+        self.shape_collection.update_buffers(&self.device, &self.queue);
+        self.queue.submit(std::iter::empty());
+        self.device.poll(wgpu::Maintain::Wait);
+    }
+
+    /// Render one frame and return it as tightly packed RGBA8 rows
+    pub async fn render(&mut self) -> Result<Vec<u8>, HeadlessError> {
+        self.shape_collection.update_buffers(&self.device, &self.queue);
+        self.camera_manager.update_buffers(&self.queue);
+        self.light_manager.update_buffers(&self.queue);
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+            label:Some("Headless Render Encoder")
+        });
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&ComputePassDescriptor{ label: None });
+            compute_pass.set_pipeline(self.specialized_pipeline.as_ref().unwrap_or(&self.render_pipeline));
+            compute_pass.set_bind_group(0,&self.target_texture_bind_group,&[]);
+            compute_pass.set_bind_group(1, self.shape_collection.bind_group(),&[]);
+            compute_pass.set_bind_group(2, self.camera_manager.bind_group(),&[]);
+            compute_pass.set_bind_group(3, self.light_manager.bind_group(),&[]);
+            compute_pass.dispatch(
+                (self.width + WORKGROUP_SIZE_X - 1) / WORKGROUP_SIZE_X,
+                (self.height + WORKGROUP_SIZE_Y - 1) / WORKGROUP_SIZE_Y,
+                1
+            )
+        }
+        encoder.copy_texture_to_buffer(
+            ImageCopyTexture{
+                texture: &self.target_texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All
+            },
+            ImageCopyBuffer{
+                buffer: &self.output_buffer,
+                layout: ImageDataLayout{
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(self.padded_bytes_per_row),
+                    rows_per_image: None
+                }
+            },
+            Extent3d{
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1
+            }
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = self.output_buffer.slice(..);
+        let mapping = slice.map_async(MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        mapping.await?;
+
+        // Strip the row padding
+        let unpadded_bytes_per_row = (self.width * 4) as usize;
+        let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * self.height as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+            }
+        }
+        self.output_buffer.unmap();
+        Ok(pixels)
+    }
+
+    /// Render one frame and encode it as a PNG at `path`
+    pub async fn render_to_png(&mut self, path: &Path) -> Result<(), HeadlessError> {
+        let pixels = self.render().await?;
+        write_png(path, self.width, self.height, &pixels)
+    }
+}
+
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<(), HeadlessError> {
+    let file = match File::create(path) {
+        Ok(ok) => ok,
+        Err(err) => return Err(HeadlessError::IOError(path.display().to_string(), err))
+    };
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = match encoder.write_header() {
+        Ok(ok) => ok,
+        Err(err) => return Err(HeadlessError::EncodingError(path.display().to_string(), err))
+    };
+    if let Err(err) = writer.write_image_data(pixels) {
+        return Err(HeadlessError::EncodingError(path.display().to_string(), err));
+    }
+    Ok(())
+}
@@ -0,0 +1,13 @@
+pub mod app;
+pub mod camera;
+pub mod light;
+pub mod scene;
+pub mod cli;
+pub mod headless;
+pub mod reference;
+pub mod bench;
+pub mod shader;
+pub mod template;
+mod watcher;
+pub mod shapes;
+pub mod color;
@@ -0,0 +1,130 @@
+use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, Device, Queue, ShaderStages};
+use bytemuck::{Zeroable, Pod};
+use thiserror::Error;
+use crate::color::Color;
+
+/// Number of lights the shader can handle
+pub const MAX_LIGHTS: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum LightError {
+    #[error("{0} lights were given but at most {} are supported", MAX_LIGHTS)]
+    TooManyLights(usize),
+}
+
+/// Check the shader can handle `lights`
+pub fn check_lights(lights: &[Light]) -> Result<(), LightError> {
+    if lights.len() > MAX_LIGHTS {
+        return Err(LightError::TooManyLights(lights.len()));
+    }
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+struct LightRaw {
+    direction: [f32;3],
+    _pad1: [f32;1],
+    color: Color,
+    _pad2: [f32;1]
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+struct LightsUniform {
+    lights: [LightRaw; MAX_LIGHTS],
+    count: u32,
+    _pad: [u32;3]
+}
+
+/// Directional light
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    pub direction: [f32;3],
+    pub color: Color
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self{ direction: [-1.0, -1.0, 0.4], color: Color(1.0, 1.0, 1.0) }
+    }
+}
+
+pub struct LightManager{
+    dirty: bool,
+    lights: Vec<Light>,
+
+    lights_uniform: Buffer,
+    lights_bind_group: BindGroup
+}
+
+impl LightManager {
+    pub fn new(device: &Device) -> Self{
+        let lights_uniform = Self::init_buffers(device);
+        let bind_group_layout = Self::bind_group_layout(device);
+
+        let lights_bind_group = device.create_bind_group(&BindGroupDescriptor{
+            label: Some("LightsBindGroup"),
+            layout: &bind_group_layout,
+            entries: &[
+                BindGroupEntry{ binding: 0, resource: lights_uniform.as_entire_binding() }
+            ]
+        });
+
+        Self{ dirty: true, lights: vec![Light::default()], lights_uniform, lights_bind_group }
+    }
+
+    pub fn bind_group_layout(device:&Device) -> wgpu::BindGroupLayout{
+        let bind_group_layout = wgpu::BindGroupLayoutDescriptor {
+            label: Some("LightsBindGroupLayout"),
+            entries: &[
+                BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(std::mem::size_of::<LightsUniform>() as u64)
+                    },
+                    count:None
+                }
+            ]
+        };
+        device.create_bind_group_layout(&bind_group_layout)
+    }
+
+    fn init_buffers(device:&Device) -> wgpu::Buffer{
+        device.create_buffer(&BufferDescriptor{
+            label: Some("Lights Uniform"),
+            size: std::mem::size_of::<LightsUniform>() as u64,
+            usage: BufferUsages::UNIFORM|BufferUsages::COPY_DST,
+            mapped_at_creation: false
+        })
+    }
+
+    pub fn update_buffers(&mut self, queue:&Queue){
+        if self.dirty {
+            queue.write_buffer(&self.lights_uniform, 0, bytemuck::bytes_of(&self.generate_uniform()));
+            self.dirty = false;
+        }
+    }
+
+    fn generate_uniform(&self) -> LightsUniform{
+        let mut uniform = LightsUniform::zeroed();
+        for (raw, light) in uniform.lights.iter_mut().zip(self.lights.iter()) {
+            raw.direction = light.direction;
+            raw.color = light.color;
+        }
+        uniform.count = self.lights.len() as u32;
+        uniform
+    }
+
+    pub fn bind_group(&self) -> &BindGroup{&self.lights_bind_group}
+
+    pub fn set_lights(&mut self, lights:&[Light]) -> Result<(), LightError>{
+        check_lights(lights)?;
+        self.lights = lights.to_vec();
+        self.dirty = true;
+        Ok(())
+    }
+}
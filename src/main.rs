@@ -1,19 +1,19 @@
-mod app;
-mod camera;
-mod shapes;
-pub mod color;
-
 use winit::event::{ElementState, Event, VirtualKeyCode};
 use winit::event::WindowEvent;
 use winit::event::KeyboardInput;
 use winit::event_loop::EventLoop;
 use winit::window::Window;
 use winit::event_loop::ControlFlow;
-use crate::app::AppState;
+use std::process::exit;
+use raymarcher::app::AppState;
+use raymarcher::bench;
+use raymarcher::cli::{self, BenchArgs, RenderArgs, ViewerArgs};
+use raymarcher::scene::{Scene, SceneWatcher};
 
+const TITLE: &str = "Raymarcher";
 
-async fn run(event_loop: EventLoop<()>, window:Window) {
-    let mut app = AppState::new(&window).await;
+async fn run(event_loop: EventLoop<()>, window:Window, scene: Scene, mut scene_watcher: Option<SceneWatcher>, dev: bool) {
+    let mut app = AppState::new(&window, &scene, dev).await;
     let mut last_frame = std::time::Instant::now();
     event_loop.run(move |event, _, control_flow|{
         match event {
@@ -44,6 +44,19 @@ async fn run(event_loop: EventLoop<()>, window:Window) {
                 }
             },
             Event::RedrawRequested(window_id) if window_id == window.id() => {
+                // Reload the scene file when it changes, keeping the previous scene if it is invalid
+                match scene_watcher.as_mut().and_then(|watcher| watcher.poll()) {
+                    Some(Ok(scene)) => {
+                        println!("Scene reloaded");
+                        app.set_scene(&scene);
+                        window.set_title(TITLE);
+                    }
+                    Some(Err(err)) => {
+                        eprintln!("{}", err);
+                        window.set_title(&format!("{} - {}", TITLE, err));
+                    }
+                    None => {}
+                }
                 let delta_t = std::time::Instant::now()-last_frame;
                 last_frame=std::time::Instant::now();
                 app.update(delta_t);
@@ -71,9 +84,67 @@ async fn run(event_loop: EventLoop<()>, window:Window) {
 }
 
 fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(|arg| arg.as_str()) {
+        Some("render") => {
+            env_logger::init();
+            let render_args = match RenderArgs::parse(args.skip(1)) {
+                Ok(ok) => ok,
+                Err(err) => {
+                    eprintln!("{}\n\n{}", err, cli::USAGE);
+                    exit(2);
+                }
+            };
+            if let Err(err) = pollster::block_on(cli::render(&render_args)) {
+                eprintln!("{}", err);
+                exit(1);
+            }
+            return;
+        }
+        Some("bench") => {
+            env_logger::init();
+            let bench_args = match BenchArgs::parse(args.skip(1)) {
+                Ok(ok) => ok,
+                Err(err) => {
+                    eprintln!("{}\n\n{}", err, cli::USAGE);
+                    exit(2);
+                }
+            };
+            if let Err(err) = pollster::block_on(bench::bench(&bench_args)) {
+                eprintln!("{}", err);
+                exit(1);
+            }
+            return;
+        }
+        Some("--help") | Some("-h") => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        _ => {}
+    }
+
+    let viewer_args = match ViewerArgs::parse(args) {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            exit(2);
+        }
+    };
+    let (scene, scene_watcher) = match &viewer_args.scene {
+        None => (Scene::demo(), None),
+        Some(path) => match Scene::load(path) {
+            Ok(ok) => (ok, Some(SceneWatcher::new(path))),
+            Err(err) => {
+                eprintln!("{}", err);
+                exit(1);
+            }
+        }
+    };
+
     let event_loop = EventLoop::new();
     let window = Window::new(&event_loop).unwrap();
+    window.set_title(TITLE);
     env_logger::init();
 
-    pollster::block_on(run(event_loop, window));
+    pollster::block_on(run(event_loop, window, scene, scene_watcher, viewer_args.dev));
 }
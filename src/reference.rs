@@ -0,0 +1,402 @@
+//! CPU reference of the ray marcher.
+//!
+//! [`ReferenceRenderer`] runs `send_ray` and the shading loop of `render` from `main.wgsl` on the CPU, over the
+//! same [`ShapeCollection`] the GPU reads, with [`ShapeCollection::shape_distance`] and [`ShapeCollection::shape_surface`]
+//! in place of the shader functions. It is much slower than the GPU but needs no adapter, so it can render golden
+//! images for the tests, stand in when there is no GPU, and show what happens on a single pixel with
+//! [`ReferenceRenderer::trace_pixel`].
+
+use cgmath::{ElementWise, InnerSpace, Matrix, Matrix3, Vector3, Zero};
+use crate::camera::camera_axes;
+use crate::light::{check_lights, Light, LightError};
+use crate::shader::Shading;
+use crate::shapes::ShapeCollection;
+use crate::shapes::reference::SurfaceInfo;
+use crate::shapes::registry::PrimitiveRegistry;
+
+// Constants of `render` in main.wgsl
+const STEP_CAP: u32 = 1000000;
+const RENDER_DISTANCE: f32 = 100.0;
+const HIT_THRESHOLD: f32 = 0.00001;
+const BACKGROUND_COLOR: Vector3<f32> = Vector3::new(0.005, 0.0, 0.03);
+const REFLECTION_RAYS: u32 = 10;
+const REFLECTION_THRESHOLD: f32 = 0.000001;
+
+/// `RayParams` of the shader
+#[derive(Copy, Clone, Debug)]
+pub struct RayParams {
+    pub max_length: f32,
+    pub max_step: u32,
+    pub threshold: f32,
+    /// Shape ignored by the ray, -1 for none
+    pub skip_shape: i32
+}
+
+/// `Hit` of the shader
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    /// Closest shape that isn't a composite, -1 when nothing was hit
+    pub hit_shape: i32,
+    /// Shape of the collection whose tree contains `hit_shape`
+    pub root_shape: i32,
+    pub step_count: u32,
+    pub hit_pos: Vector3<f32>,
+    pub ray_length: f32,
+    /// Smallest distance to the scene along the ray
+    pub min_distance: f32
+}
+
+/// Camera of the shader, placed the same way as by [`CameraManager`](crate::camera::CameraManager)
+#[derive(Copy, Clone, Debug)]
+pub struct ReferenceCamera {
+    pub position: Vector3<f32>,
+    pub angle: f32,
+    pub screen_depth: f32
+}
+
+impl Default for ReferenceCamera {
+    fn default() -> Self {
+        Self{ position: Vector3::zero(), angle: 0.0, screen_depth: 2.0 }
+    }
+}
+
+impl ReferenceCamera {
+    pub fn forward(&self) -> Vector3<f32> {
+        camera_axes(self.angle)[0].into()
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        camera_axes(self.angle)[1].into()
+    }
+
+    pub fn up(&self) -> Vector3<f32> {
+        camera_axes(self.angle)[2].into()
+    }
+
+    /// Place the camera at `distance` from the origin looking at it along `angle`
+    pub fn orbit(&mut self, angle: f32, distance: f32) {
+        self.angle = angle;
+        self.position = self.forward() * -distance;
+    }
+
+    /// `ray_dir` of the camera uniform
+    fn ray_matrix(&self) -> Matrix3<f32> {
+        Matrix3::from_cols(-self.right(), self.up(), self.forward())
+    }
+}
+
+/// Defines of the shader variants, the defaults are the ones of `raymarcher2.wgsl`
+#[derive(Copy, Clone, Debug)]
+pub struct ReferenceOptions {
+    pub shadows: bool,
+    pub reflections: bool,
+    /// Color the pixels with the normal of the first hit, like the `normals` variant
+    pub debug_normals: bool
+}
+
+impl Default for ReferenceOptions {
+    fn default() -> Self {
+        Self{ shadows: true, reflections: true, debug_normals: false }
+    }
+}
+
+impl From<Shading> for ReferenceOptions {
+    /// Defines of the variant of `shading`
+    fn from(shading: Shading) -> Self {
+        match shading {
+            Shading::Full => Self::default(),
+            Shading::Flat => Self{ shadows: false, reflections: false, ..Self::default() },
+            Shading::Normals => Self{ debug_normals: true, ..Self::default() }
+        }
+    }
+}
+
+/// Ray sent from a hit toward a light, the light is hidden when it hits a shape
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowRay {
+    /// Index of the light
+    pub light: usize,
+    pub hit: Hit
+}
+
+/// Ray sent from the camera or reflected by the previous hit
+#[derive(Clone, Debug)]
+pub struct Bounce {
+    pub direction: Vector3<f32>,
+    pub hit: Hit,
+    /// Surface at the hit, `None` when the ray missed
+    pub surface: Option<SurfaceInfo>,
+    pub shadow_rays: Vec<ShadowRay>,
+    /// Color of the pixel once this bounce is added
+    pub color: Vector3<f32>,
+    /// Weight of the next bounce in the color
+    pub color_weight: f32
+}
+
+/// Every ray the shader sends for a pixel and the color it ends with
+#[derive(Clone, Debug)]
+pub struct PixelTrace {
+    pub x: u32,
+    pub y: u32,
+    pub bounces: Vec<Bounce>,
+    pub color: Vector3<f32>
+}
+
+fn vcos(a: Vector3<f32>, b: Vector3<f32>) -> f32 {
+    a.dot(b) / (a.magnitude() * b.magnitude())
+}
+
+fn reflection(incoming: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    -2.0 * incoming.dot(normal) / normal.dot(normal) * normal + incoming
+}
+
+/// Value the `Rgba8Unorm` target texture stores for `value`
+fn unorm8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Renders a [`ShapeCollection`] on the CPU with the same computations as the compute shader
+pub struct ReferenceRenderer {
+    width: u32,
+    height: u32,
+    shape_collection: ShapeCollection,
+    registry: PrimitiveRegistry,
+    camera: ReferenceCamera,
+    lights: Vec<Light>,
+    options: ReferenceOptions
+}
+
+impl ReferenceRenderer {
+    /// Renderer of the builtin primitives
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::with_registry(width, height, PrimitiveRegistry::builtin())
+    }
+
+    /// Renderer whose collection may hold the primitives of `registry`
+    pub fn with_registry(width: u32, height: u32, registry: PrimitiveRegistry) -> Self {
+        Self{
+            width,
+            height,
+            shape_collection: ShapeCollection::without_device(),
+            registry,
+            camera: ReferenceCamera::default(),
+            lights: vec![Light::default()],
+            options: ReferenceOptions::default()
+        }
+    }
+
+    pub fn shape_collection(&mut self) -> &mut ShapeCollection {
+        &mut self.shape_collection
+    }
+
+    pub fn camera(&mut self) -> &mut ReferenceCamera {
+        &mut self.camera
+    }
+
+    pub fn options(&mut self) -> &mut ReferenceOptions {
+        &mut self.options
+    }
+
+    pub fn set_lights(&mut self, lights: &[Light]) -> Result<(), LightError> {
+        check_lights(lights)?;
+        self.lights = lights.to_vec();
+        Ok(())
+    }
+
+    /// `send_ray` of the shader : march from `origin` along `direction` until a shape is closer than the threshold
+    pub fn send_ray(&self, origin: Vector3<f32>, direction: Vector3<f32>, params: RayParams) -> Hit {
+        let mut step_count = 0;
+        let mut ray_length = 0.0;
+        let mut closest_shape;
+        let mut root_shape = -1;
+        let mut closest_distance;
+        let mut closest_distance_g = 9999999999.0;
+        let mut ray_pos = origin + direction * params.threshold * 10.0;
+        loop {
+            closest_distance = 9999999999.0;
+            closest_shape = -1;
+            for i in 0..self.shape_collection.len() as u32 {
+                if params.threshold >= closest_distance {
+                    break;
+                }
+                if i as i32 == params.skip_shape || !self.shape_collection.visible(i) {
+                    continue;
+                }
+                let shape_dist_r = self.shape_collection.shape_distance(&self.registry, ray_pos, i, params.skip_shape);
+                // Shorter steps through deformed shapes so that the ray doesn't go through them
+                let distance = shape_dist_r.distance / shape_dist_r.lipschitz;
+                if closest_distance > distance {
+                    closest_shape = shape_dist_r.index as i32;
+                    root_shape = i as i32;
+                    closest_distance = distance;
+                }
+            }
+            ray_pos += direction * closest_distance;
+            ray_length += closest_distance;
+            step_count += 1;
+            if closest_distance < closest_distance_g {
+                closest_distance_g = closest_distance;
+            }
+
+            let marching = step_count < params.max_step && params.threshold < closest_distance && ray_length < params.max_length;
+            if !marching {
+                break;
+            }
+        }
+        let hit = params.threshold > closest_distance;
+        Hit{
+            hit_shape: if hit { closest_shape } else { -1 },
+            root_shape: if hit { root_shape } else { -1 },
+            step_count,
+            hit_pos: ray_pos,
+            ray_length,
+            min_distance: closest_distance_g
+        }
+    }
+
+    /// Direction of the first ray of the pixel at column `x` and row `y`
+    pub fn ray_direction(&self, x: u32, y: u32) -> Vector3<f32> {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let ratio = width / height;
+        let screen = Vector3::new((-(x as f32) / width + 0.5) * ratio, -(y as f32) / height + 0.5, self.camera.screen_depth);
+        // `screen * ray_dir` in the shader
+        (self.camera.ray_matrix().transpose() * screen).normalize()
+    }
+
+    /// Rays sent by `render` for the pixel at column `x` and row `y` and its final color, before the conversion to RGBA8
+    pub fn trace_pixel(&self, x: u32, y: u32) -> PixelTrace {
+        let reflection_rays = if self.options.reflections { REFLECTION_RAYS } else { 1 };
+        let ray = RayParams{ max_length: RENDER_DISTANCE, max_step: STEP_CAP, threshold: HIT_THRESHOLD, skip_shape: -1 };
+
+        let mut ray_direction = self.ray_direction(x, y);
+        let mut color = Vector3::zero();
+        let mut color_weight = 1.0;
+        let mut hit_pos = self.camera.position;
+        let mut bounces = vec![];
+        loop {
+            if bounces.len() as u32 >= reflection_rays || color_weight < REFLECTION_THRESHOLD {
+                color *= 1.0 / (1.0 - color_weight);
+                break;
+            }
+            let hit = self.send_ray(hit_pos, ray_direction, ray);
+            hit_pos = hit.hit_pos;
+            if hit.hit_shape < 0 {
+                color += BACKGROUND_COLOR * color_weight;
+                bounces.push(Bounce{ direction: ray_direction, hit, surface: None, shadow_rays: vec![], color, color_weight });
+                break;
+            }
+            let surface = self.shape_collection.shape_surface(&self.registry, hit.hit_pos, hit.root_shape as u32);
+            if self.options.debug_normals {
+                color = surface.normal * 0.5 + Vector3::new(0.5, 0.5, 0.5);
+                bounces.push(Bounce{ direction: ray_direction, hit, surface: Some(surface), shadow_rays: vec![], color, color_weight });
+                break;
+            }
+
+            let matness = 1.0 - surface.reflectivity;
+            let normal = surface.normal;
+            let mut lit = Vector3::zero();
+            let mut shadow_rays = vec![];
+            for (index, light) in self.lights.iter().enumerate() {
+                let light_direction = Vector3::from(light.direction).normalize();
+                let light_rgb = Vector3::new(light.color.0, light.color.1, light.color.2);
+                let diffuse = vcos(normal, -light_direction);
+                let mut light_color = surface.color.mul_element_wise(light_rgb) * diffuse;
+                if self.options.shadows && diffuse > 0.00001 {
+                    let light_ray = RayParams{ max_length: 2000.0, max_step: 200, threshold: 0.0000001, skip_shape: -1 };
+                    let light_hit = self.send_ray(hit.hit_pos, -light_direction, light_ray);
+                    light_color *= (-(light_hit.hit_shape as f32)).max(0.0);
+                    shadow_rays.push(ShadowRay{ light: index, hit: light_hit });
+                }
+
+                // Specular lighting
+                let light_reflection = reflection(light_direction, normal);
+                let specular = surface.reflectivity * vcos(light_reflection, ray_direction).abs().powf(45.0) * diffuse.max(0.0);
+
+                color += light_rgb * specular;
+                lit += light_color;
+            }
+
+            color += lit * color_weight * matness;
+            color_weight *= surface.reflectivity;
+            bounces.push(Bounce{ direction: ray_direction, hit, surface: Some(surface), shadow_rays, color, color_weight });
+            ray_direction = reflection(ray_direction, normal);
+        }
+        PixelTrace{ x, y, bounces, color }
+    }
+
+    /// Render one frame as tightly packed RGBA8 rows, like [`HeadlessRenderer::render`](crate::headless::HeadlessRenderer::render).
+    /// The rows are split between the available threads.
+    pub fn render(&self) -> Vec<u8> {
+        let row_bytes = self.width as usize * 4;
+        let mut pixels = vec![0; row_bytes * self.height as usize];
+        let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+        let rows_per_thread = (self.height as usize).div_ceil(threads);
+        std::thread::scope(|scope| {
+            for (chunk, rows) in pixels.chunks_mut(rows_per_thread.max(1) * row_bytes).enumerate() {
+                scope.spawn(move || {
+                    for (row, pixels) in rows.chunks_mut(row_bytes).enumerate() {
+                        let y = (chunk * rows_per_thread + row) as u32;
+                        for (x, pixel) in pixels.chunks_mut(4).enumerate() {
+                            let color = self.trace_pixel(x as u32, y).color;
+                            pixel.copy_from_slice(&[unorm8(color.x), unorm8(color.y), unorm8(color.z), 255]);
+                        }
+                    }
+                });
+            }
+        });
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::light::MAX_LIGHTS;
+    use crate::shapes::ShapeProperties;
+    use crate::shapes::sphere::Sphere;
+
+    /// Renderer of 2x2 pixels with a unit sphere at `position`, looking at the origin from 5 units along -x
+    fn sphere_renderer(position: [f32;3]) -> ReferenceRenderer {
+        let mut renderer = ReferenceRenderer::new(2, 2);
+        let props = ShapeProperties{ color: Color(1.0, 0.0, 0.0), reflectivity: 0.0, visible: true };
+        renderer.shape_collection().add_sphere(Sphere::new(position, 1.0), props);
+        renderer.camera().orbit(0.0, 5.0);
+        renderer
+    }
+
+    fn assert_close(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!((actual - expected).magnitude() < 1e-3, "{:?} instead of {:?}", actual, expected);
+    }
+
+    #[test]
+    fn sphere_seen_head_on() {
+        let renderer = sphere_renderer([0.0;3]);
+        // The pixel at the middle of the screen looks straight forward
+        assert_close(renderer.ray_direction(1, 1), Vector3::unit_x());
+        let trace = renderer.trace_pixel(1, 1);
+        let bounce = &trace.bounces[0];
+        assert_eq!(bounce.hit.hit_shape, 0);
+        assert!((bounce.hit.ray_length - 4.0).abs() < 1e-3, "{}", bounce.hit.ray_length);
+        assert_close(bounce.hit.hit_pos, Vector3::new(-1.0, 0.0, 0.0));
+        assert_close(bounce.surface.unwrap().normal, Vector3::new(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn missed_pixels_get_the_background() {
+        let renderer = sphere_renderer([0.0, 10.0, 0.0]);
+        let trace = renderer.trace_pixel(1, 1);
+        assert_eq!(trace.bounces.len(), 1);
+        assert_eq!(trace.bounces[0].hit.hit_shape, -1);
+        assert_eq!(trace.color, BACKGROUND_COLOR);
+        let background = [unorm8(BACKGROUND_COLOR.x), unorm8(BACKGROUND_COLOR.y), unorm8(BACKGROUND_COLOR.z), 255];
+        assert_eq!(renderer.render(), background.repeat(4));
+    }
+
+    #[test]
+    fn too_many_lights_are_refused() {
+        let mut renderer = ReferenceRenderer::new(2, 2);
+        assert!(matches!(renderer.set_lights(&[Light::default(); MAX_LIGHTS + 1]), Err(LightError::TooManyLights(_))));
+        assert!(renderer.set_lights(&[Light::default(); MAX_LIGHTS]).is_ok());
+    }
+}
@@ -0,0 +1,484 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use thiserror::Error;
+use crate::camera::CameraManager;
+use crate::color::Color;
+use crate::light::{Light, MAX_LIGHTS};
+use crate::shapes::{ShapeCollection, ShapeError, ShapeProperties, STACK_DEPTH};
+use crate::shapes::composit::CompositDescriptor;
+use crate::shapes::cuboid::Cuboid;
+use crate::shapes::primitives::{Capsule, Cone, Cylinder, Ellipsoid, HexPrism, Plane, RoundedBox};
+use crate::shapes::sphere::Sphere;
+use crate::shapes::torus::Torus;
+use crate::shapes::transform::Transform;
+use crate::watcher::FileWatcher;
+
+#[derive(Debug, Error)]
+pub enum SceneError {
+    #[error("Fail to open the scene \"{0}\" : \n {1}")]
+    IOError(PathBuf, std::io::Error),
+    #[error("{0}:{1}:{2}: {3}")]
+    ParseError(PathBuf, usize, usize, String),
+    #[error("{0}: the scene has {1} lights but at most {} are supported", MAX_LIGHTS)]
+    TooManyLights(PathBuf, usize),
+    #[error("{0}: the shape {1} needs stacks of {2} slots but the shader only has {}", STACK_DEPTH)]
+    TooDeep(PathBuf, usize, u32),
+    #[error("{0}: the shape {1} is not valid: {2}")]
+    InvalidShape(PathBuf, usize, ShapeError),
+}
+
+/// Scene file, written in RON
+///
+/// ```ron
+/// Scene(
+///     camera: (angle: 0.5, distance: 12.0),
+///     lights: [(direction: (-1.0, -1.0, 0.4))],
+///     shapes: [
+///         Difference(
+///             Sphere(position: (0.0, 0.0, 0.0), radius: 1.0),
+///             Cuboid(position: (0.0, 0.0, 0.0), size: (1.5, 1.5, 1.5)),
+///         ),
+///     ],
+/// )
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub camera: CameraDescriptor,
+    #[serde(default = "default_lights")]
+    pub lights: Vec<LightDescriptor>,
+    #[serde(default)]
+    pub shapes: Vec<ShapeDescriptor>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CameraDescriptor {
+    pub angle: f32,
+    pub distance: f32,
+    pub screen_depth: f32
+}
+
+impl Default for CameraDescriptor {
+    fn default() -> Self {
+        Self{ angle: PI / 6.0, distance: 12.0, screen_depth: 2.0 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LightDescriptor {
+    pub direction: [f32;3],
+    #[serde(default = "default_color")]
+    pub color: [f32;3]
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PropertiesDescriptor {
+    pub color: [f32;3],
+    pub reflectivity: f32,
+    pub visible: bool
+}
+
+impl Default for PropertiesDescriptor {
+    fn default() -> Self {
+        Self{ color: default_color(), reflectivity: 0.0, visible: true }
+    }
+}
+
+/// Serializable counterpart of [`CompositDescriptor`]
+#[derive(Clone, Debug, Deserialize)]
+pub enum ShapeDescriptor {
+    Sphere {
+        position: [f32;3],
+        radius: f32,
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Cuboid {
+        position: [f32;3],
+        size: [f32;3],
+        /// Euler angles in radians
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Torus {
+        position: [f32;3],
+        /// Distance from the center to the center of the tube
+        radius: f32,
+        /// Radius of the tube
+        thickness: f32,
+        /// Euler angles in radians, the torus lies in the xz plane without rotation
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    /// Infinite plane, its normal is y before rotation
+    Plane {
+        position: [f32;3],
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Capsule {
+        position: [f32;3],
+        /// Length of the segment along y, without the rounded ends
+        height: f32,
+        radius: f32,
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Cylinder {
+        position: [f32;3],
+        height: f32,
+        radius: f32,
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Cone {
+        position: [f32;3],
+        height: f32,
+        bottom_radius: f32,
+        #[serde(default)]
+        top_radius: f32,
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Ellipsoid {
+        position: [f32;3],
+        radii: [f32;3],
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    RoundedBox {
+        position: [f32;3],
+        size: [f32;3],
+        /// Radius of the edges
+        radius: f32,
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    HexPrism {
+        position: [f32;3],
+        height: f32,
+        /// Distance from the axis to the faces
+        radius: f32,
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default)]
+        properties: PropertiesDescriptor
+    },
+    Union(Box<ShapeDescriptor>, Box<ShapeDescriptor>),
+    Intersection(Box<ShapeDescriptor>, Box<ShapeDescriptor>),
+    /// Union of a list of shapes, as cheap to evaluate as nested unions without making the tree deeper
+    UnionAll(Vec<ShapeDescriptor>),
+    IntersectionAll(Vec<ShapeDescriptor>),
+    Difference(Box<ShapeDescriptor>, Box<ShapeDescriptor>),
+    Blend(Box<ShapeDescriptor>, Box<ShapeDescriptor>, f32),
+    /// Union with a seam rounded over the radius
+    SmoothUnion(Box<ShapeDescriptor>, Box<ShapeDescriptor>, f32),
+    SmoothIntersection(Box<ShapeDescriptor>, Box<ShapeDescriptor>, f32),
+    /// Second shape minus the first one with a seam rounded over the radius
+    SmoothDifference(Box<ShapeDescriptor>, Box<ShapeDescriptor>, f32),
+    Translate(Box<ShapeDescriptor>, [f32;3]),
+    /// Euler angles in radians, around the origin
+    Rotate(Box<ShapeDescriptor>, [f32;3]),
+    /// Scale factors along x, y and z, from the origin
+    Scale(Box<ShapeDescriptor>, [f32;3]),
+    Transform {
+        shape: Box<ShapeDescriptor>,
+        #[serde(default)]
+        position: [f32;3],
+        /// Euler angles in radians
+        #[serde(default)]
+        rotation: [f32;3],
+        #[serde(default = "default_scale")]
+        scale: [f32;3]
+    },
+    /// Grid repetition, a size of 0 doesn't repeat along the axis
+    Repeat {
+        shape: Box<ShapeDescriptor>,
+        size: [f32;3],
+        /// Number of copies on each side of the origin, infinite without a limit
+        #[serde(default)]
+        limit: Option<[u32;3]>
+    },
+    /// Mirror across a plane, the side the normal points to is reflected
+    Mirror {
+        shape: Box<ShapeDescriptor>,
+        normal: [f32;3],
+        /// Distance from the origin to the plane along the normal
+        #[serde(default)]
+        offset: f32
+    },
+    /// Copies around the y axis
+    Polar {
+        shape: Box<ShapeDescriptor>,
+        count: u32
+    },
+    /// Twist around the y axis
+    Twist {
+        shape: Box<ShapeDescriptor>,
+        /// Radians per unit along y
+        rate: f32,
+        /// Largest distance from the shape to the axis, limits the steps of the rays
+        radius: f32
+    },
+    /// Bend of the x axis toward y
+    Bend {
+        shape: Box<ShapeDescriptor>,
+        /// Radians per unit along x
+        rate: f32,
+        /// Largest distance from the shape to the origin, limits the steps of the rays
+        radius: f32
+    },
+    /// Noise added to the surface
+    Displace {
+        shape: Box<ShapeDescriptor>,
+        amplitude: f32,
+        frequency: f32
+    },
+    Round {
+        shape: Box<ShapeDescriptor>,
+        radius: f32
+    },
+    /// Hollow shape with a shell of the given thickness
+    Onion {
+        shape: Box<ShapeDescriptor>,
+        thickness: f32
+    },
+    /// Stretch by the given length on each side of the origin
+    Elongate {
+        shape: Box<ShapeDescriptor>,
+        size: [f32;3]
+    },
+    /// Paint the whole shape with the color and reflectivity of the properties, visibility is ignored
+    Material {
+        shape: Box<ShapeDescriptor>,
+        properties: PropertiesDescriptor
+    },
+    /// Mix the materials of the two shapes of a composite over the width on each side of their seam
+    MaterialBlend {
+        shape: Box<ShapeDescriptor>,
+        width: f32
+    }
+}
+
+fn default_lights() -> Vec<LightDescriptor> {
+    let light = Light::default();
+    vec![LightDescriptor{ direction: light.direction, color: default_color() }]
+}
+
+fn default_color() -> [f32;3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_scale() -> [f32;3] {
+    [1.0, 1.0, 1.0]
+}
+
+impl PropertiesDescriptor {
+    fn to_properties(&self, root: bool) -> ShapeProperties {
+        let [r, g, b] = self.color;
+        ShapeProperties{
+            color: Color(r, g, b),
+            reflectivity: self.reflectivity,
+            // Children are drawn through their composite
+            visible: root && self.visible
+        }
+    }
+}
+
+impl ShapeDescriptor {
+    pub fn to_composite(&self, root: bool) -> CompositDescriptor {
+        let child = |desc: &ShapeDescriptor| Box::new(desc.to_composite(false));
+        match self {
+            ShapeDescriptor::Sphere { position, radius, properties } =>
+                CompositDescriptor::SPHERE(Sphere::new(*position, *radius), properties.to_properties(root)),
+            ShapeDescriptor::Cuboid { position, size, rotation, properties } =>
+                CompositDescriptor::CUBOID(Cuboid::new(*position, *size, *rotation), properties.to_properties(root)),
+            ShapeDescriptor::Torus { position, radius, thickness, rotation, properties } =>
+                CompositDescriptor::TORUS(Torus::new(*position, *radius, *thickness, *rotation), properties.to_properties(root)),
+            ShapeDescriptor::Plane { position, rotation, properties } =>
+                CompositDescriptor::primitive(&Plane::new(*position, *rotation), properties.to_properties(root)),
+            ShapeDescriptor::Capsule { position, height, radius, rotation, properties } =>
+                CompositDescriptor::primitive(&Capsule::new(*position, *rotation, *height, *radius), properties.to_properties(root)),
+            ShapeDescriptor::Cylinder { position, height, radius, rotation, properties } =>
+                CompositDescriptor::primitive(&Cylinder::new(*position, *rotation, *height, *radius), properties.to_properties(root)),
+            ShapeDescriptor::Cone { position, height, bottom_radius, top_radius, rotation, properties } =>
+                CompositDescriptor::primitive(&Cone::new(*position, *rotation, *height, *bottom_radius, *top_radius), properties.to_properties(root)),
+            ShapeDescriptor::Ellipsoid { position, radii, rotation, properties } =>
+                CompositDescriptor::primitive(&Ellipsoid::new(*position, *rotation, *radii), properties.to_properties(root)),
+            ShapeDescriptor::RoundedBox { position, size, radius, rotation, properties } =>
+                CompositDescriptor::primitive(&RoundedBox::new(*position, *rotation, *size, *radius), properties.to_properties(root)),
+            ShapeDescriptor::HexPrism { position, height, radius, rotation, properties } =>
+                CompositDescriptor::primitive(&HexPrism::new(*position, *rotation, *height, *radius), properties.to_properties(root)),
+            ShapeDescriptor::Union(a, b) => CompositDescriptor::UNION(child(a), child(b)),
+            ShapeDescriptor::Intersection(a, b) => CompositDescriptor::INTERSECTION(child(a), child(b)),
+            ShapeDescriptor::UnionAll(shapes) => CompositDescriptor::UNION_ALL(shapes.iter().map(|shape| shape.to_composite(false)).collect()),
+            ShapeDescriptor::IntersectionAll(shapes) => CompositDescriptor::INTERSECTION_ALL(shapes.iter().map(|shape| shape.to_composite(false)).collect()),
+            ShapeDescriptor::Difference(a, b) => CompositDescriptor::DIFFERENCE(child(a), child(b)),
+            ShapeDescriptor::Blend(a, b, alpha) => CompositDescriptor::BLEND(child(a), child(b), *alpha),
+            ShapeDescriptor::SmoothUnion(a, b, radius) => CompositDescriptor::SMOOTH_UNION(child(a), child(b), *radius),
+            ShapeDescriptor::SmoothIntersection(a, b, radius) => CompositDescriptor::SMOOTH_INTERSECTION(child(a), child(b), *radius),
+            ShapeDescriptor::SmoothDifference(a, b, radius) => CompositDescriptor::SMOOTH_DIFFERENCE(child(a), child(b), *radius),
+            ShapeDescriptor::Translate(a, position) => CompositDescriptor::TRANSLATE(child(a), *position),
+            ShapeDescriptor::Rotate(a, euler) => CompositDescriptor::ROTATE(child(a), *euler),
+            ShapeDescriptor::Scale(a, scale) => CompositDescriptor::SCALE(child(a), *scale),
+            ShapeDescriptor::Transform { shape, position, rotation, scale } =>
+                CompositDescriptor::TRANSFORM(child(shape), Transform::from_euler(*position, *rotation, *scale)),
+            ShapeDescriptor::Repeat { shape, size, limit } => CompositDescriptor::REPEAT(child(shape), *size, *limit),
+            ShapeDescriptor::Mirror { shape, normal, offset } => CompositDescriptor::MIRROR(child(shape), *normal, *offset),
+            ShapeDescriptor::Polar { shape, count } => CompositDescriptor::POLAR(child(shape), *count),
+            ShapeDescriptor::Twist { shape, rate, radius } => CompositDescriptor::TWIST(child(shape), *rate, *radius),
+            ShapeDescriptor::Bend { shape, rate, radius } => CompositDescriptor::BEND(child(shape), *rate, *radius),
+            ShapeDescriptor::Displace { shape, amplitude, frequency } => CompositDescriptor::DISPLACE(child(shape), *amplitude, *frequency),
+            ShapeDescriptor::Round { shape, radius } => CompositDescriptor::ROUND(child(shape), *radius),
+            ShapeDescriptor::Onion { shape, thickness } => CompositDescriptor::ONION(child(shape), *thickness),
+            ShapeDescriptor::Elongate { shape, size } => CompositDescriptor::ELONGATE(child(shape), *size),
+            // Both only modify the shape they wrap, which stays the root if they are
+            ShapeDescriptor::Material { shape, properties } =>
+                CompositDescriptor::MATERIAL(Box::new(shape.to_composite(root)), properties.to_properties(root)),
+            ShapeDescriptor::MaterialBlend { shape, width } =>
+                CompositDescriptor::MATERIAL_BLEND(Box::new(shape.to_composite(root)), *width),
+        }
+    }
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self, SceneError> {
+        let source = match fs::read_to_string(path) {
+            Ok(ok) => ok,
+            Err(err) => return Err(SceneError::IOError(path.to_path_buf(), err))
+        };
+        Self::parse(&source, path)
+    }
+
+    /// Parse a scene, `path` is only used in the error messages
+    pub fn parse(source: &str, path: &Path) -> Result<Self, SceneError> {
+        let scene: Scene = match ron::de::from_str(source) {
+            Ok(ok) => ok,
+            Err(err) => {
+                return Err(SceneError::ParseError(path.to_path_buf(), err.position.line, err.position.col, err.code.to_string()));
+            }
+        };
+        if scene.lights.len() > MAX_LIGHTS {
+            return Err(SceneError::TooManyLights(path.to_path_buf(), scene.lights.len()));
+        }
+        for (index, shape) in scene.shapes.iter().enumerate() {
+            let composite = shape.to_composite(true);
+            if let Err(err) = composite.validate() {
+                return Err(SceneError::InvalidShape(path.to_path_buf(), index, err));
+            }
+            let depth = composite.stack_usage().depth();
+            if depth > STACK_DEPTH {
+                return Err(SceneError::TooDeep(path.to_path_buf(), index, depth));
+            }
+        }
+        Ok(scene)
+    }
+
+    /// The scene shown when no scene file is given
+    pub fn demo() -> Self {
+        Self::parse(include_str!("../scenes/demo.ron"), Path::new("scenes/demo.ron")).unwrap()
+    }
+
+    /// Add every shape of the scene, the shapes before the first one that doesn't fit in the shader stay
+    pub fn build_shapes(&self, shape_collection: &mut ShapeCollection) -> Result<(), ShapeError> {
+        for shape in self.shapes.iter() {
+            shape_collection.create_composite(&shape.to_composite(true))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_camera(&self, camera_manager: &mut CameraManager) {
+        camera_manager.set_screen_depth(self.camera.screen_depth);
+        camera_manager.orbit(self.camera.angle, self.camera.distance);
+    }
+
+    pub fn lights(&self) -> Vec<Light> {
+        self.lights.iter().map(|light| {
+            let [r, g, b] = light.color;
+            Light{ direction: light.direction, color: Color(r, g, b) }
+        }).collect()
+    }
+}
+
+/// Reloads a scene file when it is modified
+pub struct SceneWatcher {
+    path: PathBuf,
+    watcher: FileWatcher
+}
+
+impl SceneWatcher {
+    pub fn new(path: &Path) -> Self {
+        Self{ path: path.to_path_buf(), watcher: FileWatcher::new(vec![path.to_path_buf()]) }
+    }
+
+    /// Reload the scene if the file changed since the last reload
+    pub fn poll(&mut self) -> Option<Result<Scene, SceneError>> {
+        if !self.watcher.poll() {
+            return None;
+        }
+        Some(Scene::load(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path, line and column of the parse error of `source`
+    fn parse_error(source: &str) -> (PathBuf, usize, usize, String) {
+        match Scene::parse(source, Path::new("scenes/broken.ron")) {
+            Err(SceneError::ParseError(path, line, col, message)) => (path, line, col, message),
+            other => panic!("Expected a parse error, got {:?}", other.map(|scene| scene.shapes.len()))
+        }
+    }
+
+    #[test]
+    fn parse_errors_give_their_position() {
+        let source = "Scene(\n    shapes: [\n        Sphere(position: (0.0, 0.0 0.0), radius: 1.0),\n    ],\n)";
+        let (path, line, col, _) = parse_error(source);
+        assert_eq!(path, PathBuf::from("scenes/broken.ron"));
+        assert_eq!((line, col), (3, 36));
+    }
+
+    #[test]
+    fn unknown_shapes_are_refused() {
+        let source = "Scene(\n    shapes: [\n        Sphere(position: (0.0, 0.0, 0.0), radius: 1.0),\n        Dodecahedron(size: 1.0),\n    ],\n)";
+        let (_, line, _, message) = parse_error(source);
+        assert_eq!(line, 4);
+        assert!(message.contains("Dodecahedron"), "{}", message);
+    }
+
+    #[test]
+    fn empty_groups_are_refused() {
+        let source = "Scene(shapes: [Sphere(position: (0.0, 0.0, 0.0), radius: 1.0), Translate(IntersectionAll([]), (1.0, 0.0, 0.0))])";
+        let err = Scene::parse(source, Path::new("empty.ron")).err();
+        assert!(matches!(err, Some(SceneError::InvalidShape(_, 1, ShapeError::EmptyGroup))), "{:?}", err);
+    }
+
+    #[test]
+    fn null_mirror_normals_are_refused() {
+        let source = "Scene(shapes: [Mirror(shape: Sphere(position: (1.0, 0.0, 0.0), radius: 1.0), normal: (0.0, 0.0, 0.0))])";
+        let err = Scene::parse(source, Path::new("mirror.ron")).err();
+        assert!(matches!(err, Some(SceneError::InvalidShape(_, 0, ShapeError::ZeroNormal(_)))), "{:?}", err);
+    }
+}
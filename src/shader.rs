@@ -0,0 +1,99 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
+use crate::template::{Template, TemplateError};
+use crate::watcher::FileWatcher;
+
+/// Template of the ray marcher, relative to the crate root
+pub const RAYMARCHER_TEMPLATE: &str = "src/shaders/raymarcher2.wgsl.json";
+/// Ray marcher generated by the build script
+pub const RAYMARCHER_SHADER: &str = include_str!("shaders/raymarcher2.wgsl");
+/// Variant of the ray marcher without shadows nor reflections
+pub const RAYMARCHER_FLAT_SHADER: &str = include_str!("shaders/raymarcher2_flat.wgsl");
+/// Variant of the ray marcher coloring the pixels with the normal of the first hit
+pub const RAYMARCHER_NORMALS_SHADER: &str = include_str!("shaders/raymarcher2_normals.wgsl");
+/// Variant of the ray marcher calling `scene_sdf`, to be replaced by the one generated from the scene
+pub const RAYMARCHER_SPECIALIZED_SHADER: &str = include_str!("shaders/raymarcher2_specialized.wgsl");
+
+/// How the pixels are shaded, each mode being a variant of the ray marcher, written `full`, `flat` or `normals`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Shading {
+    /// Shadows and reflections
+    #[default]
+    Full,
+    /// Only the diffuse lighting of the first hit
+    Flat,
+    /// Normal of the first hit as a color
+    Normals
+}
+
+impl Shading {
+    /// Ray marcher generated by the build script for this mode
+    pub fn source(self) -> &'static str {
+        match self {
+            Shading::Full => RAYMARCHER_SHADER,
+            Shading::Flat => RAYMARCHER_FLAT_SHADER,
+            Shading::Normals => RAYMARCHER_NORMALS_SHADER
+        }
+    }
+}
+
+impl FromStr for Shading {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Shading::Full),
+            "flat" => Ok(Shading::Flat),
+            "normals" => Ok(Shading::Normals),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ShaderError {
+    #[error(transparent)]
+    TemplateError(#[from] TemplateError),
+    #[error("Failed to create the pipeline : \n {0}")]
+    PipelineError(String),
+}
+
+/// Re-assembles the ray marcher template when it or one of its components changes
+pub struct ShaderReloader {
+    root: PathBuf,
+    template_path: PathBuf,
+    watcher: FileWatcher
+}
+
+impl ShaderReloader {
+    pub fn new() -> Self {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let template_path = root.join(RAYMARCHER_TEMPLATE);
+        let mut paths = vec![template_path.clone()];
+        if let Ok(template) = Template::load(&template_path) {
+            paths.extend(template.component_paths(&root));
+        }
+        Self{ root, template_path, watcher: FileWatcher::new(paths) }
+    }
+
+    /// New validated shader source if the template or one of its components changed
+    pub fn poll(&mut self) -> Option<Result<String, ShaderError>> {
+        if !self.watcher.poll() {
+            return None;
+        }
+        Some(self.reload())
+    }
+
+    fn reload(&mut self) -> Result<String, ShaderError> {
+        let template = Template::load(&self.template_path)?;
+        // Components may have been added or removed from the template
+        let mut paths = vec![self.template_path.clone()];
+        paths.extend(template.component_paths(&self.root));
+        self.watcher.set_paths(paths);
+
+        let shader = template.assemble(&self.root)?;
+        shader.validate()?;
+        Ok(shader.source)
+    }
+}
@@ -1,3 +1,5 @@
+#include "types.wgsl"
+
 // Texture that recieve the result of the computations
 @group(0) @binding(0)
 var target_texture: texture_storage_2d<rgba8unorm, write>;
@@ -13,7 +15,17 @@ var<storage> spheres: array<Sphere>;
 var<storage> cuboids: array<Cuboid>;
 @group(1) @binding(4)
 var<storage> composites: array<Composite>;
+@group(1) @binding(5)
+var<storage> tori: array<Torus>;
+@group(1) @binding(6)
+var<storage> primitives: array<Primitive>;
+@group(1) @binding(7)
+var<storage> instructions: array<Instruction>;
 
 // Camera bind group
 @group(2) @binding(0)
-var<uniform> camera: Camera;
\ No newline at end of file
+var<uniform> camera: Camera;
+
+// Lights bind group
+@group(3) @binding(0)
+var<uniform> lights: Lights;
\ No newline at end of file
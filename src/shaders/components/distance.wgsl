@@ -1,21 +1,30 @@
-var<private> shape_stack: array<i32,20u>;
-var<private> shape_stack_pointer : u32 = 0u;
-var<private> res_stack: array<f32,20u>;
+#include "bindings.wgsl"
+#include "math.wgsl"
+#include "transform.wgsl"
+#include "domain.wgsl"
+#include "distances.wgsl"
+
+// Point in the frame of each node being evaluated, w scales the distances of that frame
+var<private> frame_stack: array<vec4<f32>,STACK_DEPTH>;
+var<private> frame_stack_pointer : u32 = 0u;
+var<private> res_stack: array<f32,STACK_DEPTH>;
 var<private> res_stack_pointer : u32 = 0u;
 
-fn add_sstack(s: i32){
-    shape_stack[shape_stack_pointer] = s;
-    shape_stack_pointer=shape_stack_pointer+ 1u;
+fn add_fstack(p: vec4<f32>){
+    frame_stack[frame_stack_pointer] = p;
+    frame_stack_pointer = frame_stack_pointer + 1u;
 };
 
-fn pop_sstack()->i32{
-    shape_stack_pointer = shape_stack_pointer - 1u;
-    let res = shape_stack[shape_stack_pointer];
-    return res;
+fn pop_fstack(){
+    frame_stack_pointer = frame_stack_pointer - 1u;
 };
 
-fn clear_sstack(){
-    shape_stack_pointer = 0u;
+fn top_fstack()->vec4<f32>{
+    return frame_stack[frame_stack_pointer - 1u];
+};
+
+fn clear_fstack(){
+    frame_stack_pointer = 0u;
 };
 
 
@@ -34,88 +43,145 @@ fn clear_rstack(){
     res_stack_pointer = 0u;
 };
 
+// Distance to a shape that isn't a composite, `frame` being the point in the frame of its parent
+fn leaf_distance(frame: vec4<f32>, shape: Shape)->f32{
+    let local = transform_point(frame, shape.transform);
+    var d: f32;
+    switch(shape.shape_type){
+        case 0u:{
+            d = sphere_distance(local.xyz, spheres[shape.index]);
+        }
+        case 1u:{
+            d = cube_distance(local.xyz, cuboids[shape.index]);
+        }
+        case 2u:{
+            d = torus_distance(local.xyz, tori[shape.index]);
+        }
+        // Primitives of the registry
+        default:{
+            d = primitive_distance(local.xyz, shape.shape_type, primitives[shape.index]);
+        }
+    }
+    return d * local.w;
+};
 
+// Distance of two children combined by the composite c, `w` scaling the distances of the frame of the composite.
+// The program evaluates b before a unless a_first is set, so the result of a is on top of the stack.
+fn combine_distances(a: f32, b: f32, c: Composite, w: f32)->f32{
+    var d = 0.0;
+    switch(binary_op(c.t)){
+        case 0u:{
+            d = min(a,b);
+        }
+        case 1u:{
+            d = max(a,b);
+        }
+        case 2u:{
+            d = max(b,-a);
+        }
+        case 3u:{
+            d = smooth_max(a,b,-c.alpha);
+        }
+        case 14u:{
+            d = poly_smooth_min(a, b, c.alpha * w).x;
+        }
+        case 15u:{
+            d = poly_smooth_max(a, b, c.alpha * w).x;
+        }
+        case 16u:{
+            d = poly_smooth_max(b, -a, c.alpha * w).x;
+        }
+        default:{}
+    }
+    return d;
+};
+
+// Runs the program of the composite `root`, see program.rs for the instructions
 fn shape_distance(point: vec3<f32>, root:u32, skip:i32)-> DistRes{
+    var res: DistRes;
+    res.lipschitz = 1.0;
+    let root_shape = shapes[root];
+    if(root_shape.shape_type != 9u){
+        res.distance = leaf_distance(vec4<f32>(point, 1.0), root_shape);
+        if(i32(root) == skip){
+            res.distance = 9999999.0;
+        }
+        res.index = root;
+        return res;
+    }
+
     clear_rstack();
-    clear_sstack();
-    add_sstack(-i32(root+1u));
+    clear_fstack();
+    add_fstack(vec4<f32>(point, 1.0));
 
     var mdist : f32 = 99999999999.0;
     var midx: u32 = 0u;
     var skip_sign = 1.0;
+    var lipschitz = 1.0;
+    var pc = composites[root_shape.index].program;
     loop {
-        if(shape_stack_pointer == 0u){break;}
-
-        let current = pop_sstack();
-        if (current<0){
-            let index = u32(-current) - 1u;
-            let shape = shapes[index];
+        let instruction = instructions[pc];
+        pc = pc + 1u;
+        if(instruction.op == OP_END){break;}
 
-            add_sstack(i32(index));
-            switch(shape.shape_type){
-                case 9u:{
-                    let c = composites[shape.index];
-                    add_sstack(-i32(c.a+1u));
-                    add_sstack(-i32(c.b+1u));
-                    if(c.t == 2u && i32(c.a) == skip){
-                        skip_sign = -1.0;
-                    }
+        let shape = shapes[instruction.shape];
+        let frame = top_fstack();
+        switch(instruction.op){
+            // OP_LEAF
+            case 1u:{
+                var d = leaf_distance(frame, shape);
+                if (i32(instruction.shape) == skip){
+                    d = 9999999.0 * skip_sign;
                 }
-                default:{}
+                if(mdist>abs(d)){
+                    mdist = abs(d);
+                    midx = instruction.shape;
+                }
+                add_rstack(d);
             }
-        }else{
-            let index = u32(current);
-            let shape = shapes[index];
-
-            switch(shape.shape_type){
-                case 0u:{
-                    var d = sphere_distance(point, spheres[shape.index]);
-                    if (i32(index) == skip){
-                        d = 9999999.0 * skip_sign;
-                    }
-                    if(mdist>abs(d)){
-                        mdist = abs(d);
-                        midx = index;
-                    }
-                    add_rstack(d);
+            // OP_PUSH
+            case 2u:{
+                let c = composites[shape.index];
+                add_fstack(transform_point(frame, shape.transform));
+                if((c.t == 2u || c.t == 16u) && i32(c.a) == skip){
+                    skip_sign = -1.0;
                 }
-                case 1u:{
-                    var d = cube_distance(point, cuboids[shape.index]);
-                    if (i32(index) == skip){
-                        d = 9999999.0 * skip_sign;
-                    }
-                    if(mdist>abs(d)){
-                        mdist = abs(d);
-                        midx = index;
-                    }
-                    add_rstack(d);
+            }
+            // OP_PUSH_DOMAIN
+            case 3u:{
+                let c = composites[shape.index];
+                add_fstack(domain_point(transform_point(frame, shape.transform), c));
+                // Deformations can shrink distances, the product of their factors bounds the whole tree
+                if(c.t >= 8u){
+                    lipschitz = lipschitz * c.alpha;
                 }
-                case 9u:{
-                    let c = composites[shape.index];
-                    let a = pop_rstack();
-                    let b = pop_rstack();
-                    switch(c.t){
-                        case 0u:{
-                            add_rstack(min(a,b));
-                        }
-                        case 1u:{
-                            add_rstack(max(a,b));
-                        }
-                        case 2u:{
-                            add_rstack(max(b,-a));
-                        }
-                        case 3u:{
-                            add_rstack(smooth_max(a,b,-c.alpha));
-                        }
-                        default:{}
-                    }
+            }
+            // OP_COMBINE
+            case 4u:{
+                let a = pop_rstack();
+                let b = pop_rstack();
+                let c = composites[shape.index];
+                if(c.a_first != 0u){
+                    add_rstack(combine_distances(b, a, c, frame.w));
+                }else{
+                    add_rstack(combine_distances(a, b, c, frame.w));
                 }
-                default:{}
             }
+            // OP_POP
+            case 5u:{
+                pop_fstack();
+            }
+            // OP_APPLY, nodes with a single child only change the result of the child, if at all
+            case 6u:{
+                pop_fstack();
+                let local = transform_point(top_fstack(), shape.transform);
+                add_rstack(domain_distance(pop_rstack(), local, composites[shape.index]));
+            }
+            default:{}
         }
     }
-    var res: DistRes;
     res.distance = pop_rstack();
     res.index = midx;
+    res.lipschitz = lipschitz;
     return res;
-};
\ No newline at end of file
+};
@@ -1,3 +1,6 @@
+#include "types.wgsl"
+#include "primitives.wgsl"
+
 fn cube_distance(a:vec3<f32>, b:Cuboid)->f32{
     let a_centered = a-b.pos;
     let a_rotated = a_centered*b.rotation;
@@ -14,3 +17,9 @@ fn cube_distance(a:vec3<f32>, b:Cuboid)->f32{
 fn sphere_distance(a: vec3<f32>, b:Sphere)->f32{
     return distance(a,b.pos) - b.radius;
 };
+
+fn torus_distance(a: vec3<f32>, b:Torus)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let q = vec2<f32>(length(a_rotated.xz) - b.radius, a_rotated.y);
+    return length(q) - b.thickness;
+};
@@ -0,0 +1,164 @@
+#include "types.wgsl"
+
+let PI = 3.14159265;
+
+// Transform, domain and deformation nodes only have one child, stored in both a and b
+fn single_child(t: u32)->bool{
+    return t >= 4u && t <= 13u;
+};
+
+// Binary operation folding the children of a node, n-ary unions and intersections fold them like the binary ones
+fn binary_op(t: u32)->u32{
+    var op = t;
+    switch(t){
+        case 17u:{
+            op = 0u;
+        }
+        case 18u:{
+            op = 1u;
+        }
+        default:{}
+    }
+    return op;
+};
+
+// Value of the lattice point p, in [-1, 1]
+fn lattice_hash(p: vec3<f32>)->f32{
+    return fract(sin(dot(p, vec3<f32>(127.1, 311.7, 74.7))) * 43758.5453) * 2.0 - 1.0;
+};
+
+// Value noise in [-1, 1], smoothly interpolated between the lattice points
+fn value_noise(p: vec3<f32>)->f32{
+    let i = floor(p);
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let x00 = mix(lattice_hash(i), lattice_hash(i + vec3<f32>(1.0, 0.0, 0.0)), u.x);
+    let x10 = mix(lattice_hash(i + vec3<f32>(0.0, 1.0, 0.0)), lattice_hash(i + vec3<f32>(1.0, 1.0, 0.0)), u.x);
+    let x01 = mix(lattice_hash(i + vec3<f32>(0.0, 0.0, 1.0)), lattice_hash(i + vec3<f32>(1.0, 0.0, 1.0)), u.x);
+    let x11 = mix(lattice_hash(i + vec3<f32>(0.0, 1.0, 1.0)), lattice_hash(i + vec3<f32>(1.0, 1.0, 1.0)), u.x);
+    return mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z);
+};
+
+// Point given to the child of a composite, only repetition, mirror and deformation nodes change it.
+// w is kept, the deformations that stretch space are corrected by their Lipschitz factor in send_ray.
+fn domain_point(p: vec4<f32>, c: Composite)->vec4<f32>{
+    var q = p.xyz;
+    switch(c.t){
+        // Repetition, the cell size is in domain.xyz, a size of 0 doesn't repeat along the axis
+        case 5u:{
+            var cell = round(q / c.domain.xyz);
+            // Limited repetition, limit.xyz copies on each side
+            if(c.limit.w > 0.0){
+                cell = clamp(cell, -c.limit.xyz, c.limit.xyz);
+            }
+            q = select(q, q - c.domain.xyz * cell, c.domain.xyz > vec3<f32>(0.0, 0.0, 0.0));
+        }
+        // Mirror across the plane of normal domain.xyz at distance domain.w from the origin
+        case 6u:{
+            let d = dot(q, c.domain.xyz) - c.domain.w;
+            if(d < 0.0){
+                q = q - 2.0 * d * c.domain.xyz;
+            }
+        }
+        // Polar repetition around y, domain.x copies
+        case 7u:{
+            let sector = 2.0 * PI / c.domain.x;
+            let angle = round(atan2(q.z, q.x) / sector) * sector;
+            q = vec3<f32>(q.x * cos(angle) + q.z * sin(angle), q.y, q.z * cos(angle) - q.x * sin(angle));
+        }
+        // Twist around y, domain.x radians per unit
+        case 8u:{
+            let angle = c.domain.x * q.y;
+            q = vec3<f32>(cos(angle) * q.x - sin(angle) * q.z, q.y, sin(angle) * q.x + cos(angle) * q.z);
+        }
+        // Bend of the x axis toward y, domain.x radians per unit
+        case 9u:{
+            let angle = c.domain.x * q.x;
+            q = vec3<f32>(cos(angle) * q.x - sin(angle) * q.y, sin(angle) * q.x + cos(angle) * q.y, q.z);
+        }
+        // Elongation, the child is stretched by domain.xyz on each side of the origin
+        case 13u:{
+            q = q - clamp(q, -c.domain.xyz, c.domain.xyz);
+        }
+        default:{}
+    }
+    return vec4<f32>(q, p.w);
+};
+
+// Distance of a composite with a single child from the distance `d` of the child,
+// `p` being the point in the frame of the composite
+fn domain_distance(d: f32, p: vec4<f32>, c: Composite)->f32{
+    var res = d;
+    switch(c.t){
+        // Displacement by value noise, domain.x is the amplitude and domain.y the frequency
+        case 10u:{
+            res = d + c.domain.x * value_noise(p.xyz * c.domain.y) * p.w;
+        }
+        // Rounding by domain.x
+        case 11u:{
+            res = d - c.domain.x * p.w;
+        }
+        // Shell of thickness domain.x
+        case 12u:{
+            res = abs(d) - c.domain.x * p.w;
+        }
+        default:{}
+    }
+    return res;
+};
+
+// Matrix taking the normals of the child of a composite to the frame of the composite, `p` is not remapped yet
+fn domain_normal_matrix(p: vec3<f32>, c: Composite)->mat3x3<f32>{
+    var m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+    switch(c.t){
+        case 6u:{
+            let n = c.domain.xyz;
+            if(dot(p, n) - c.domain.w < 0.0){
+                m = mat3x3<f32>(
+                    vec3<f32>(1.0, 0.0, 0.0) - 2.0 * n.x * n,
+                    vec3<f32>(0.0, 1.0, 0.0) - 2.0 * n.y * n,
+                    vec3<f32>(0.0, 0.0, 1.0) - 2.0 * n.z * n
+                );
+            }
+        }
+        case 7u:{
+            let sector = 2.0 * PI / c.domain.x;
+            let angle = round(atan2(p.z, p.x) / sector) * sector;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), 0.0, sin(angle)),
+                vec3<f32>(0.0, 1.0, 0.0),
+                vec3<f32>(-sin(angle), 0.0, cos(angle))
+            );
+        }
+        // The deformations are approximated by the rotation at the point, without their shear
+        case 8u:{
+            let angle = c.domain.x * p.y;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), 0.0, -sin(angle)),
+                vec3<f32>(0.0, 1.0, 0.0),
+                vec3<f32>(sin(angle), 0.0, cos(angle))
+            );
+        }
+        case 9u:{
+            let angle = c.domain.x * p.x;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), -sin(angle), 0.0),
+                vec3<f32>(sin(angle), cos(angle), 0.0),
+                vec3<f32>(0.0, 0.0, 1.0)
+            );
+        }
+        default:{}
+    }
+    return m;
+};
+
+// Gradient of the displacement of a displacement node in its frame, added to the normal of its child
+fn displacement_gradient(p: vec3<f32>, c: Composite)->vec3<f32>{
+    let e = 0.001;
+    let f = c.domain.y;
+    return c.domain.x * vec3<f32>(
+        value_noise((p + vec3<f32>(e, 0.0, 0.0)) * f) - value_noise((p - vec3<f32>(e, 0.0, 0.0)) * f),
+        value_noise((p + vec3<f32>(0.0, e, 0.0)) * f) - value_noise((p - vec3<f32>(0.0, e, 0.0)) * f),
+        value_noise((p + vec3<f32>(0.0, 0.0, e)) * f) - value_noise((p - vec3<f32>(0.0, 0.0, e)) * f)
+    ) / (2.0 * e);
+};
@@ -1,8 +1,16 @@
+#include "geometry.wgsl"
+#include "surface.wgsl"
+#include "ray.wgsl"
+
 @stage(compute) @workgroup_size(16,16)
 fn render(@builtin(global_invocation_id) global_invocation_id: vec3<u32>){
     let target_size = textureDimensions(target_texture);
     let x = global_invocation_id.x;
     let y = global_invocation_id.y;
+    // The workgroup count is rounded up, the last workgroups go past the edges of the texture
+    if (x >= u32(target_size.x) || y >= u32(target_size.y)) {
+        return;
+    }
     let width = f32(target_size[0]);
     let height = f32(target_size[1]);
 
@@ -11,8 +19,11 @@ fn render(@builtin(global_invocation_id) global_invocation_id: vec3<u32>){
     let shadow_blur = 5.0;
     let hit_threshold = 0.00001;
     let background_color = vec3<f32>(0.005, 0.0, 0.03);
-    let light_direction = normalize(vec3<f32>(-1.0, -1.0, 0.4));
+#if REFLECTIONS
     let reflection_rays = 10u;
+#else
+    let reflection_rays = 1u;
+#endif
     let reflection_threshold = 0.000001;
 
     let shape_count = 5u;
@@ -45,6 +56,10 @@ fn render(@builtin(global_invocation_id) global_invocation_id: vec3<u32>){
         }
         var s = shapes[latest_hit.hit_shape];
         var surface_info = shape_surface(latest_hit.hit_pos, u32(latest_hit.root_shape));
+#if DEBUG_NORMALS
+        color = surface_info.normal * 0.5 + vec3<f32>(0.5, 0.5, 0.5);
+        break;
+#else
         var matcolor = surface_info.color;
         let reflectivity = surface_info.reflectivity;
         let matness = 1.0 - reflectivity;
@@ -52,29 +67,38 @@ fn render(@builtin(global_invocation_id) global_invocation_id: vec3<u32>){
 
 
         let normal = surface_info.normal;//shape_normal(latest_hit.hit_pos,u32(latest_hit.hit_shape));
-        let diffuse = vcos(normal, -light_direction);
-        matcolor = matcolor * diffuse ;
-        // Applying mat lighting
-        if (diffuse>0.00001){
-            var light_ray : RayParams;
-            light_ray.max_length = 2000.0;
-            light_ray.max_step = 200u;
-            light_ray.threshold = 0.0000001;
-            light_ray.skip_shape = -1;
-            let light_hit = send_ray(latest_hit.hit_pos, -light_direction, light_ray);
-            matcolor = matcolor * max(0.0,-f32(light_hit.hit_shape));
-        };
+        var lit = vec3<f32>(0.0,0.0,0.0);
+        for(var l:u32 = 0u; l < lights.count; l=l+1u){
+            let light = lights.lights[l];
+            let light_direction = normalize(light.direction);
+            let diffuse = vcos(normal, -light_direction);
+            var light_color = matcolor * light.color * diffuse;
+            // Applying mat lighting
+#if SHADOWS
+            if (diffuse>0.00001){
+                var light_ray : RayParams;
+                light_ray.max_length = 2000.0;
+                light_ray.max_step = 200u;
+                light_ray.threshold = 0.0000001;
+                light_ray.skip_shape = -1;
+                let light_hit = send_ray(latest_hit.hit_pos, -light_direction, light_ray);
+                light_color = light_color * max(0.0,-f32(light_hit.hit_shape));
+            };
+#endif
 
-        //Specular lighting
-        let light_reflection = reflection(light_direction, normal);
-        let specular = reflectivity*pow(abs(vcos(light_reflection, ray_direction)),45.0)*max(0.0,diffuse);
+            //Specular lighting
+            let light_reflection = reflection(light_direction, normal);
+            let specular = reflectivity*pow(abs(vcos(light_reflection, ray_direction)),45.0)*max(0.0,diffuse);
 
-        color+=vec3<f32>(specular,specular,specular);
+            color += light.color * specular;
+            lit += light_color;
+        }
 
-        color += matcolor * color_weight * matness;
+        color += lit * color_weight * matness;
         color_weight = color_weight * reflectivity;
         ray_direction = reflection(ray_direction, normal);
         bounce_count += 1u;
+#endif
     }
     textureStore(target_texture, vec2<i32>(i32(x),i32(y)), vec4<f32>(color,1.0));
 };
\ No newline at end of file
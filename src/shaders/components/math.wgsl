@@ -1,3 +1,16 @@
 fn smooth_max(a:f32, b:f32, alpha:f32)->f32{
     return (a * exp2(a * alpha) + b * exp2(b * alpha))/(exp2(a * alpha) + exp2(b * alpha));
 };
+
+// Polynomial smooth minimum of a and b over the radius k, y is the weight of a in the result
+fn poly_smooth_min(a:f32, b:f32, k:f32)->vec2<f32>{
+    let radius = max(k, 0.000001);
+    let h = clamp(0.5 + 0.5 * (b - a) / radius, 0.0, 1.0);
+    return vec2<f32>(mix(b, a, h) - radius * h * (1.0 - h), h);
+};
+
+// Polynomial smooth maximum of a and b over the radius k, y is the weight of a in the result
+fn poly_smooth_max(a:f32, b:f32, k:f32)->vec2<f32>{
+    let res = poly_smooth_min(-a, -b, k);
+    return vec2<f32>(-res.x, res.y);
+};
@@ -1,18 +1,28 @@
-
+#include "bindings.wgsl"
+#include "normals.wgsl"
+#include "transform.wgsl"
 
 fn shape_normal(point: vec3<f32>, index:u32)-> vec3<f32>{
     let shape = shapes[index];
+    let local = transform_point(vec4<f32>(point, 1.0), shape.transform).xyz;
     var ret : vec3<f32>;
     switch(shape.shape_type){
         case 0u:{
-            ret = sphere_normal(point, spheres[shape.index]);
+            ret = sphere_normal(local, spheres[shape.index]);
         }
         case 1u:{
-            ret = cube_normal(point, cuboids[shape.index]);
+            ret = cube_normal(local, cuboids[shape.index]);
         }
-        default:{
+        case 2u:{
+            ret = torus_normal(local, tori[shape.index]);
+        }
+        case 9u:{
             ret = vec3<f32>(1.0, 0.0, 0.0);
         }
+        // Primitives of the registry
+        default:{
+            ret = primitive_normal(local, shape.shape_type, primitives[shape.index]);
+        }
     }
-    return ret;
+    return normalize(transform_normal_matrix(shape.transform) * ret);
 };
@@ -1,3 +1,6 @@
+#include "types.wgsl"
+#include "distances.wgsl"
+
 fn cube_normal(a:vec3<f32>, b:Cuboid)->vec3<f32>{
     let a_centered = a-b.pos;
     let a_rotated = a_centered*b.rotation;
@@ -50,3 +53,19 @@ fn cube_normal(a:vec3<f32>, b:Cuboid)->vec3<f32>{
 fn sphere_normal(point: vec3<f32>, sphere:Sphere)->vec3<f32>{
     return normalize(point - sphere.pos);
 };
+
+fn torus_normal(point: vec3<f32>, torus:Torus)->vec3<f32>{
+    let a_rotated = (point-torus.pos)*torus.rotation;
+    // Closest point of the center circle of the tube
+    let ring = normalize(vec3<f32>(a_rotated.x, 0.0, a_rotated.z)) * torus.radius;
+    return torus.rotation * normalize(a_rotated - ring);
+};
+
+// Gradient of the distance, for the primitives without an analytic normal
+fn primitive_normal(point: vec3<f32>, shape_type:u32, primitive:Primitive)->vec3<f32>{
+    let e = 0.0001;
+    let dx = primitive_distance(point + vec3<f32>(e, 0.0, 0.0), shape_type, primitive) - primitive_distance(point - vec3<f32>(e, 0.0, 0.0), shape_type, primitive);
+    let dy = primitive_distance(point + vec3<f32>(0.0, e, 0.0), shape_type, primitive) - primitive_distance(point - vec3<f32>(0.0, e, 0.0), shape_type, primitive);
+    let dz = primitive_distance(point + vec3<f32>(0.0, 0.0, e), shape_type, primitive) - primitive_distance(point - vec3<f32>(0.0, 0.0, e), shape_type, primitive);
+    return normalize(vec3<f32>(dx, dy, dz));
+};
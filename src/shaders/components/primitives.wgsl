@@ -0,0 +1,38 @@
+#include "types.wgsl"
+#include "../primitives/plane.wgsl"
+#include "../primitives/capsule.wgsl"
+#include "../primitives/cylinder.wgsl"
+#include "../primitives/cone.wgsl"
+#include "../primitives/ellipsoid.wgsl"
+#include "../primitives/rounded_box.wgsl"
+#include "../primitives/hex_prism.wgsl"
+
+// Replaced at runtime by PrimitiveRegistry::shader_source with the registered primitives
+fn primitive_distance(a: vec3<f32>, shape_type:u32, b:Primitive)->f32{
+    var ret = 9999999.0;
+    switch(shape_type){
+        case 3u:{
+            ret = plane_distance(a, b);
+        }
+        case 4u:{
+            ret = capsule_distance(a, b);
+        }
+        case 5u:{
+            ret = cylinder_distance(a, b);
+        }
+        case 6u:{
+            ret = cone_distance(a, b);
+        }
+        case 7u:{
+            ret = ellipsoid_distance(a, b);
+        }
+        case 8u:{
+            ret = rounded_box_distance(a, b);
+        }
+        case 10u:{
+            ret = hex_prism_distance(a, b);
+        }
+        default:{}
+    }
+    return ret;
+};
@@ -1,3 +1,6 @@
+#include "distance.wgsl"
+#include "scene.wgsl"
+
 fn send_ray(origin:vec3<f32>, direction:vec3<f32>, params: RayParams)->Hit{
     var res: Hit;
     var step_count = 0u;
@@ -16,15 +19,25 @@ fn send_ray(origin:vec3<f32>, direction:vec3<f32>, params: RayParams)->Hit{
     loop {
         var closest_distance : f32 = 9999999999.0;
         closest_shape = -1;
+#if SCENE_SDF
+        // Single function generated from the scene, the hit shape is the root shape and skip_shape is ignored
+        let scene_dist_r = scene_sdf(ray_pos);
+        closest_distance = scene_dist_r.distance;
+        closest_shape = i32(scene_dist_r.index);
+        root_shape = closest_shape;
+#else
         for(var i:u32 = 0u; i < shape_count.count && threshold < closest_distance; i=i+1u){
             if (i32(i) == skip_shape || shapes[i].visible == 0u){continue;}
             let shape_dist_r = shape_distance(ray_pos, i, skip_shape);
-            if(closest_distance > shape_dist_r.distance){
+            // Shorter steps through deformed shapes so that the ray doesn't go through them
+            let distance = shape_dist_r.distance / shape_dist_r.lipschitz;
+            if(closest_distance > distance){
                 closest_shape = i32(shape_dist_r.index);
                 root_shape = i32(i);
-                closest_distance = shape_dist_r.distance;
+                closest_distance = distance;
             }
         }
+#endif
         ray_pos += direction * closest_distance;
         ray_length += closest_distance;
         step_count += 1u;
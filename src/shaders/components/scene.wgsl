@@ -0,0 +1,21 @@
+#include "distance.wgsl"
+
+// Distance to the closest visible shape, index being that root shape and the distance being divided by its lipschitz factor.
+// The specialized ray marcher replaces this function by the one generated from the scene (see specialize.rs),
+// this one runs the program of every shape.
+fn scene_sdf(p: vec3<f32>)->DistRes{
+    var res: DistRes;
+    res.distance = 9999999999.0;
+    res.index = 0u;
+    res.lipschitz = 1.0;
+    for(var i:u32 = 0u; i < shape_count.count; i=i+1u){
+        if (shapes[i].visible == 0u){continue;}
+        let shape_dist_r = shape_distance(p, i, -1);
+        let distance = shape_dist_r.distance / shape_dist_r.lipschitz;
+        if(res.distance > distance){
+            res.distance = distance;
+            res.index = i;
+        }
+    }
+    return res;
+};
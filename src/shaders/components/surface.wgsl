@@ -1,6 +1,11 @@
-// Note we reuse the shape stack from the distance function
-var<private> sres_stack: array<SurfaceInfo,20u>;
+#include "distance.wgsl"
+#include "normals.wgsl"
+
+// Note we reuse the frame and result stacks from the distance function
+var<private> sres_stack: array<SurfaceInfo,STACK_DEPTH>;
 var<private> sres_stack_pointer : u32 = 0u;
+// Matrix taking the normals in each frame of the frame stack to world space
+var<private> normal_stack: array<mat3x3<f32>,STACK_DEPTH>;
 
 fn add_srstack(s:SurfaceInfo){
     sres_stack[sres_stack_pointer] = s;
@@ -13,106 +18,211 @@ fn pop_srstack()->SurfaceInfo{
     return res;
 };
 
+fn add_nstack(p: vec4<f32>, normal_matrix: mat3x3<f32>){
+    normal_stack[frame_stack_pointer] = normal_matrix;
+    add_fstack(p);
+};
+
+// Normal matrix of the frame on top of the frame stack
+fn top_normal_matrix()->mat3x3<f32>{
+    return normal_stack[frame_stack_pointer - 1u];
+};
+
+// Surface of a mixed with the surface of b, the weights being the ones of a.
+// Every material field of SurfaceInfo is mixed with material_weight.
+fn combine_surfaces(a: SurfaceInfo, b: SurfaceInfo, normal_weight: f32, material_weight: f32)->SurfaceInfo{
+    var res: SurfaceInfo;
+    res.normal = normalize(mix(b.normal, a.normal, normal_weight));
+    res.color = mix(b.color, a.color, material_weight);
+    res.reflectivity = mix(b.reflectivity, a.reflectivity, material_weight);
+    return res;
+};
+
 fn clear_srstack(){
     sres_stack_pointer = 0u;
 };
 
+// Surface of a shape that isn't a composite, `frame` being the point in the frame of its parent
+fn leaf_surface(frame: vec4<f32>, normal_matrix: mat3x3<f32>, shape: Shape)->SurfaceInfo{
+    let local = transform_point(frame, shape.transform).xyz;
+    var normal: vec3<f32>;
+    switch(shape.shape_type){
+        case 0u:{
+            normal = sphere_normal(local, spheres[shape.index]);
+        }
+        case 1u:{
+            normal = cube_normal(local, cuboids[shape.index]);
+        }
+        case 2u:{
+            normal = torus_normal(local, tori[shape.index]);
+        }
+        // Primitives of the registry
+        default:{
+            normal = primitive_normal(local, shape.shape_type, primitives[shape.index]);
+        }
+    }
+    var surface_info : SurfaceInfo;
+    surface_info.color = shape.color;
+    surface_info.reflectivity = shape.reflectivity;
+    surface_info.normal = normalize(normal_matrix * transform_normal_matrix(shape.transform) * normal);
+    return surface_info;
+};
+
+// Push the distance and surface of two children combined by the composite c,
+// `w` scaling the distances of the frame of the composite
+fn combine_children(ad: f32, a_surface: SurfaceInfo, bd: f32, b_surface: SurfaceInfo, c: Composite, w: f32){
+    var as = a_surface;
+    var d = 0.0;
+    // How much closer to the surface of the composite a is than b
+    var x = 0.0;
+    // Weight of a in the normal of the composite
+    var normal_weight = 0.0;
+    let op = binary_op(c.t);
+    switch(op){
+        case 0u:{
+            d = min(ad,bd);
+            x = bd - ad;
+        }
+        case 1u:{
+            d = max(ad,bd);
+            x = ad - bd;
+        }
+        case 2u:{
+            as.normal = -as.normal;
+            d = max(bd,-ad);
+            x = -ad - bd;
+        }
+        case 3u:{
+            d = smooth_max(ad,bd,-c.alpha);
+            x = bd - ad;
+        }
+        // Smooth operators blend the normals of the children across the seam
+        case 14u:{
+            let res = poly_smooth_min(ad, bd, c.alpha * w);
+            d = res.x;
+            x = bd - ad;
+            normal_weight = res.y;
+        }
+        case 15u:{
+            let res = poly_smooth_max(ad, bd, c.alpha * w);
+            d = res.x;
+            x = ad - bd;
+            normal_weight = res.y;
+        }
+        case 16u:{
+            let res = poly_smooth_max(bd, -ad, c.alpha * w);
+            as.normal = -as.normal;
+            d = res.x;
+            x = -ad - bd;
+            normal_weight = 1.0 - res.y;
+        }
+        default:{}
+    }
+    if(op < 4u){
+        normal_weight = select(0.0, 1.0, x > 0.0);
+    }
+    // Materials are mixed over the seam width of the node, like the normals without one
+    var material_weight = normal_weight;
+    if(c.seam > 0.0){
+        material_weight = clamp(0.5 + 0.5 * x / (c.seam * w), 0.0, 1.0);
+    }
+    add_srstack(combine_surfaces(as, b_surface, normal_weight, material_weight));
+    add_rstack(d);
+};
+
+// The composite of `shape` replaces the material of its subtree with its own
+fn override_material(shape: Shape){
+    if(composites[shape.index].override_material != 0u){
+        var surface_info = pop_srstack();
+        surface_info.color = shape.color;
+        surface_info.reflectivity = shape.reflectivity;
+        add_srstack(surface_info);
+    }
+};
+
+// Same program as shape_distance, with the surfaces of the shapes
 fn shape_surface(point: vec3<f32>, root:u32)-> SurfaceInfo{
+    let identity = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+    let root_shape = shapes[root];
+    if(root_shape.shape_type != 9u){
+        return leaf_surface(vec4<f32>(point, 1.0), identity, root_shape);
+    }
+
     clear_rstack();
     clear_srstack();
-    clear_sstack();
-    add_sstack(-i32(root+1u));
+    clear_fstack();
+    add_nstack(vec4<f32>(point, 1.0), identity);
 
-    var mdist : f32 = 99999999999.0;
-    var midx: u32 = 0u;
-    var skip_sign = 1.0;
+    var pc = composites[root_shape.index].program;
     loop {
-        if(shape_stack_pointer == 0u){break;}
+        let instruction = instructions[pc];
+        pc = pc + 1u;
+        if(instruction.op == OP_END){break;}
 
-        let current = pop_sstack();
-        if (current<0){
-            let index = u32(-current) - 1u;
-            let shape = shapes[index];
-
-            add_sstack(i32(index));
-            switch(shape.shape_type){
-                case 9u:{
-                    let c = composites[shape.index];
-                    add_sstack(-i32(c.a+1u));
-                    add_sstack(-i32(c.b+1u));
-                }
-                default:{}
+        let shape = shapes[instruction.shape];
+        let frame = top_fstack();
+        let normal_matrix = top_normal_matrix();
+        switch(instruction.op){
+            // OP_LEAF
+            case 1u:{
+                add_rstack(leaf_distance(frame, shape));
+                add_srstack(leaf_surface(frame, normal_matrix, shape));
             }
-        }else{
-            let index = u32(current);
-            let shape = shapes[index];
-
-            switch(shape.shape_type){
-                case 0u:{
-                    var d = sphere_distance(point, spheres[shape.index]);
-                    var surface_info : SurfaceInfo;
-                    surface_info.color = shape.color;
-                    surface_info.reflectivity = shape.reflectivity;
-                    surface_info.normal = sphere_normal(point, spheres[shape.index]);
-                    add_rstack(d);
-                    add_srstack(surface_info);
-                }
-                case 1u:{
-                    var d = cube_distance(point, cuboids[shape.index]);
-                    var surface_info : SurfaceInfo;
-                    surface_info.color = shape.color;
-                    surface_info.reflectivity = shape.reflectivity;
-                    surface_info.normal = cube_normal(point, cuboids[shape.index]);
-                    add_rstack(d);
-                    add_srstack(surface_info);
+            // OP_PUSH
+            case 2u:{
+                add_nstack(transform_point(frame, shape.transform), normal_matrix * transform_normal_matrix(shape.transform));
+            }
+            // OP_PUSH_DOMAIN
+            case 3u:{
+                let c = composites[shape.index];
+                let local = transform_point(frame, shape.transform);
+                let local_normal_matrix = normal_matrix * transform_normal_matrix(shape.transform);
+                add_nstack(domain_point(local, c), local_normal_matrix * domain_normal_matrix(local.xyz, c));
+            }
+            // OP_COMBINE
+            case 4u:{
+                let ad = pop_rstack();
+                let as = pop_srstack();
+                let bd = pop_rstack();
+                let bs = pop_srstack();
+                let c = composites[shape.index];
+                if(c.a_first != 0u){
+                    combine_children(bd, bs, ad, as, c, frame.w);
+                }else{
+                    combine_children(ad, as, bd, bs, c, frame.w);
                 }
-                case 9u:{
-                    let c = composites[shape.index];
-                    let ad = pop_rstack();
-                    var as = pop_srstack();
-                    let bd = pop_rstack();
-                    let bs = pop_srstack();
-
-                    switch(c.t){
-                        case 0u:{
-                            if(ad<bd){
-                                add_srstack(as);
-                            }else{
-                                add_srstack(bs);
-                            }
-                            add_rstack(min(ad,bd));
-                        }
-                        case 1u:{
-                            if(ad>bd){
-                                add_srstack(as);
-                            }else{
-                                add_srstack(bs);
-                            }
-                            add_rstack(max(ad,bd));
-                        }
-                        case 2u:{
-                            if(-ad>bd){
-                                as.normal = -as.normal;
-                                add_srstack(as);
-                            }else{
-                                add_srstack(bs);
-                            }
-                            add_rstack(max(bd,-ad));
-                        }
-                        case 3u:{
-                            if(ad<bd){
-                                add_srstack(as);
-                            }else{
-                                add_srstack(bs);
-                            }
-                            add_rstack(smooth_max(ad,bd,-c.alpha));
+            }
+            // OP_POP
+            case 5u:{
+                pop_fstack();
+                override_material(shape);
+            }
+            // OP_APPLY, nodes with a single child only change the result of the child, if at all
+            case 6u:{
+                pop_fstack();
+                let c = composites[shape.index];
+                let local = transform_point(top_fstack(), shape.transform);
+                let local_normal_matrix = top_normal_matrix() * transform_normal_matrix(shape.transform);
+                let d = pop_rstack();
+                var surface_info = pop_srstack();
+                switch(c.t){
+                    case 10u:{
+                        surface_info.normal = normalize(surface_info.normal + local_normal_matrix * displacement_gradient(local.xyz, c));
+                    }
+                    // The inside of the shell faces the other way
+                    case 12u:{
+                        if(d < 0.0){
+                            surface_info.normal = -surface_info.normal;
                         }
-                        default:{}
                     }
+                    default:{}
                 }
-                default:{}
+                add_rstack(domain_distance(d, local, c));
+                add_srstack(surface_info);
+                override_material(shape);
             }
+            default:{}
         }
     }
     return pop_srstack();
-};
\ No newline at end of file
+};
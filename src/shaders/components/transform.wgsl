@@ -0,0 +1,23 @@
+#include "types.wgsl"
+
+// Rotation of v by the unit quaternion q
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>)->vec3<f32>{
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+};
+
+// Point in the frame of the transform, w is the factor the distances of that frame are scaled by.
+// The smallest scale factor is used so that a non uniform scale never overestimates the distance.
+fn transform_point(p: vec4<f32>, t: Transform)->vec4<f32>{
+    let local = quat_rotate(vec4<f32>(-t.rotation.xyz, t.rotation.w), p.xyz - t.position) / t.scale;
+    return vec4<f32>(local, p.w * min(t.scale.x, min(t.scale.y, t.scale.z)));
+};
+
+// Matrix taking the normals of the frame of the transform to the frame of its parent
+fn transform_normal_matrix(t: Transform)->mat3x3<f32>{
+    return mat3x3<f32>(
+        quat_rotate(t.rotation, vec3<f32>(1.0, 0.0, 0.0)) / t.scale.x,
+        quat_rotate(t.rotation, vec3<f32>(0.0, 1.0, 0.0)) / t.scale.y,
+        quat_rotate(t.rotation, vec3<f32>(0.0, 0.0, 1.0)) / t.scale.z
+    );
+};
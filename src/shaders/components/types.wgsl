@@ -11,11 +11,59 @@ struct Cuboid{ //align(16)
     rotation : mat3x3<f32>; // ofset(32) align(16) size(48)
 };
 
+struct Torus{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    radius : f32; // offset(12) align(4) size(4)
+    rotation : mat3x3<f32>; // offset(16) align(16) size(48)
+    thickness : f32; // offset(64) align(4) size(4)
+    //pad 12
+};
+
+// Plane, capsule, cylinder, cone, ellipsoid, rounded box and hex prism
+struct Primitive{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    //pad 4
+    rotation : mat3x3<f32>; // offset(16) align(16) size(48)
+    params : vec4<f32>; // offset(64) align(16) size(16)
+};
+
 struct Composite{ //align(16)
-    a:u32;
-    b:u32;
-    t:u32;
-    alpha:f32;
+    a:u32; // first child in the child lists of the ShapeCollection for the n-ary nodes
+    b:u32; // same as a for the nodes with a single child, from 4 to 13, child count for the n-ary nodes
+    t:u32; // 0 union, 1 intersection, 2 difference, 3 blend, 4 transform of the Shape applied to a,
+           // 5 repetition, 6 mirror, 7 polar repetition, 8 twist, 9 bend, 10 displacement,
+           // 11 rounding, 12 shell, 13 elongation (see domain.wgsl), 14 smooth union,
+           // 15 smooth intersection, 16 smooth difference, 17 n-ary union, 18 n-ary intersection
+    alpha:f32; // smoothness of blends, Lipschitz factor of the deformations from 8 on
+    domain: vec4<f32>; //offset(16) align(16) size(16)
+    limit: vec4<f32>; //offset(32) align(16) size(16)
+    seam: f32; //offset(48) width over which the materials of the children are mixed, 0 for a hard seam
+    override_material: u32; //offset(52) the color and reflectivity of the Shape replace the ones of the children
+    a_first: u32; //offset(56) a is evaluated before b, b comes first otherwise
+    program: u32; //offset(60) first instruction of the program evaluating the tree of the composite, 0xffffffff until the composite is visible
+};
+
+// Instruction of the programs evaluating the composites, see program.rs
+struct Instruction{
+    op: u32;
+    shape: u32; // index of the shape the instruction applies to
+};
+
+let OP_END = 0u;
+let OP_LEAF = 1u;
+let OP_PUSH = 2u;
+let OP_PUSH_DOMAIN = 3u;
+let OP_COMBINE = 4u;
+let OP_POP = 5u;
+let OP_APPLY = 6u;
+
+// Moves a shape and its children, see transform.wgsl
+struct Transform{ //align(16)
+    position: vec3<f32>; //offset(0) align(16) size(12)
+    //pad 4
+    rotation: vec4<f32>; //offset(16) align(16) size(16) quaternion
+    scale: vec3<f32>; //offset(32) align(16) size(12)
+    //pad 4
 };
 
 struct Shape{ //align(16)
@@ -23,8 +71,9 @@ struct Shape{ //align(16)
     index: u32; //offset(12) align(4) size(4)
     shape_type: u32; //offset(16) align(4) size(4)
     reflectivity: f32; //offset(20) align(4) size(4)
-    visible:u32;
-    //padding(8)
+    visible:u32; //offset(24) align(4) size(4)
+    //padding(4)
+    transform: Transform; //offset(32) align(16) size(48)
 };
 
 struct Camera{
@@ -34,9 +83,21 @@ struct Camera{
     depth : f32;
 };
 
+struct Light{ //align(16)
+    direction: vec3<f32>; //offset(0) align(16) size(12)
+    color: vec3<f32>; //offset(16) align(16) size(12)
+};
+
+struct Lights{
+    lights: array<Light, 4u>; //offset(0) align(16) size(128)
+    count: u32; //offset(128) align(4) size(4)
+};
+
 struct DistRes{
     distance:f32;
     index:u32;
+    // The distance may be overestimated by this factor because of the deformations
+    lipschitz:f32;
 };
 
 struct RayParams{
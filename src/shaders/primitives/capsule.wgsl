@@ -0,0 +1,5 @@
+fn capsule_distance(a: vec3<f32>, b:Primitive)->f32{
+    var a_rotated = (a-b.pos)*b.rotation;
+    a_rotated.y = a_rotated.y - clamp(a_rotated.y, -b.params.x, b.params.x);
+    return length(a_rotated) - b.params.y;
+};
@@ -0,0 +1,20 @@
+fn cone_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let h = b.params.x;
+    let r1 = b.params.y;
+    let r2 = b.params.z;
+    let q = vec2<f32>(length(a_rotated.xz), a_rotated.y);
+    let k1 = vec2<f32>(r2, h);
+    let k2 = vec2<f32>(r2 - r1, 2.0 * h);
+    var cap_radius = r2;
+    if (q.y < 0.0){
+        cap_radius = r1;
+    }
+    let ca = vec2<f32>(q.x - min(q.x, cap_radius), abs(q.y) - h);
+    let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    var inside = 1.0;
+    if (cb.x < 0.0 && ca.y < 0.0){
+        inside = -1.0;
+    }
+    return inside * sqrt(min(dot(ca, ca), dot(cb, cb)));
+};
@@ -0,0 +1,5 @@
+fn cylinder_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let d = vec2<f32>(length(a_rotated.xz) - b.params.y, abs(a_rotated.y) - b.params.x);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2<f32>(0.0, 0.0)));
+};
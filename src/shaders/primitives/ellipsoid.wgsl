@@ -0,0 +1,7 @@
+// Bound of the distance, not exact
+fn ellipsoid_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let k0 = length(a_rotated / b.params.xyz);
+    let k1 = length(a_rotated / (b.params.xyz * b.params.xyz));
+    return k0 * (k0 - 1.0) / k1;
+};
@@ -0,0 +1,10 @@
+fn hex_prism_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = abs((a-b.pos)*b.rotation);
+    let k = vec3<f32>(-0.8660254, 0.5, 0.57735);
+    let radius = b.params.x;
+    var hex = a_rotated.xz;
+    hex = hex - 2.0 * min(dot(k.xy, hex), 0.0) * k.xy;
+    let edge = vec2<f32>(clamp(hex.x, -k.z * radius, k.z * radius), radius);
+    let d = vec2<f32>(length(hex - edge) * sign(hex.y - radius), a_rotated.y - b.params.y);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2<f32>(0.0, 0.0)));
+};
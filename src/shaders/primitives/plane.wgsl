@@ -0,0 +1,4 @@
+fn plane_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    return a_rotated.y;
+};
@@ -0,0 +1,5 @@
+fn rounded_box_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let q = abs(a_rotated) - b.params.xyz + vec3<f32>(b.params.w, b.params.w, b.params.w);
+    return length(max(q, vec3<f32>(0.0, 0.0, 0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - b.params.w;
+};
@@ -0,0 +1,1317 @@
+
+
+/////////////////////////////////////////////
+// Types 
+/////////////////////////////////////////////
+
+struct Sphere{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    radius : f32; // offset(12) align(4) size(4)
+};
+
+struct Cuboid{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    //pad 4
+    scale : vec3<f32>; // offset(16) align(16) size(12)
+    //pad 4
+    rotation : mat3x3<f32>; // ofset(32) align(16) size(48)
+};
+
+struct Torus{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    radius : f32; // offset(12) align(4) size(4)
+    rotation : mat3x3<f32>; // offset(16) align(16) size(48)
+    thickness : f32; // offset(64) align(4) size(4)
+    //pad 12
+};
+
+// Plane, capsule, cylinder, cone, ellipsoid, rounded box and hex prism
+struct Primitive{ //align(16)
+    pos : vec3<f32>; //offset(0) align(16) size(12)
+    //pad 4
+    rotation : mat3x3<f32>; // offset(16) align(16) size(48)
+    params : vec4<f32>; // offset(64) align(16) size(16)
+};
+
+struct Composite{ //align(16)
+    a:u32; // first child in the child lists of the ShapeCollection for the n-ary nodes
+    b:u32; // same as a for the nodes with a single child, from 4 to 13, child count for the n-ary nodes
+    t:u32; // 0 union, 1 intersection, 2 difference, 3 blend, 4 transform of the Shape applied to a,
+           // 5 repetition, 6 mirror, 7 polar repetition, 8 twist, 9 bend, 10 displacement,
+           // 11 rounding, 12 shell, 13 elongation (see domain.wgsl), 14 smooth union,
+           // 15 smooth intersection, 16 smooth difference, 17 n-ary union, 18 n-ary intersection
+    alpha:f32; // smoothness of blends, Lipschitz factor of the deformations from 8 on
+    domain: vec4<f32>; //offset(16) align(16) size(16)
+    limit: vec4<f32>; //offset(32) align(16) size(16)
+    seam: f32; //offset(48) width over which the materials of the children are mixed, 0 for a hard seam
+    override_material: u32; //offset(52) the color and reflectivity of the Shape replace the ones of the children
+    a_first: u32; //offset(56) a is evaluated before b, b comes first otherwise
+    program: u32; //offset(60) first instruction of the program evaluating the tree of the composite, 0xffffffff until the composite is visible
+};
+
+// Instruction of the programs evaluating the composites, see program.rs
+struct Instruction{
+    op: u32;
+    shape: u32; // index of the shape the instruction applies to
+};
+
+let OP_END = 0u;
+let OP_LEAF = 1u;
+let OP_PUSH = 2u;
+let OP_PUSH_DOMAIN = 3u;
+let OP_COMBINE = 4u;
+let OP_POP = 5u;
+let OP_APPLY = 6u;
+
+// Moves a shape and its children, see transform.wgsl
+struct Transform{ //align(16)
+    position: vec3<f32>; //offset(0) align(16) size(12)
+    //pad 4
+    rotation: vec4<f32>; //offset(16) align(16) size(16) quaternion
+    scale: vec3<f32>; //offset(32) align(16) size(12)
+    //pad 4
+};
+
+struct Shape{ //align(16)
+    color: vec3<f32>; //offset(0) align(16) size(12)
+    index: u32; //offset(12) align(4) size(4)
+    shape_type: u32; //offset(16) align(4) size(4)
+    reflectivity: f32; //offset(20) align(4) size(4)
+    visible:u32; //offset(24) align(4) size(4)
+    //padding(4)
+    transform: Transform; //offset(32) align(16) size(48)
+};
+
+struct Camera{
+    ray_dir : mat3x3<f32>;
+    position: vec3<f32>;
+    ratio : f32;
+    depth : f32;
+};
+
+struct Light{ //align(16)
+    direction: vec3<f32>; //offset(0) align(16) size(12)
+    color: vec3<f32>; //offset(16) align(16) size(12)
+};
+
+struct Lights{
+    lights: array<Light, 4u>; //offset(0) align(16) size(128)
+    count: u32; //offset(128) align(4) size(4)
+};
+
+struct DistRes{
+    distance:f32;
+    index:u32;
+    // The distance may be overestimated by this factor because of the deformations
+    lipschitz:f32;
+};
+
+struct RayParams{
+    max_length: f32;
+    max_step: u32;
+    threshold: f32;
+    skip_shape: i32;
+};
+
+struct Hit{
+    hit_shape: i32;
+    root_shape: i32;
+    step_count: u32;
+    hit_pos: vec3<f32>;
+    ray_length: f32;
+    min_distance: f32;
+};
+
+struct ShapeCount{
+    count:u32;
+};
+
+struct SurfaceInfo{
+    normal:vec3<f32>;
+    color:vec3<f32>;
+    reflectivity:f32;
+};
+
+/////////////////////////////////////////////
+// Bindings 
+/////////////////////////////////////////////
+
+
+
+// Texture that recieve the result of the computations
+@group(0) @binding(0)
+var target_texture: texture_storage_2d<rgba8unorm, write>;
+
+// Bind group related to the shapes
+@group(1) @binding(0)
+var<uniform> shape_count: ShapeCount;
+@group(1) @binding(1)
+var<storage> shapes: array<Shape>;
+@group(1) @binding(2)
+var<storage> spheres: array<Sphere>;
+@group(1) @binding(3)
+var<storage> cuboids: array<Cuboid>;
+@group(1) @binding(4)
+var<storage> composites: array<Composite>;
+@group(1) @binding(5)
+var<storage> tori: array<Torus>;
+@group(1) @binding(6)
+var<storage> primitives: array<Primitive>;
+@group(1) @binding(7)
+var<storage> instructions: array<Instruction>;
+
+// Camera bind group
+@group(2) @binding(0)
+var<uniform> camera: Camera;
+
+// Lights bind group
+@group(3) @binding(0)
+var<uniform> lights: Lights;
+
+/////////////////////////////////////////////
+// Math 
+/////////////////////////////////////////////
+
+fn smooth_max(a:f32, b:f32, alpha:f32)->f32{
+    return (a * exp2(a * alpha) + b * exp2(b * alpha))/(exp2(a * alpha) + exp2(b * alpha));
+};
+
+// Polynomial smooth minimum of a and b over the radius k, y is the weight of a in the result
+fn poly_smooth_min(a:f32, b:f32, k:f32)->vec2<f32>{
+    let radius = max(k, 0.000001);
+    let h = clamp(0.5 + 0.5 * (b - a) / radius, 0.0, 1.0);
+    return vec2<f32>(mix(b, a, h) - radius * h * (1.0 - h), h);
+};
+
+// Polynomial smooth maximum of a and b over the radius k, y is the weight of a in the result
+fn poly_smooth_max(a:f32, b:f32, k:f32)->vec2<f32>{
+    let res = poly_smooth_min(-a, -b, k);
+    return vec2<f32>(-res.x, res.y);
+};
+
+
+/////////////////////////////////////////////
+// Transform 
+/////////////////////////////////////////////
+
+
+
+// Rotation of v by the unit quaternion q
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>)->vec3<f32>{
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+};
+
+// Point in the frame of the transform, w is the factor the distances of that frame are scaled by.
+// The smallest scale factor is used so that a non uniform scale never overestimates the distance.
+fn transform_point(p: vec4<f32>, t: Transform)->vec4<f32>{
+    let local = quat_rotate(vec4<f32>(-t.rotation.xyz, t.rotation.w), p.xyz - t.position) / t.scale;
+    return vec4<f32>(local, p.w * min(t.scale.x, min(t.scale.y, t.scale.z)));
+};
+
+// Matrix taking the normals of the frame of the transform to the frame of its parent
+fn transform_normal_matrix(t: Transform)->mat3x3<f32>{
+    return mat3x3<f32>(
+        quat_rotate(t.rotation, vec3<f32>(1.0, 0.0, 0.0)) / t.scale.x,
+        quat_rotate(t.rotation, vec3<f32>(0.0, 1.0, 0.0)) / t.scale.y,
+        quat_rotate(t.rotation, vec3<f32>(0.0, 0.0, 1.0)) / t.scale.z
+    );
+};
+
+
+/////////////////////////////////////////////
+// Domain 
+/////////////////////////////////////////////
+
+
+
+let PI = 3.14159265;
+
+// Transform, domain and deformation nodes only have one child, stored in both a and b
+fn single_child(t: u32)->bool{
+    return t >= 4u && t <= 13u;
+};
+
+// Binary operation folding the children of a node, n-ary unions and intersections fold them like the binary ones
+fn binary_op(t: u32)->u32{
+    var op = t;
+    switch(t){
+        case 17u:{
+            op = 0u;
+        }
+        case 18u:{
+            op = 1u;
+        }
+        default:{}
+    }
+    return op;
+};
+
+// Value of the lattice point p, in [-1, 1]
+fn lattice_hash(p: vec3<f32>)->f32{
+    return fract(sin(dot(p, vec3<f32>(127.1, 311.7, 74.7))) * 43758.5453) * 2.0 - 1.0;
+};
+
+// Value noise in [-1, 1], smoothly interpolated between the lattice points
+fn value_noise(p: vec3<f32>)->f32{
+    let i = floor(p);
+    let f = fract(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let x00 = mix(lattice_hash(i), lattice_hash(i + vec3<f32>(1.0, 0.0, 0.0)), u.x);
+    let x10 = mix(lattice_hash(i + vec3<f32>(0.0, 1.0, 0.0)), lattice_hash(i + vec3<f32>(1.0, 1.0, 0.0)), u.x);
+    let x01 = mix(lattice_hash(i + vec3<f32>(0.0, 0.0, 1.0)), lattice_hash(i + vec3<f32>(1.0, 0.0, 1.0)), u.x);
+    let x11 = mix(lattice_hash(i + vec3<f32>(0.0, 1.0, 1.0)), lattice_hash(i + vec3<f32>(1.0, 1.0, 1.0)), u.x);
+    return mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z);
+};
+
+// Point given to the child of a composite, only repetition, mirror and deformation nodes change it.
+// w is kept, the deformations that stretch space are corrected by their Lipschitz factor in send_ray.
+fn domain_point(p: vec4<f32>, c: Composite)->vec4<f32>{
+    var q = p.xyz;
+    switch(c.t){
+        // Repetition, the cell size is in domain.xyz, a size of 0 doesn't repeat along the axis
+        case 5u:{
+            var cell = round(q / c.domain.xyz);
+            // Limited repetition, limit.xyz copies on each side
+            if(c.limit.w > 0.0){
+                cell = clamp(cell, -c.limit.xyz, c.limit.xyz);
+            }
+            q = select(q, q - c.domain.xyz * cell, c.domain.xyz > vec3<f32>(0.0, 0.0, 0.0));
+        }
+        // Mirror across the plane of normal domain.xyz at distance domain.w from the origin
+        case 6u:{
+            let d = dot(q, c.domain.xyz) - c.domain.w;
+            if(d < 0.0){
+                q = q - 2.0 * d * c.domain.xyz;
+            }
+        }
+        // Polar repetition around y, domain.x copies
+        case 7u:{
+            let sector = 2.0 * PI / c.domain.x;
+            let angle = round(atan2(q.z, q.x) / sector) * sector;
+            q = vec3<f32>(q.x * cos(angle) + q.z * sin(angle), q.y, q.z * cos(angle) - q.x * sin(angle));
+        }
+        // Twist around y, domain.x radians per unit
+        case 8u:{
+            let angle = c.domain.x * q.y;
+            q = vec3<f32>(cos(angle) * q.x - sin(angle) * q.z, q.y, sin(angle) * q.x + cos(angle) * q.z);
+        }
+        // Bend of the x axis toward y, domain.x radians per unit
+        case 9u:{
+            let angle = c.domain.x * q.x;
+            q = vec3<f32>(cos(angle) * q.x - sin(angle) * q.y, sin(angle) * q.x + cos(angle) * q.y, q.z);
+        }
+        // Elongation, the child is stretched by domain.xyz on each side of the origin
+        case 13u:{
+            q = q - clamp(q, -c.domain.xyz, c.domain.xyz);
+        }
+        default:{}
+    }
+    return vec4<f32>(q, p.w);
+};
+
+// Distance of a composite with a single child from the distance `d` of the child,
+// `p` being the point in the frame of the composite
+fn domain_distance(d: f32, p: vec4<f32>, c: Composite)->f32{
+    var res = d;
+    switch(c.t){
+        // Displacement by value noise, domain.x is the amplitude and domain.y the frequency
+        case 10u:{
+            res = d + c.domain.x * value_noise(p.xyz * c.domain.y) * p.w;
+        }
+        // Rounding by domain.x
+        case 11u:{
+            res = d - c.domain.x * p.w;
+        }
+        // Shell of thickness domain.x
+        case 12u:{
+            res = abs(d) - c.domain.x * p.w;
+        }
+        default:{}
+    }
+    return res;
+};
+
+// Matrix taking the normals of the child of a composite to the frame of the composite, `p` is not remapped yet
+fn domain_normal_matrix(p: vec3<f32>, c: Composite)->mat3x3<f32>{
+    var m = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+    switch(c.t){
+        case 6u:{
+            let n = c.domain.xyz;
+            if(dot(p, n) - c.domain.w < 0.0){
+                m = mat3x3<f32>(
+                    vec3<f32>(1.0, 0.0, 0.0) - 2.0 * n.x * n,
+                    vec3<f32>(0.0, 1.0, 0.0) - 2.0 * n.y * n,
+                    vec3<f32>(0.0, 0.0, 1.0) - 2.0 * n.z * n
+                );
+            }
+        }
+        case 7u:{
+            let sector = 2.0 * PI / c.domain.x;
+            let angle = round(atan2(p.z, p.x) / sector) * sector;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), 0.0, sin(angle)),
+                vec3<f32>(0.0, 1.0, 0.0),
+                vec3<f32>(-sin(angle), 0.0, cos(angle))
+            );
+        }
+        // The deformations are approximated by the rotation at the point, without their shear
+        case 8u:{
+            let angle = c.domain.x * p.y;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), 0.0, -sin(angle)),
+                vec3<f32>(0.0, 1.0, 0.0),
+                vec3<f32>(sin(angle), 0.0, cos(angle))
+            );
+        }
+        case 9u:{
+            let angle = c.domain.x * p.x;
+            m = mat3x3<f32>(
+                vec3<f32>(cos(angle), -sin(angle), 0.0),
+                vec3<f32>(sin(angle), cos(angle), 0.0),
+                vec3<f32>(0.0, 0.0, 1.0)
+            );
+        }
+        default:{}
+    }
+    return m;
+};
+
+// Gradient of the displacement of a displacement node in its frame, added to the normal of its child
+fn displacement_gradient(p: vec3<f32>, c: Composite)->vec3<f32>{
+    let e = 0.001;
+    let f = c.domain.y;
+    return c.domain.x * vec3<f32>(
+        value_noise((p + vec3<f32>(e, 0.0, 0.0)) * f) - value_noise((p - vec3<f32>(e, 0.0, 0.0)) * f),
+        value_noise((p + vec3<f32>(0.0, e, 0.0)) * f) - value_noise((p - vec3<f32>(0.0, e, 0.0)) * f),
+        value_noise((p + vec3<f32>(0.0, 0.0, e)) * f) - value_noise((p - vec3<f32>(0.0, 0.0, e)) * f)
+    ) / (2.0 * e);
+};
+
+
+/////////////////////////////////////////////
+// Geometry 
+/////////////////////////////////////////////
+
+fn vcos(a:vec3<f32>, b:vec3<f32>) -> f32{
+    return dot(a,b) /(length(a)*length(b));
+};
+
+fn reflection(incoming:vec3<f32>, normal:vec3<f32>)->vec3<f32>{
+    return -2.0*dot(incoming,normal)/dot(normal,normal)*normal+incoming;
+};
+
+/////////////////////////////////////////////
+// plane.wgsl 
+/////////////////////////////////////////////
+
+fn plane_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    return a_rotated.y;
+};
+
+
+/////////////////////////////////////////////
+// capsule.wgsl 
+/////////////////////////////////////////////
+
+fn capsule_distance(a: vec3<f32>, b:Primitive)->f32{
+    var a_rotated = (a-b.pos)*b.rotation;
+    a_rotated.y = a_rotated.y - clamp(a_rotated.y, -b.params.x, b.params.x);
+    return length(a_rotated) - b.params.y;
+};
+
+
+/////////////////////////////////////////////
+// cylinder.wgsl 
+/////////////////////////////////////////////
+
+fn cylinder_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let d = vec2<f32>(length(a_rotated.xz) - b.params.y, abs(a_rotated.y) - b.params.x);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2<f32>(0.0, 0.0)));
+};
+
+
+/////////////////////////////////////////////
+// cone.wgsl 
+/////////////////////////////////////////////
+
+fn cone_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let h = b.params.x;
+    let r1 = b.params.y;
+    let r2 = b.params.z;
+    let q = vec2<f32>(length(a_rotated.xz), a_rotated.y);
+    let k1 = vec2<f32>(r2, h);
+    let k2 = vec2<f32>(r2 - r1, 2.0 * h);
+    var cap_radius = r2;
+    if (q.y < 0.0){
+        cap_radius = r1;
+    }
+    let ca = vec2<f32>(q.x - min(q.x, cap_radius), abs(q.y) - h);
+    let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    var inside = 1.0;
+    if (cb.x < 0.0 && ca.y < 0.0){
+        inside = -1.0;
+    }
+    return inside * sqrt(min(dot(ca, ca), dot(cb, cb)));
+};
+
+
+/////////////////////////////////////////////
+// ellipsoid.wgsl 
+/////////////////////////////////////////////
+
+// Bound of the distance, not exact
+fn ellipsoid_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let k0 = length(a_rotated / b.params.xyz);
+    let k1 = length(a_rotated / (b.params.xyz * b.params.xyz));
+    return k0 * (k0 - 1.0) / k1;
+};
+
+
+/////////////////////////////////////////////
+// rounded_box.wgsl 
+/////////////////////////////////////////////
+
+fn rounded_box_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let q = abs(a_rotated) - b.params.xyz + vec3<f32>(b.params.w, b.params.w, b.params.w);
+    return length(max(q, vec3<f32>(0.0, 0.0, 0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - b.params.w;
+};
+
+
+/////////////////////////////////////////////
+// hex_prism.wgsl 
+/////////////////////////////////////////////
+
+fn hex_prism_distance(a: vec3<f32>, b:Primitive)->f32{
+    let a_rotated = abs((a-b.pos)*b.rotation);
+    let k = vec3<f32>(-0.8660254, 0.5, 0.57735);
+    let radius = b.params.x;
+    var hex = a_rotated.xz;
+    hex = hex - 2.0 * min(dot(k.xy, hex), 0.0) * k.xy;
+    let edge = vec2<f32>(clamp(hex.x, -k.z * radius, k.z * radius), radius);
+    let d = vec2<f32>(length(hex - edge) * sign(hex.y - radius), a_rotated.y - b.params.y);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2<f32>(0.0, 0.0)));
+};
+
+
+/////////////////////////////////////////////
+// Primitives 
+/////////////////////////////////////////////
+
+
+
+
+
+
+
+
+
+
+// Replaced at runtime by PrimitiveRegistry::shader_source with the registered primitives
+fn primitive_distance(a: vec3<f32>, shape_type:u32, b:Primitive)->f32{
+    var ret = 9999999.0;
+    switch(shape_type){
+        case 3u:{
+            ret = plane_distance(a, b);
+        }
+        case 4u:{
+            ret = capsule_distance(a, b);
+        }
+        case 5u:{
+            ret = cylinder_distance(a, b);
+        }
+        case 6u:{
+            ret = cone_distance(a, b);
+        }
+        case 7u:{
+            ret = ellipsoid_distance(a, b);
+        }
+        case 8u:{
+            ret = rounded_box_distance(a, b);
+        }
+        case 10u:{
+            ret = hex_prism_distance(a, b);
+        }
+        default:{}
+    }
+    return ret;
+};
+
+
+/////////////////////////////////////////////
+// Distances 
+/////////////////////////////////////////////
+
+
+
+
+fn cube_distance(a:vec3<f32>, b:Cuboid)->f32{
+    let a_centered = a-b.pos;
+    let a_rotated = a_centered*b.rotation;
+    let half_size = b.scale/2.0;
+    let offset = abs(a_rotated)-half_size;
+    var sign = 1.0;
+    if offset[0]<0.0 && offset[1]<0.0 && offset[2]<0.0{
+        return -length(offset);
+    }else{
+        return length(max(offset, vec3<f32>(0.0,0.0,0.0)));
+    }
+};
+
+fn sphere_distance(a: vec3<f32>, b:Sphere)->f32{
+    return distance(a,b.pos) - b.radius;
+};
+
+fn torus_distance(a: vec3<f32>, b:Torus)->f32{
+    let a_rotated = (a-b.pos)*b.rotation;
+    let q = vec2<f32>(length(a_rotated.xz) - b.radius, a_rotated.y);
+    return length(q) - b.thickness;
+};
+
+
+/////////////////////////////////////////////
+// Normals 
+/////////////////////////////////////////////
+
+
+
+
+fn cube_normal(a:vec3<f32>, b:Cuboid)->vec3<f32>{
+    let a_centered = a-b.pos;
+    let a_rotated = a_centered*b.rotation;
+    let half_size = b.scale/2.0;
+
+    let a_scaled =  a_rotated/half_size;
+    var magnet = vec3<f32>(0.0,0.0,0.0);
+    var distmag = 9999999.0;
+
+    let right = vec3<f32>(1.0,0.0,0.0);
+    let left = vec3<f32>(-1.0,0.0,0.0);
+    let forw = vec3<f32>(0.0,1.0,0.0);
+    let back = vec3<f32>(0.0,-1.0,0.0);
+    let up = vec3<f32>(0.0,0.0,1.0);
+    let down = vec3<f32>(0.0,0.0,-1.0);
+    var d = 0.0;
+    d = distance(a_scaled,right);
+    if(distmag > d){
+        magnet = right;
+        distmag = d;
+    }
+    d = distance(a_scaled,left);
+    if(distmag > d){
+        magnet = left;
+        distmag = d;
+    }
+    d = distance(a_scaled,forw);
+    if(distmag > d){
+        magnet = forw;
+        distmag = d;
+    }
+    d = distance(a_scaled,back);
+    if(distmag > d){
+        magnet = back;
+        distmag = d;
+    }
+    d = distance(a_scaled,up);
+    if(distmag > d){
+        magnet = up;
+        distmag = d;
+    }
+    d = distance(a_scaled,down);
+    if(distmag > d){
+        magnet = down;
+        distmag = d;
+    }
+    return magnet;
+};
+
+fn sphere_normal(point: vec3<f32>, sphere:Sphere)->vec3<f32>{
+    return normalize(point - sphere.pos);
+};
+
+fn torus_normal(point: vec3<f32>, torus:Torus)->vec3<f32>{
+    let a_rotated = (point-torus.pos)*torus.rotation;
+    // Closest point of the center circle of the tube
+    let ring = normalize(vec3<f32>(a_rotated.x, 0.0, a_rotated.z)) * torus.radius;
+    return torus.rotation * normalize(a_rotated - ring);
+};
+
+// Gradient of the distance, for the primitives without an analytic normal
+fn primitive_normal(point: vec3<f32>, shape_type:u32, primitive:Primitive)->vec3<f32>{
+    let e = 0.0001;
+    let dx = primitive_distance(point + vec3<f32>(e, 0.0, 0.0), shape_type, primitive) - primitive_distance(point - vec3<f32>(e, 0.0, 0.0), shape_type, primitive);
+    let dy = primitive_distance(point + vec3<f32>(0.0, e, 0.0), shape_type, primitive) - primitive_distance(point - vec3<f32>(0.0, e, 0.0), shape_type, primitive);
+    let dz = primitive_distance(point + vec3<f32>(0.0, 0.0, e), shape_type, primitive) - primitive_distance(point - vec3<f32>(0.0, 0.0, e), shape_type, primitive);
+    return normalize(vec3<f32>(dx, dy, dz));
+};
+
+
+/////////////////////////////////////////////
+// Distance 
+/////////////////////////////////////////////
+
+
+
+
+
+
+
+// Point in the frame of each node being evaluated, w scales the distances of that frame
+var<private> frame_stack: array<vec4<f32>,20u>;
+var<private> frame_stack_pointer : u32 = 0u;
+var<private> res_stack: array<f32,20u>;
+var<private> res_stack_pointer : u32 = 0u;
+
+fn add_fstack(p: vec4<f32>){
+    frame_stack[frame_stack_pointer] = p;
+    frame_stack_pointer = frame_stack_pointer + 1u;
+};
+
+fn pop_fstack(){
+    frame_stack_pointer = frame_stack_pointer - 1u;
+};
+
+fn top_fstack()->vec4<f32>{
+    return frame_stack[frame_stack_pointer - 1u];
+};
+
+fn clear_fstack(){
+    frame_stack_pointer = 0u;
+};
+
+
+fn add_rstack(s:f32){
+    res_stack[res_stack_pointer] = s;
+    res_stack_pointer = res_stack_pointer+1u;
+};
+
+fn pop_rstack()->f32{
+    res_stack_pointer = res_stack_pointer - 1u;
+    let res = res_stack[res_stack_pointer];
+    return res;
+};
+
+fn clear_rstack(){
+    res_stack_pointer = 0u;
+};
+
+// Distance to a shape that isn't a composite, `frame` being the point in the frame of its parent
+fn leaf_distance(frame: vec4<f32>, shape: Shape)->f32{
+    let local = transform_point(frame, shape.transform);
+    var d: f32;
+    switch(shape.shape_type){
+        case 0u:{
+            d = sphere_distance(local.xyz, spheres[shape.index]);
+        }
+        case 1u:{
+            d = cube_distance(local.xyz, cuboids[shape.index]);
+        }
+        case 2u:{
+            d = torus_distance(local.xyz, tori[shape.index]);
+        }
+        // Primitives of the registry
+        default:{
+            d = primitive_distance(local.xyz, shape.shape_type, primitives[shape.index]);
+        }
+    }
+    return d * local.w;
+};
+
+// Distance of two children combined by the composite c, `w` scaling the distances of the frame of the composite.
+// The program evaluates b before a unless a_first is set, so the result of a is on top of the stack.
+fn combine_distances(a: f32, b: f32, c: Composite, w: f32)->f32{
+    var d = 0.0;
+    switch(binary_op(c.t)){
+        case 0u:{
+            d = min(a,b);
+        }
+        case 1u:{
+            d = max(a,b);
+        }
+        case 2u:{
+            d = max(b,-a);
+        }
+        case 3u:{
+            d = smooth_max(a,b,-c.alpha);
+        }
+        case 14u:{
+            d = poly_smooth_min(a, b, c.alpha * w).x;
+        }
+        case 15u:{
+            d = poly_smooth_max(a, b, c.alpha * w).x;
+        }
+        case 16u:{
+            d = poly_smooth_max(b, -a, c.alpha * w).x;
+        }
+        default:{}
+    }
+    return d;
+};
+
+// Runs the program of the composite `root`, see program.rs for the instructions
+fn shape_distance(point: vec3<f32>, root:u32, skip:i32)-> DistRes{
+    var res: DistRes;
+    res.lipschitz = 1.0;
+    let root_shape = shapes[root];
+    if(root_shape.shape_type != 9u){
+        res.distance = leaf_distance(vec4<f32>(point, 1.0), root_shape);
+        if(i32(root) == skip){
+            res.distance = 9999999.0;
+        }
+        res.index = root;
+        return res;
+    }
+
+    clear_rstack();
+    clear_fstack();
+    add_fstack(vec4<f32>(point, 1.0));
+
+    var mdist : f32 = 99999999999.0;
+    var midx: u32 = 0u;
+    var skip_sign = 1.0;
+    var lipschitz = 1.0;
+    var pc = composites[root_shape.index].program;
+    loop {
+        let instruction = instructions[pc];
+        pc = pc + 1u;
+        if(instruction.op == OP_END){break;}
+
+        let shape = shapes[instruction.shape];
+        let frame = top_fstack();
+        switch(instruction.op){
+            // OP_LEAF
+            case 1u:{
+                var d = leaf_distance(frame, shape);
+                if (i32(instruction.shape) == skip){
+                    d = 9999999.0 * skip_sign;
+                }
+                if(mdist>abs(d)){
+                    mdist = abs(d);
+                    midx = instruction.shape;
+                }
+                add_rstack(d);
+            }
+            // OP_PUSH
+            case 2u:{
+                let c = composites[shape.index];
+                add_fstack(transform_point(frame, shape.transform));
+                if((c.t == 2u || c.t == 16u) && i32(c.a) == skip){
+                    skip_sign = -1.0;
+                }
+            }
+            // OP_PUSH_DOMAIN
+            case 3u:{
+                let c = composites[shape.index];
+                add_fstack(domain_point(transform_point(frame, shape.transform), c));
+                // Deformations can shrink distances, the product of their factors bounds the whole tree
+                if(c.t >= 8u){
+                    lipschitz = lipschitz * c.alpha;
+                }
+            }
+            // OP_COMBINE
+            case 4u:{
+                let a = pop_rstack();
+                let b = pop_rstack();
+                let c = composites[shape.index];
+                if(c.a_first != 0u){
+                    add_rstack(combine_distances(b, a, c, frame.w));
+                }else{
+                    add_rstack(combine_distances(a, b, c, frame.w));
+                }
+            }
+            // OP_POP
+            case 5u:{
+                pop_fstack();
+            }
+            // OP_APPLY, nodes with a single child only change the result of the child, if at all
+            case 6u:{
+                pop_fstack();
+                let local = transform_point(top_fstack(), shape.transform);
+                add_rstack(domain_distance(pop_rstack(), local, composites[shape.index]));
+            }
+            default:{}
+        }
+    }
+    res.distance = pop_rstack();
+    res.index = midx;
+    res.lipschitz = lipschitz;
+    return res;
+};
+
+
+/////////////////////////////////////////////
+// Normal 
+/////////////////////////////////////////////
+
+
+
+
+
+fn shape_normal(point: vec3<f32>, index:u32)-> vec3<f32>{
+    let shape = shapes[index];
+    let local = transform_point(vec4<f32>(point, 1.0), shape.transform).xyz;
+    var ret : vec3<f32>;
+    switch(shape.shape_type){
+        case 0u:{
+            ret = sphere_normal(local, spheres[shape.index]);
+        }
+        case 1u:{
+            ret = cube_normal(local, cuboids[shape.index]);
+        }
+        case 2u:{
+            ret = torus_normal(local, tori[shape.index]);
+        }
+        case 9u:{
+            ret = vec3<f32>(1.0, 0.0, 0.0);
+        }
+        // Primitives of the registry
+        default:{
+            ret = primitive_normal(local, shape.shape_type, primitives[shape.index]);
+        }
+    }
+    return normalize(transform_normal_matrix(shape.transform) * ret);
+};
+
+
+/////////////////////////////////////////////
+// Surface 
+/////////////////////////////////////////////
+
+
+
+
+// Note we reuse the frame and result stacks from the distance function
+var<private> sres_stack: array<SurfaceInfo,20u>;
+var<private> sres_stack_pointer : u32 = 0u;
+// Matrix taking the normals in each frame of the frame stack to world space
+var<private> normal_stack: array<mat3x3<f32>,20u>;
+
+fn add_srstack(s:SurfaceInfo){
+    sres_stack[sres_stack_pointer] = s;
+    sres_stack_pointer = sres_stack_pointer + 1u;
+};
+
+fn pop_srstack()->SurfaceInfo{
+    sres_stack_pointer = sres_stack_pointer - 1u;
+    let res = sres_stack[sres_stack_pointer];
+    return res;
+};
+
+fn add_nstack(p: vec4<f32>, normal_matrix: mat3x3<f32>){
+    normal_stack[frame_stack_pointer] = normal_matrix;
+    add_fstack(p);
+};
+
+// Normal matrix of the frame on top of the frame stack
+fn top_normal_matrix()->mat3x3<f32>{
+    return normal_stack[frame_stack_pointer - 1u];
+};
+
+// Surface of a mixed with the surface of b, the weights being the ones of a.
+// Every material field of SurfaceInfo is mixed with material_weight.
+fn combine_surfaces(a: SurfaceInfo, b: SurfaceInfo, normal_weight: f32, material_weight: f32)->SurfaceInfo{
+    var res: SurfaceInfo;
+    res.normal = normalize(mix(b.normal, a.normal, normal_weight));
+    res.color = mix(b.color, a.color, material_weight);
+    res.reflectivity = mix(b.reflectivity, a.reflectivity, material_weight);
+    return res;
+};
+
+fn clear_srstack(){
+    sres_stack_pointer = 0u;
+};
+
+// Surface of a shape that isn't a composite, `frame` being the point in the frame of its parent
+fn leaf_surface(frame: vec4<f32>, normal_matrix: mat3x3<f32>, shape: Shape)->SurfaceInfo{
+    let local = transform_point(frame, shape.transform).xyz;
+    var normal: vec3<f32>;
+    switch(shape.shape_type){
+        case 0u:{
+            normal = sphere_normal(local, spheres[shape.index]);
+        }
+        case 1u:{
+            normal = cube_normal(local, cuboids[shape.index]);
+        }
+        case 2u:{
+            normal = torus_normal(local, tori[shape.index]);
+        }
+        // Primitives of the registry
+        default:{
+            normal = primitive_normal(local, shape.shape_type, primitives[shape.index]);
+        }
+    }
+    var surface_info : SurfaceInfo;
+    surface_info.color = shape.color;
+    surface_info.reflectivity = shape.reflectivity;
+    surface_info.normal = normalize(normal_matrix * transform_normal_matrix(shape.transform) * normal);
+    return surface_info;
+};
+
+// Push the distance and surface of two children combined by the composite c,
+// `w` scaling the distances of the frame of the composite
+fn combine_children(ad: f32, a_surface: SurfaceInfo, bd: f32, b_surface: SurfaceInfo, c: Composite, w: f32){
+    var as = a_surface;
+    var d = 0.0;
+    // How much closer to the surface of the composite a is than b
+    var x = 0.0;
+    // Weight of a in the normal of the composite
+    var normal_weight = 0.0;
+    let op = binary_op(c.t);
+    switch(op){
+        case 0u:{
+            d = min(ad,bd);
+            x = bd - ad;
+        }
+        case 1u:{
+            d = max(ad,bd);
+            x = ad - bd;
+        }
+        case 2u:{
+            as.normal = -as.normal;
+            d = max(bd,-ad);
+            x = -ad - bd;
+        }
+        case 3u:{
+            d = smooth_max(ad,bd,-c.alpha);
+            x = bd - ad;
+        }
+        // Smooth operators blend the normals of the children across the seam
+        case 14u:{
+            let res = poly_smooth_min(ad, bd, c.alpha * w);
+            d = res.x;
+            x = bd - ad;
+            normal_weight = res.y;
+        }
+        case 15u:{
+            let res = poly_smooth_max(ad, bd, c.alpha * w);
+            d = res.x;
+            x = ad - bd;
+            normal_weight = res.y;
+        }
+        case 16u:{
+            let res = poly_smooth_max(bd, -ad, c.alpha * w);
+            as.normal = -as.normal;
+            d = res.x;
+            x = -ad - bd;
+            normal_weight = 1.0 - res.y;
+        }
+        default:{}
+    }
+    if(op < 4u){
+        normal_weight = select(0.0, 1.0, x > 0.0);
+    }
+    // Materials are mixed over the seam width of the node, like the normals without one
+    var material_weight = normal_weight;
+    if(c.seam > 0.0){
+        material_weight = clamp(0.5 + 0.5 * x / (c.seam * w), 0.0, 1.0);
+    }
+    add_srstack(combine_surfaces(as, b_surface, normal_weight, material_weight));
+    add_rstack(d);
+};
+
+// The composite of `shape` replaces the material of its subtree with its own
+fn override_material(shape: Shape){
+    if(composites[shape.index].override_material != 0u){
+        var surface_info = pop_srstack();
+        surface_info.color = shape.color;
+        surface_info.reflectivity = shape.reflectivity;
+        add_srstack(surface_info);
+    }
+};
+
+// Same program as shape_distance, with the surfaces of the shapes
+fn shape_surface(point: vec3<f32>, root:u32)-> SurfaceInfo{
+    let identity = mat3x3<f32>(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 0.0, 1.0));
+    let root_shape = shapes[root];
+    if(root_shape.shape_type != 9u){
+        return leaf_surface(vec4<f32>(point, 1.0), identity, root_shape);
+    }
+
+    clear_rstack();
+    clear_srstack();
+    clear_fstack();
+    add_nstack(vec4<f32>(point, 1.0), identity);
+
+    var pc = composites[root_shape.index].program;
+    loop {
+        let instruction = instructions[pc];
+        pc = pc + 1u;
+        if(instruction.op == OP_END){break;}
+
+        let shape = shapes[instruction.shape];
+        let frame = top_fstack();
+        let normal_matrix = top_normal_matrix();
+        switch(instruction.op){
+            // OP_LEAF
+            case 1u:{
+                add_rstack(leaf_distance(frame, shape));
+                add_srstack(leaf_surface(frame, normal_matrix, shape));
+            }
+            // OP_PUSH
+            case 2u:{
+                add_nstack(transform_point(frame, shape.transform), normal_matrix * transform_normal_matrix(shape.transform));
+            }
+            // OP_PUSH_DOMAIN
+            case 3u:{
+                let c = composites[shape.index];
+                let local = transform_point(frame, shape.transform);
+                let local_normal_matrix = normal_matrix * transform_normal_matrix(shape.transform);
+                add_nstack(domain_point(local, c), local_normal_matrix * domain_normal_matrix(local.xyz, c));
+            }
+            // OP_COMBINE
+            case 4u:{
+                let ad = pop_rstack();
+                let as = pop_srstack();
+                let bd = pop_rstack();
+                let bs = pop_srstack();
+                let c = composites[shape.index];
+                if(c.a_first != 0u){
+                    combine_children(bd, bs, ad, as, c, frame.w);
+                }else{
+                    combine_children(ad, as, bd, bs, c, frame.w);
+                }
+            }
+            // OP_POP
+            case 5u:{
+                pop_fstack();
+                override_material(shape);
+            }
+            // OP_APPLY, nodes with a single child only change the result of the child, if at all
+            case 6u:{
+                pop_fstack();
+                let c = composites[shape.index];
+                let local = transform_point(top_fstack(), shape.transform);
+                let local_normal_matrix = top_normal_matrix() * transform_normal_matrix(shape.transform);
+                let d = pop_rstack();
+                var surface_info = pop_srstack();
+                switch(c.t){
+                    case 10u:{
+                        surface_info.normal = normalize(surface_info.normal + local_normal_matrix * displacement_gradient(local.xyz, c));
+                    }
+                    // The inside of the shell faces the other way
+                    case 12u:{
+                        if(d < 0.0){
+                            surface_info.normal = -surface_info.normal;
+                        }
+                    }
+                    default:{}
+                }
+                add_rstack(domain_distance(d, local, c));
+                add_srstack(surface_info);
+                override_material(shape);
+            }
+            default:{}
+        }
+    }
+    return pop_srstack();
+};
+
+
+/////////////////////////////////////////////
+// Scene 
+/////////////////////////////////////////////
+
+
+
+// Distance to the closest visible shape, index being that root shape and the distance being divided by its lipschitz factor.
+// The specialized ray marcher replaces this function by the one generated from the scene (see specialize.rs),
+// this one runs the program of every shape.
+fn scene_sdf(p: vec3<f32>)->DistRes{
+    var res: DistRes;
+    res.distance = 9999999999.0;
+    res.index = 0u;
+    res.lipschitz = 1.0;
+    for(var i:u32 = 0u; i < shape_count.count; i=i+1u){
+        if (shapes[i].visible == 0u){continue;}
+        let shape_dist_r = shape_distance(p, i, -1);
+        let distance = shape_dist_r.distance / shape_dist_r.lipschitz;
+        if(res.distance > distance){
+            res.distance = distance;
+            res.index = i;
+        }
+    }
+    return res;
+};
+
+
+/////////////////////////////////////////////
+// Ray 
+/////////////////////////////////////////////
+
+
+
+
+fn send_ray(origin:vec3<f32>, direction:vec3<f32>, params: RayParams)->Hit{
+    var res: Hit;
+    var step_count = 0u;
+    var ray_length = 0.0;
+    var closest_shape = -1;
+    var root_shape = -1;
+    var closest_distance_g = 9999999999.0;
+    //Params
+    let threshold = params.threshold;
+    let max_step = params.max_step;
+    let max_length = params.max_length;
+    let skip_shape = params.skip_shape;
+    res.hit_shape = -1;
+    res.root_shape = -1;
+    var ray_pos = origin + direction * threshold * 10.0;
+    loop {
+        var closest_distance : f32 = 9999999999.0;
+        closest_shape = -1;
+
+
+
+
+
+
+
+        for(var i:u32 = 0u; i < shape_count.count && threshold < closest_distance; i=i+1u){
+            if (i32(i) == skip_shape || shapes[i].visible == 0u){continue;}
+            let shape_dist_r = shape_distance(ray_pos, i, skip_shape);
+            // Shorter steps through deformed shapes so that the ray doesn't go through them
+            let distance = shape_dist_r.distance / shape_dist_r.lipschitz;
+            if(closest_distance > distance){
+                closest_shape = i32(shape_dist_r.index);
+                root_shape = i32(i);
+                closest_distance = distance;
+            }
+        }
+
+        ray_pos += direction * closest_distance;
+        ray_length += closest_distance;
+        step_count += 1u;
+        if (closest_distance < closest_distance_g){
+            closest_distance_g = closest_distance;
+        }
+
+        if !( step_count < max_step
+                && threshold < closest_distance
+                && ray_length < max_length ){
+            break;
+        }
+    }
+    if(threshold > closest_distance){
+        res.hit_shape = closest_shape;
+        res.root_shape = root_shape;
+    }
+    res.ray_length = ray_length;
+    res.step_count = step_count;
+    res.hit_pos = ray_pos;
+    res.min_distance = closest_distance_g;
+    return res;
+};
+
+/////////////////////////////////////////////
+// Main 
+/////////////////////////////////////////////
+
+
+
+
+
+@stage(compute) @workgroup_size(16,16)
+fn render(@builtin(global_invocation_id) global_invocation_id: vec3<u32>){
+    let target_size = textureDimensions(target_texture);
+    let x = global_invocation_id.x;
+    let y = global_invocation_id.y;
+    // The workgroup count is rounded up, the last workgroups go past the edges of the texture
+    if (x >= u32(target_size.x) || y >= u32(target_size.y)) {
+        return;
+    }
+    let width = f32(target_size[0]);
+    let height = f32(target_size[1]);
+
+    let step_cap = 1000000u;
+    let render_distance = 100.0;
+    let shadow_blur = 5.0;
+    let hit_threshold = 0.00001;
+    let background_color = vec3<f32>(0.005, 0.0, 0.03);
+
+    let reflection_rays = 10u;
+
+
+
+    let reflection_threshold = 0.000001;
+
+    let shape_count = 5u;
+
+    let depth = 2.0;
+    var ray_direction = normalize(vec3<f32>((-f32(x) / width + 0.5) * camera.ratio, (-f32(y) / height + 0.5), camera.depth) * camera.ray_dir);
+
+    var ray : RayParams;
+    ray.max_length = render_distance;
+    ray.max_step = step_cap;
+    ray.threshold = hit_threshold;
+    ray.skip_shape = -1;
+
+    var color: vec3<f32> = vec3<f32>(0.0,0.0,0.0);
+    var color_weight:f32 = 1.0;
+    var latest_hit:Hit;
+    latest_hit.hit_pos = camera.position;
+    latest_hit.hit_shape = -1;
+    var bounce_count = 0u;
+    loop {
+        if (bounce_count >= reflection_rays || color_weight<reflection_threshold){
+            color = color * (1.0/(1.0-color_weight));
+            break;
+        }
+        ray.skip_shape = -1;// latest_hit.hit_shape;
+        latest_hit = send_ray(latest_hit.hit_pos, ray_direction, ray);
+        if (latest_hit.hit_shape < 0){
+            color += background_color * color_weight;
+            break;
+        }
+        var s = shapes[latest_hit.hit_shape];
+        var surface_info = shape_surface(latest_hit.hit_pos, u32(latest_hit.root_shape));
+
+        color = surface_info.normal * 0.5 + vec3<f32>(0.5, 0.5, 0.5);
+        break;
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+
+    }
+    textureStore(target_texture, vec2<i32>(i32(x),i32(y)), vec4<f32>(color,1.0));
+};
\ No newline at end of file
@@ -0,0 +1,304 @@
+use std::ops::Range;
+use bytemuck::Pod;
+use wgpu::{Buffer, BufferDescriptor, BufferUsages, Device, Queue};
+
+/// Storage buffer that is reallocated, doubling its capacity, when its content outgrows it
+pub struct GrowableBuffer {
+    label: &'static str,
+    element_size: u64,
+    capacity: u64,
+    // Only allocated by the first `reserve` when created without a device
+    buffer: Option<Buffer>
+}
+
+/// Usage of one of the buffers of a [`ShapeCollection`](crate::shapes::ShapeCollection)
+#[derive(Copy, Clone, Debug)]
+pub struct BufferStats {
+    pub label: &'static str,
+    /// Number of elements in the collection, uploaded or not
+    pub len: u64,
+    /// Number of elements the GPU buffer can hold
+    pub capacity: u64,
+    /// Size of the GPU buffer in bytes
+    pub size: u64
+}
+
+/// Writes the next upload of a buffer will make
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PendingUpload {
+    /// Number of `write_buffer` calls
+    pub writes: usize,
+    pub bytes: u64
+}
+
+impl GrowableBuffer {
+    pub fn new(device: &Device, label: &'static str, element_size: u64, capacity: u64) -> Self {
+        let buffer = Self::create_buffer(device, label, element_size * capacity);
+        Self{ label, element_size, capacity, buffer: Some(buffer) }
+    }
+
+    /// Buffer of `capacity` elements allocated by the first [`GrowableBuffer::reserve`]
+    pub fn unallocated(label: &'static str, element_size: u64, capacity: u64) -> Self {
+        Self{ label, element_size, capacity, buffer: None }
+    }
+
+    fn create_buffer(device: &Device, label: &'static str, size: u64) -> Buffer {
+        device.create_buffer(&BufferDescriptor {
+            label: Some(label),
+            size,
+            usage: BufferUsages::STORAGE|BufferUsages::COPY_DST,
+            mapped_at_creation: false
+        })
+    }
+
+    /// Make room for `len` elements, allocating the buffer if it wasn't yet.
+    /// Returns true when the buffer was reallocated, the bind groups using it must then be recreated.
+    pub fn reserve(&mut self, device: &Device, len: u64) -> bool {
+        if self.buffer.is_some() && len <= self.capacity {
+            return false;
+        }
+        let mut capacity = self.capacity.max(1);
+        while capacity < len {
+            capacity *= 2;
+        }
+        self.buffer = Some(Self::create_buffer(device, self.label, self.element_size * capacity));
+        self.capacity = capacity;
+        true
+    }
+
+    pub fn write(&self, queue: &Queue, offset: u64, bytes: &[u8]) {
+        queue.write_buffer(self.buffer(), offset, bytes);
+    }
+
+    /// Panics if the buffer wasn't allocated yet
+    pub fn buffer(&self) -> &Buffer {
+        self.buffer.as_ref().expect("The buffer is only allocated by its first upload")
+    }
+
+    /// Number of elements the buffer can hold
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn stats(&self, len: usize) -> BufferStats {
+        BufferStats{ label: self.label, len: len as u64, capacity: self.capacity, size: self.element_size * self.capacity }
+    }
+}
+
+/// CPU copy of one of the shape buffers, remembering which ranges of elements changed since the last upload
+pub struct ShapeArray<T: Pod> {
+    elements: Vec<T>,
+    buffer: GrowableBuffer,
+    // Ranges of elements to write on the next upload, sorted and disjoint
+    dirty: Vec<Range<usize>>
+}
+
+impl<T: Pod> ShapeArray<T> {
+    pub fn new(device: &Device, label: &'static str, capacity: u64) -> Self {
+        let buffer = GrowableBuffer::new(device, label, std::mem::size_of::<T>() as u64, capacity);
+        Self{ elements: vec![], buffer, dirty: vec![] }
+    }
+
+    /// Array whose GPU buffer is allocated by the first upload
+    pub fn unallocated(label: &'static str, capacity: u64) -> Self {
+        let buffer = GrowableBuffer::unallocated(label, std::mem::size_of::<T>() as u64, capacity);
+        Self{ elements: vec![], buffer, dirty: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.elements
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.elements.get(index)
+    }
+
+    /// Append an element and return its index
+    pub fn push(&mut self, element: T) -> usize {
+        let index = self.elements.len();
+        self.elements.push(element);
+        self.mark(index..index + 1);
+        index
+    }
+
+    pub fn set(&mut self, index: usize, element: T) {
+        self.elements[index] = element;
+        self.mark(index..index + 1);
+    }
+
+    /// Remove the element at `index` by moving the last one in its place.
+    /// Returns the previous index of the moved element, `None` when `index` was the last one.
+    pub fn swap_remove(&mut self, index: usize) -> Option<usize> {
+        let last = self.elements.len() - 1;
+        self.elements.swap_remove(index);
+        if index == last {
+            return None;
+        }
+        self.mark(index..index + 1);
+        Some(last)
+    }
+
+    /// Remove a range of elements, the ones after it are moved back to fill the gap
+    pub fn remove_range(&mut self, range: Range<usize>) {
+        let start = range.start;
+        self.elements.drain(range);
+        if start < self.elements.len() {
+            self.mark(start..self.elements.len());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.elements.clear();
+        self.dirty.clear();
+    }
+
+    /// Write every element on the next upload
+    pub fn mark_all(&mut self) {
+        self.dirty.clear();
+        if !self.elements.is_empty() {
+            self.dirty.push(0..self.elements.len());
+        }
+    }
+
+    fn mark(&mut self, range: Range<usize>) {
+        // Merge with the ranges it overlaps or touches
+        let first = self.dirty.partition_point(|dirty| dirty.end < range.start);
+        let last = self.dirty.partition_point(|dirty| dirty.start <= range.end);
+        if first == last {
+            self.dirty.insert(first, range);
+        } else {
+            let merged = self.dirty[first].start.min(range.start)..self.dirty[last - 1].end.max(range.end);
+            self.dirty.splice(first..last, std::iter::once(merged));
+        }
+    }
+
+    /// Dirty ranges still inside the array, removals can leave some past its end
+    fn dirty_ranges(&self) -> impl Iterator<Item=Range<usize>> + '_ {
+        let len = self.elements.len();
+        self.dirty.iter()
+            .map(move |range| range.start..range.end.min(len))
+            .filter(|range| !range.is_empty())
+    }
+
+    /// What [`ShapeArray::upload`] would write now
+    pub fn pending(&self) -> PendingUpload {
+        let size = std::mem::size_of::<T>() as u64;
+        if self.elements.len() as u64 > self.buffer.capacity() {
+            return PendingUpload{ writes: 1, bytes: self.elements.len() as u64 * size };
+        }
+        self.dirty_ranges().fold(PendingUpload::default(), |pending, range| PendingUpload{
+            writes: pending.writes + 1,
+            bytes: pending.bytes + range.len() as u64 * size
+        })
+    }
+
+    /// Write the dirty ranges to the GPU buffer, growing it if needed.
+    /// Returns true when the buffer was reallocated, everything is written again and
+    /// the bind groups using it must be recreated.
+    pub fn upload(&mut self, device: &Device, queue: &Queue) -> bool {
+        let reallocated = self.buffer.reserve(device, self.elements.len() as u64);
+        if reallocated {
+            self.mark_all();
+        }
+        let size = std::mem::size_of::<T>() as u64;
+        for range in self.dirty_ranges() {
+            self.buffer.write(queue, range.start as u64 * size, bytemuck::cast_slice(&self.elements[range]));
+        }
+        self.dirty.clear();
+        reallocated
+    }
+
+    pub fn buffer(&self) -> &GrowableBuffer {
+        &self.buffer
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        self.buffer.stats(self.elements.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Array of `len` elements, with nothing left to upload
+    fn clean_array(len: u32, capacity: u64) -> ShapeArray<u32> {
+        let mut array = ShapeArray::unallocated("Test", capacity);
+        for element in 0..len {
+            array.push(element);
+        }
+        array.dirty.clear();
+        array
+    }
+
+    #[test]
+    fn adjacent_marks_are_merged() {
+        let mut array = clean_array(8, 8);
+        array.set(2, 0);
+        array.set(3, 0);
+        array.set(5, 0);
+        assert_eq!(array.dirty, vec![2..4, 5..6]);
+        array.set(4, 0);
+        assert_eq!(array.dirty, vec![2..6]);
+        array.set(0, 0);
+        assert_eq!(array.dirty, vec![0..1, 2..6]);
+    }
+
+    #[test]
+    fn overlapping_marks_are_merged() {
+        let mut array = clean_array(16, 16);
+        array.mark(1..3);
+        array.mark(8..10);
+        array.mark(12..14);
+        array.mark(2..4);
+        assert_eq!(array.dirty, vec![1..4, 8..10, 12..14]);
+        array.mark(9..13);
+        assert_eq!(array.dirty, vec![1..4, 8..14]);
+        array.mark(0..16);
+        assert_eq!(array.dirty, vec![0..16]);
+    }
+
+    #[test]
+    fn swap_remove_moves_the_last_element() {
+        let mut array = clean_array(4, 4);
+        assert_eq!(array.swap_remove(3), None);
+        assert_eq!(array.as_slice(), &[0, 1, 2]);
+        assert!(array.dirty.is_empty());
+        assert_eq!(array.swap_remove(0), Some(2));
+        assert_eq!(array.as_slice(), &[2, 1]);
+        assert_eq!(array.dirty, vec![0..1]);
+    }
+
+    #[test]
+    fn remove_range_marks_the_moved_elements() {
+        let mut array = clean_array(6, 8);
+        array.set(5, 5);
+        array.remove_range(1..3);
+        assert_eq!(array.as_slice(), &[0, 3, 4, 5]);
+        // The range marked before the removal now ends past the array
+        assert_eq!(array.dirty_ranges().collect::<Vec<_>>(), vec![1..4]);
+        array.dirty.clear();
+        array.remove_range(2..4);
+        assert!(array.dirty.is_empty());
+    }
+
+    #[test]
+    fn pending_writes_everything_after_a_grow() {
+        let mut array = clean_array(4, 4);
+        array.set(0, 0);
+        array.set(2, 0);
+        let pending = array.pending();
+        assert_eq!((pending.writes, pending.bytes), (2, 8));
+        array.push(4);
+        let pending = array.pending();
+        assert_eq!((pending.writes, pending.bytes), (1, 20));
+    }
+}
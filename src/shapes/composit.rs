@@ -1,7 +1,14 @@
+use std::f32::consts::PI;
+use std::ops::Range;
+use cgmath::{InnerSpace, Matrix3, SquareMatrix, Vector3, Vector4};
 use crate::shapes::cuboid::Cuboid;
 use crate::shapes::sphere::Sphere;
+use crate::shapes::torus::Torus;
+use crate::shapes::primitives::{Primitive, PrimitiveRecord};
 use bytemuck::{Pod,Zeroable};
-use crate::shapes::ShapeProperties;
+use crate::shapes::{ShapeError, ShapeProperties};
+use crate::shapes::transform::Transform;
+use crate::shapes::specialize::{wgsl_f32, wgsl_vec4};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -9,20 +16,710 @@ pub struct Composit{
     a: u32,
     b: u32,
     comp_type:u32,
-    alpha:f32
+    alpha:f32,
+    // Parameters of the repetition and mirror nodes, see domain.wgsl
+    domain: [f32;4],
+    limit: [f32;4],
+    // Width over which the materials of the children are mixed, 0 for a hard seam
+    seam: f32,
+    // Non zero when the color and reflectivity of the composite replace the ones of its subtree
+    override_material: u32,
+    // 1 when a is evaluated before b, by default b is
+    a_first: u32,
+    // First instruction of the program of the tree in the program buffer, see program.rs.
+    // NO_PROGRAM until the composite is visible on its own.
+    program: u32
 }
 
+/// `program` of the composites inside a tree, which are drawn through their root
+const NO_PROGRAM: u32 = u32::MAX;
+
 impl Composit {
     pub(crate) fn new(a: u32, b: u32, comp_type: u32, alpha:f32) -> Self {
-        Self{a,b,comp_type, alpha }
+        Self{a,b,comp_type, alpha, domain: [0.0;4], limit: [0.0;4], seam: 0.0, override_material: 0, a_first: 0, program: NO_PROGRAM }
+    }
+
+    /// Node remapping the sample point of its only child
+    pub(crate) fn domain(a: u32, comp_type: u32, domain: [f32;4], limit: [f32;4]) -> Self {
+        Self{a, b: a, comp_type, alpha: 0.0, domain, limit, seam: 0.0, override_material: 0, a_first: 0, program: NO_PROGRAM }
+    }
+
+    /// Node deforming its only child, `lipschitz` bounds how much it shrinks the distances
+    pub(crate) fn deformation(a: u32, comp_type: u32, params: [f32;4], lipschitz: f32) -> Self {
+        Self{a, b: a, comp_type, alpha: lipschitz, domain: params, limit: [0.0;4], seam: 0.0, override_material: 0, a_first: 0, program: NO_PROGRAM }
+    }
+
+    /// Union or intersection of the children in `start..start + count` of the child lists
+    pub(crate) fn nary(start: u32, count: u32, comp_type: u32) -> Self {
+        Self::new(start, count, comp_type, 1.0)
+    }
+
+    /// Transform, domain and deformation nodes only have one child, stored in both a and b
+    pub(crate) fn single_child(&self) -> bool {
+        (4..=13).contains(&self.comp_type)
+    }
+
+    /// Mix the materials of the children over `width` on each side of the seam, in the units of the node
+    pub(crate) fn set_seam(&mut self, width: f32) {
+        self.seam = width.max(0.0);
+    }
+
+    /// Evaluate a before b, the result of the child evaluated first is kept on the stack while the other one is evaluated
+    pub(crate) fn set_a_first(&mut self, a_first: bool) {
+        self.a_first = a_first as u32;
+    }
+
+    /// First instruction of the program of the composite, if it was compiled
+    pub(crate) fn program(&self) -> Option<u32> {
+        (self.program != NO_PROGRAM).then_some(self.program)
+    }
+
+    pub(crate) fn set_program(&mut self, program: u32) {
+        self.program = program;
+    }
+
+    pub(crate) fn comp_type(&self) -> u32 {
+        self.comp_type
+    }
+
+    pub(crate) fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    pub(crate) fn a_first(&self) -> bool {
+        self.a_first != 0
+    }
+
+    pub(crate) fn seam(&self) -> f32 {
+        self.seam
+    }
+
+    pub(crate) fn overrides_material(&self) -> bool {
+        self.override_material != 0
+    }
+
+    /// CPU reference of `domain_point`
+    pub(crate) fn domain_point(&self, p: Vector4<f32>) -> Vector4<f32> {
+        let mut q = p.truncate();
+        let domain = Vector4::from(self.domain);
+        match self.comp_type {
+            5 => {
+                let mut cell = q.zip(domain.truncate(), |q, size| round_even(q / size));
+                if self.limit[3] > 0.0 {
+                    cell = cell.zip(Vector3::new(self.limit[0], self.limit[1], self.limit[2]), |cell, limit| wgsl_clamp(cell, -limit, limit));
+                }
+                for axis in 0..3 {
+                    if domain[axis] > 0.0 {
+                        q[axis] -= domain[axis] * cell[axis];
+                    }
+                }
+            }
+            6 => {
+                let d = q.dot(domain.truncate()) - domain.w;
+                if d < 0.0 {
+                    q -= 2.0 * d * domain.truncate();
+                }
+            }
+            7 => {
+                let sector = 2.0 * PI / domain.x;
+                let angle = round_even(q.z.atan2(q.x) / sector) * sector;
+                q = Vector3::new(q.x * angle.cos() + q.z * angle.sin(), q.y, q.z * angle.cos() - q.x * angle.sin());
+            }
+            8 => {
+                let angle = domain.x * q.y;
+                q = Vector3::new(angle.cos() * q.x - angle.sin() * q.z, q.y, angle.sin() * q.x + angle.cos() * q.z);
+            }
+            9 => {
+                let angle = domain.x * q.x;
+                q = Vector3::new(angle.cos() * q.x - angle.sin() * q.y, angle.sin() * q.x + angle.cos() * q.y, q.z);
+            }
+            13 => {
+                q -= q.zip(domain.truncate(), |q, size| wgsl_clamp(q, -size, size));
+            }
+            _ => {}
+        }
+        q.extend(p.w)
+    }
+
+    /// CPU reference of `domain_distance`
+    pub(crate) fn domain_distance(&self, d: f32, p: Vector4<f32>) -> f32 {
+        match self.comp_type {
+            10 => d + self.domain[0] * value_noise(p.truncate() * self.domain[1]) * p.w,
+            11 => d - self.domain[0] * p.w,
+            12 => d.abs() - self.domain[0] * p.w,
+            _ => d
+        }
+    }
+
+    /// CPU reference of `domain_normal_matrix`
+    pub(crate) fn domain_normal_matrix(&self, p: Vector3<f32>) -> Matrix3<f32> {
+        let domain = Vector4::from(self.domain);
+        match self.comp_type {
+            6 => {
+                let n = domain.truncate();
+                if p.dot(n) - domain.w < 0.0 {
+                    Matrix3::from_cols(Vector3::unit_x() - 2.0 * n.x * n, Vector3::unit_y() - 2.0 * n.y * n, Vector3::unit_z() - 2.0 * n.z * n)
+                } else {
+                    Matrix3::identity()
+                }
+            }
+            7 => {
+                let sector = 2.0 * PI / domain.x;
+                let angle = round_even(p.z.atan2(p.x) / sector) * sector;
+                Matrix3::new(angle.cos(), 0.0, angle.sin(), 0.0, 1.0, 0.0, -angle.sin(), 0.0, angle.cos())
+            }
+            8 => {
+                let angle = domain.x * p.y;
+                Matrix3::new(angle.cos(), 0.0, -angle.sin(), 0.0, 1.0, 0.0, angle.sin(), 0.0, angle.cos())
+            }
+            9 => {
+                let angle = domain.x * p.x;
+                Matrix3::new(angle.cos(), -angle.sin(), 0.0, angle.sin(), angle.cos(), 0.0, 0.0, 0.0, 1.0)
+            }
+            _ => Matrix3::identity()
+        }
+    }
+
+    /// CPU reference of `displacement_gradient`
+    pub(crate) fn displacement_gradient(&self, p: Vector3<f32>) -> Vector3<f32> {
+        let e = 0.001;
+        let f = self.domain[1];
+        let derivative = |axis: Vector3<f32>| value_noise((p + axis * e) * f) - value_noise((p - axis * e) * f);
+        self.domain[0] * Vector3::new(derivative(Vector3::unit_x()), derivative(Vector3::unit_y()), derivative(Vector3::unit_z())) / (2.0 * e)
+    }
+
+    /// `Composite` constructor with the values of the node
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Composite({}u, {}u, {}u, {}, {}, {}, {}, {}u, {}u, {}u)",
+            self.a, self.b, self.comp_type, wgsl_f32(self.alpha), wgsl_vec4(self.domain), wgsl_vec4(self.limit),
+            wgsl_f32(self.seam), self.override_material, self.a_first, self.program)
+    }
+
+    /// Replace the material of the subtree by the one of the composite
+    pub(crate) fn set_override_material(&mut self, override_material: bool) {
+        self.override_material = override_material as u32;
+    }
+
+    /// Range of the children of an n-ary node in the child lists
+    pub(crate) fn child_range(&self) -> Option<Range<usize>> {
+        match self.comp_type {
+            17 | 18 => Some(self.a as usize..(self.a + self.b) as usize),
+            _ => None
+        }
+    }
+
+    /// Move the children of an n-ary node to `start` in the child lists
+    pub(crate) fn set_child_start(&mut self, start: u32) {
+        self.a = start;
+    }
+
+    /// Indices of the children in the shape buffer
+    pub(crate) fn children(&self, child_lists: &[u32]) -> Vec<u32> {
+        if let Some(range) = self.child_range() {
+            child_lists[range].to_vec()
+        } else if self.single_child() {
+            vec![self.a]
+        } else {
+            vec![self.a, self.b]
+        }
+    }
+
+    /// Children in the order the shader evaluates them
+    pub(crate) fn evaluation_order(&self, child_lists: &[u32]) -> Vec<u32> {
+        if self.child_range().is_some() || self.single_child() || self.a_first != 0 {
+            self.children(child_lists)
+        } else {
+            vec![self.b, self.a]
+        }
+    }
+
+    /// Point the child at shape index `old` to `new`, returns false if `old` is not a child.
+    /// The children of n-ary nodes are in the child lists, they aren't changed.
+    pub(crate) fn replace_child(&mut self, old: u32, new: u32) -> bool {
+        let found = self.a == old || self.b == old;
+        if self.a == old {
+            self.a = new;
+        }
+        if self.b == old {
+            self.b = new;
+        }
+        found
+    }
+}
+
+/// Slots of the evaluation stacks of the shader a tree needs, see [`stack_depth`](crate::shapes::program::stack_depth)
+/// for the same count on a compiled program
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackUsage{
+    /// Frames of the composites on the path to the evaluated shape, plus the point of the ray
+    pub frames: u32,
+    /// Results of the children kept while their siblings are evaluated, plus the evaluated one
+    pub results: u32
+}
+
+impl StackUsage {
+    const LEAF: StackUsage = StackUsage{ frames: 1, results: 1 };
+
+    /// Size the stacks must have to evaluate the tree
+    pub fn depth(&self) -> u32 {
+        self.frames.max(self.results)
+    }
+
+    fn single(child: StackUsage) -> Self {
+        StackUsage{ frames: child.frames + 1, results: child.results }
+    }
+
+    /// Node evaluating its children one at a time in this order, folding each result in the ones of the children before it
+    fn sequence(children: impl Iterator<Item=StackUsage>) -> Self {
+        let mut usage = StackUsage{ frames: 0, results: 0 };
+        for (k, child) in children.enumerate() {
+            usage.frames = usage.frames.max(child.frames + 1);
+            usage.results = usage.results.max(child.results + (k > 0) as u32);
+        }
+        usage
+    }
+}
+
+/// Order the children are evaluated in, the one using the most result slots first.
+/// Its result would otherwise be kept on the stack while it is evaluated.
+pub(crate) fn deepest_first(usages: &[StackUsage]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..usages.len()).collect();
+    if let Some(deepest) = (0..usages.len()).max_by_key(|k| (usages[*k].results, usages.len() - k)) {
+        order.remove(deepest);
+        order.insert(0, deepest);
     }
+    order
 }
 
+#[allow(non_camel_case_types)]
 pub enum CompositDescriptor{
     CUBOID(Cuboid, ShapeProperties),
     SPHERE(Sphere, ShapeProperties),
+    TORUS(Torus, ShapeProperties),
+    /// Shape type and record of a [`Primitive`]
+    PRIMITIVE(u32, PrimitiveRecord, ShapeProperties),
+    /// Nested unions are flattened in a single node, see [`CompositDescriptor::UNION_ALL`]
     UNION(Box<CompositDescriptor>,Box<CompositDescriptor>),
+    /// Union of any number of shapes, evaluated one after the other so the tree doesn't get deeper
+    /// with the number of shapes
+    UNION_ALL(Vec<CompositDescriptor>),
     BLEND(Box<CompositDescriptor>,Box<CompositDescriptor>, f32),
     INTERSECTION(Box<CompositDescriptor>,Box<CompositDescriptor>),
-    DIFFERENCE(Box<CompositDescriptor>,Box<CompositDescriptor>)
-}
\ No newline at end of file
+    INTERSECTION_ALL(Vec<CompositDescriptor>),
+    DIFFERENCE(Box<CompositDescriptor>,Box<CompositDescriptor>),
+    /// Union rounding the seam over a radius, in the units of the node
+    SMOOTH_UNION(Box<CompositDescriptor>,Box<CompositDescriptor>, f32),
+    /// Intersection rounding the seam over a radius
+    SMOOTH_INTERSECTION(Box<CompositDescriptor>,Box<CompositDescriptor>, f32),
+    /// The second shape minus the first one like [`CompositDescriptor::DIFFERENCE`], rounding the seam over a radius
+    SMOOTH_DIFFERENCE(Box<CompositDescriptor>,Box<CompositDescriptor>, f32),
+    /// Moves the subtree by an offset
+    TRANSLATE(Box<CompositDescriptor>, [f32;3]),
+    /// Rotates the subtree around the origin, euler angles in radians
+    ROTATE(Box<CompositDescriptor>, [f32;3]),
+    /// Scales the subtree from the origin, see [`Transform`] for non-uniform scales
+    SCALE(Box<CompositDescriptor>, [f32;3]),
+    TRANSFORM(Box<CompositDescriptor>, Transform),
+    /// Repeats the subtree on a grid of cells of the given size centered on the origin, a size of 0
+    /// doesn't repeat along that axis. With a limit, only that many copies are made on each side of the origin.
+    /// The subtree should fit in its cell, the distance to the neighbouring copies is not checked.
+    REPEAT(Box<CompositDescriptor>, [f32;3], Option<[u32;3]>),
+    /// Mirrors the subtree across the plane of normal `.1` at distance `.2` from the origin,
+    /// the part of the subtree on the side the normal points to is reflected on the other side
+    MIRROR(Box<CompositDescriptor>, [f32;3], f32),
+    /// Repeats the subtree around the y axis, the copy on the positive x axis being the original
+    POLAR(Box<CompositDescriptor>, u32),
+    /// Twists the subtree around the y axis by `.1` radians per unit, `.2` is the largest distance
+    /// from the subtree to the axis, used to bound the steps of the ray
+    TWIST(Box<CompositDescriptor>, f32, f32),
+    /// Bends the x axis of the subtree toward y by `.1` radians per unit, `.2` is the largest distance
+    /// from the subtree to the origin, used to bound the steps of the ray
+    BEND(Box<CompositDescriptor>, f32, f32),
+    /// Adds value noise of amplitude `.1` and frequency `.2` to the distance
+    DISPLACE(Box<CompositDescriptor>, f32, f32),
+    /// Rounds the edges of the subtree, making it `.1` bigger
+    ROUND(Box<CompositDescriptor>, f32),
+    /// Hollows the subtree, keeping a shell of thickness `.1` around its surface
+    ONION(Box<CompositDescriptor>, f32),
+    /// Stretches the subtree by `.1` on each side of the origin along each axis
+    ELONGATE(Box<CompositDescriptor>, [f32;3]),
+    /// Paints the whole subtree with the color and reflectivity of the properties
+    MATERIAL(Box<CompositDescriptor>, ShapeProperties),
+    /// Mixes the materials of the children of the root of the subtree over `.1` on each side of their seam,
+    /// no effect if the root is not a composite
+    MATERIAL_BLEND(Box<CompositDescriptor>, f32)
+}
+
+/// Bound of the gradient of the value noise of the displacement nodes, for a unit amplitude and frequency
+const NOISE_LIPSCHITZ: f32 = 5.2;
+
+/// Factor by which the deformation of a node can shrink distances
+pub(crate) fn twist_lipschitz(rate: f32, radius: f32) -> f32 {
+    (1.0 + (rate * radius).powi(2)).sqrt()
+}
+
+pub(crate) fn displacement_lipschitz(amplitude: f32, frequency: f32) -> f32 {
+    1.0 + (amplitude * frequency).abs() * NOISE_LIPSCHITZ
+}
+
+/// `round` of WGSL, halfway cases go to the even integer
+fn round_even(x: f32) -> f32 {
+    if x - x.floor() == 0.5 {
+        2.0 * (x * 0.5).round()
+    } else {
+        x.round()
+    }
+}
+
+/// `clamp` of WGSL, which doesn't panic when `low > high` like `f32::clamp`
+pub(crate) fn wgsl_clamp(x: f32, low: f32, high: f32) -> f32 {
+    x.max(low).min(high)
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+/// CPU reference of `lattice_hash`
+fn lattice_hash(p: Vector3<f32>) -> f32 {
+    fract(p.dot(Vector3::new(127.1, 311.7, 74.7)).sin() * 43758.547) * 2.0 - 1.0
+}
+
+/// CPU reference of `value_noise`
+fn value_noise(p: Vector3<f32>) -> f32 {
+    let i = p.map(f32::floor);
+    let f = p - i;
+    let u = f.map(|f| f * f * (3.0 - 2.0 * f));
+    let hash = |x: f32, y: f32, z: f32| lattice_hash(i + Vector3::new(x, y, z));
+    let x00 = mix(hash(0.0, 0.0, 0.0), hash(1.0, 0.0, 0.0), u.x);
+    let x10 = mix(hash(0.0, 1.0, 0.0), hash(1.0, 1.0, 0.0), u.x);
+    let x01 = mix(hash(0.0, 0.0, 1.0), hash(1.0, 0.0, 1.0), u.x);
+    let x11 = mix(hash(0.0, 1.0, 1.0), hash(1.0, 1.0, 1.0), u.x);
+    mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z)
+}
+
+impl CompositDescriptor {
+    pub fn primitive<T:Primitive>(primitive:&T, props:ShapeProperties) -> Self {
+        CompositDescriptor::PRIMITIVE(T::SHAPE_TYPE, primitive.record(), props)
+    }
+
+    /// Operands of the union (`intersection` false) or intersection this descriptor is the root of,
+    /// looking through the nested unions or intersections
+    pub(crate) fn operands(&self, intersection: bool) -> Vec<&CompositDescriptor> {
+        match self {
+            CompositDescriptor::UNION(a, b) if !intersection => [a.operands(false), b.operands(false)].concat(),
+            CompositDescriptor::UNION_ALL(shapes) if !intersection => shapes.iter().flat_map(|shape| shape.operands(false)).collect(),
+            CompositDescriptor::INTERSECTION(a, b) if intersection => [a.operands(true), b.operands(true)].concat(),
+            CompositDescriptor::INTERSECTION_ALL(shapes) if intersection => shapes.iter().flat_map(|shape| shape.operands(true)).collect(),
+            other => vec![other]
+        }
+    }
+
+    /// Stack slots the shader needs to evaluate the tree, with the children in the order
+    /// [`ShapeCollection::create_composite`](crate::shapes::ShapeCollection::create_composite) gives them
+    pub fn stack_usage(&self) -> StackUsage {
+        match self {
+            CompositDescriptor::CUBOID(..)
+            | CompositDescriptor::SPHERE(..)
+            | CompositDescriptor::TORUS(..)
+            | CompositDescriptor::PRIMITIVE(..) => StackUsage::LEAF,
+            CompositDescriptor::UNION(..) | CompositDescriptor::UNION_ALL(_) => Self::operands_usage(self.operands(false)),
+            CompositDescriptor::INTERSECTION(..) | CompositDescriptor::INTERSECTION_ALL(_) => Self::operands_usage(self.operands(true)),
+            CompositDescriptor::DIFFERENCE(a, b)
+            | CompositDescriptor::BLEND(a, b, _)
+            | CompositDescriptor::SMOOTH_UNION(a, b, _)
+            | CompositDescriptor::SMOOTH_INTERSECTION(a, b, _)
+            | CompositDescriptor::SMOOTH_DIFFERENCE(a, b, _) => {
+                let usages = [a.stack_usage(), b.stack_usage()];
+                StackUsage::sequence(deepest_first(&usages).into_iter().map(|k| usages[k]))
+            },
+            CompositDescriptor::TRANSLATE(child, _)
+            | CompositDescriptor::ROTATE(child, _)
+            | CompositDescriptor::SCALE(child, _)
+            | CompositDescriptor::TRANSFORM(child, _)
+            | CompositDescriptor::REPEAT(child, _, _)
+            | CompositDescriptor::MIRROR(child, _, _)
+            | CompositDescriptor::POLAR(child, _)
+            | CompositDescriptor::TWIST(child, _, _)
+            | CompositDescriptor::BEND(child, _, _)
+            | CompositDescriptor::DISPLACE(child, _, _)
+            | CompositDescriptor::ROUND(child, _)
+            | CompositDescriptor::ONION(child, _)
+            | CompositDescriptor::ELONGATE(child, _) => StackUsage::single(child.stack_usage()),
+            // Both change the node they wrap instead of adding one
+            CompositDescriptor::MATERIAL(child, _) | CompositDescriptor::MATERIAL_BLEND(child, _) => child.stack_usage()
+        }
+    }
+
+    /// Check the tree can be added to a [`ShapeCollection`](crate::shapes::ShapeCollection),
+    /// the stack depth is checked separately with [`CompositDescriptor::stack_usage`]
+    pub fn validate(&self) -> Result<(), ShapeError> {
+        match self {
+            CompositDescriptor::UNION_ALL(shapes) | CompositDescriptor::INTERSECTION_ALL(shapes) if shapes.is_empty() => Err(ShapeError::EmptyGroup),
+            // Normalizing a null or NaN normal would give NaN and hide the subtree
+            CompositDescriptor::MIRROR(_, normal, _) if !Vector3::from(*normal).magnitude2().is_normal() => Err(ShapeError::ZeroNormal(*normal)),
+            other => other.subtrees().into_iter().try_for_each(|child| child.validate())
+        }
+    }
+
+    /// Direct children of the root of the tree
+    fn subtrees(&self) -> Vec<&CompositDescriptor> {
+        match self {
+            CompositDescriptor::CUBOID(..)
+            | CompositDescriptor::SPHERE(..)
+            | CompositDescriptor::TORUS(..)
+            | CompositDescriptor::PRIMITIVE(..) => vec![],
+            CompositDescriptor::UNION_ALL(shapes) | CompositDescriptor::INTERSECTION_ALL(shapes) => shapes.iter().collect(),
+            CompositDescriptor::UNION(a, b)
+            | CompositDescriptor::INTERSECTION(a, b)
+            | CompositDescriptor::DIFFERENCE(a, b)
+            | CompositDescriptor::BLEND(a, b, _)
+            | CompositDescriptor::SMOOTH_UNION(a, b, _)
+            | CompositDescriptor::SMOOTH_INTERSECTION(a, b, _)
+            | CompositDescriptor::SMOOTH_DIFFERENCE(a, b, _) => vec![a, b],
+            CompositDescriptor::TRANSLATE(child, _)
+            | CompositDescriptor::ROTATE(child, _)
+            | CompositDescriptor::SCALE(child, _)
+            | CompositDescriptor::TRANSFORM(child, _)
+            | CompositDescriptor::REPEAT(child, _, _)
+            | CompositDescriptor::MIRROR(child, _, _)
+            | CompositDescriptor::POLAR(child, _)
+            | CompositDescriptor::TWIST(child, _, _)
+            | CompositDescriptor::BEND(child, _, _)
+            | CompositDescriptor::DISPLACE(child, _, _)
+            | CompositDescriptor::ROUND(child, _)
+            | CompositDescriptor::ONION(child, _)
+            | CompositDescriptor::ELONGATE(child, _)
+            | CompositDescriptor::MATERIAL(child, _)
+            | CompositDescriptor::MATERIAL_BLEND(child, _) => vec![child]
+        }
+    }
+
+    fn operands_usage(operands: Vec<&CompositDescriptor>) -> StackUsage {
+        if let [only] = operands[..] {
+            return only.stack_usage();
+        }
+        let usages: Vec<StackUsage> = operands.iter().map(|operand| operand.stack_usage()).collect();
+        StackUsage::sequence(deepest_first(&usages).into_iter().map(|k| usages[k]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+    use crate::color::Color;
+    use crate::shapes::ShapeCollection;
+    use crate::shapes::registry::PrimitiveRegistry;
+    use crate::shapes::sphere::Sphere;
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn sphere_at(position: [f32;3], radius: f32) -> CompositDescriptor {
+        let props = ShapeProperties{ color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: false };
+        CompositDescriptor::SPHERE(Sphere::new(position, radius), props)
+    }
+
+    /// Distances from `points` to the tree of `desc`, computed by the CPU reference of the shader
+    fn distances(desc: &CompositDescriptor, points: &[[f32;3]]) -> Vec<f32> {
+        let mut collection = ShapeCollection::without_device();
+        let handle = collection.create_composite(desc).unwrap();
+        let index = collection.index_of(handle).unwrap();
+        let registry = PrimitiveRegistry::builtin();
+        points.iter().map(|point| collection.shape_distance(&registry, Vector3::from(*point), index, -1).distance).collect()
+    }
+
+    fn assert_distances(desc: &CompositDescriptor, expected: &[([f32;3], f32)]) {
+        let points: Vec<[f32;3]> = expected.iter().map(|(point, _)| *point).collect();
+        for ((point, expected), actual) in expected.iter().zip(distances(desc, &points)) {
+            assert!((actual - expected).abs() < EPSILON, "{:?}: {} instead of {}", point, actual, expected);
+        }
+    }
+
+    #[test]
+    fn transforms() {
+        let translated = CompositDescriptor::TRANSLATE(Box::new(sphere_at([0.0;3], 1.0)), [2.0, 0.0, 0.0]);
+        assert_distances(&translated, &[([2.0, 0.0, 0.0], -1.0), ([5.0, 0.0, 0.0], 2.0), ([0.0, 0.0, 0.0], 1.0)]);
+        // A quarter turn around z takes x to y
+        let rotated = CompositDescriptor::ROTATE(Box::new(sphere_at([2.0, 0.0, 0.0], 1.0)), [0.0, 0.0, FRAC_PI_2]);
+        assert_distances(&rotated, &[([0.0, 2.0, 0.0], -1.0), ([2.0, 0.0, 0.0], 2f32.sqrt() * 2.0 - 1.0)]);
+        // The distances of the scaled frame are scaled back
+        let scaled = CompositDescriptor::SCALE(Box::new(sphere_at([1.0, 0.0, 0.0], 1.0)), [2.0;3]);
+        assert_distances(&scaled, &[([2.0, 0.0, 0.0], -2.0), ([7.0, 0.0, 0.0], 3.0)]);
+        let transform = Transform::from_euler([0.0, 1.0, 0.0], [0.0, 0.0, FRAC_PI_2], [2.0;3]);
+        let transformed = CompositDescriptor::TRANSFORM(Box::new(sphere_at([1.0, 0.0, 0.0], 0.5)), transform);
+        assert_distances(&transformed, &[([0.0, 3.0, 0.0], -1.0), ([0.0, 6.0, 0.0], 2.0)]);
+        // Nested transforms apply from the outside in
+        let nested = CompositDescriptor::TRANSLATE(Box::new(CompositDescriptor::SCALE(Box::new(sphere_at([0.0;3], 1.0)), [3.0;3])), [0.0, 0.0, -1.0]);
+        assert_distances(&nested, &[([0.0, 0.0, 2.0], 0.0), ([0.0, 0.0, -1.0], -3.0)]);
+    }
+
+    #[test]
+    fn repetitions() {
+        // A period away along a repeated axis, the distance is the same
+        let repeated = CompositDescriptor::REPEAT(Box::new(sphere_at([0.0;3], 0.5)), [3.0, 0.0, 2.0], None);
+        let points = [[0.2, 0.1, 0.3], [3.2, 0.1, 0.3], [-5.8, 0.1, 4.3], [0.2, 0.1, -1.7]];
+        let d = distances(&repeated, &points);
+        for (point, distance) in points.iter().zip(&d) {
+            assert!((distance - d[0]).abs() < EPSILON, "{:?}: {} instead of {}", point, distance, d[0]);
+        }
+        // Not along y, whose size is 0
+        assert_distances(&repeated, &[([0.0, 3.0, 0.0], 2.5), ([1.5, 0.0, 0.0], 1.0)]);
+        // One copy on each side of the origin along x, the last one is the closest past it
+        let limited = CompositDescriptor::REPEAT(Box::new(sphere_at([0.0;3], 0.5)), [3.0, 0.0, 0.0], Some([1, 0, 0]));
+        assert_distances(&limited, &[([3.0, 0.0, 0.0], -0.5), ([-3.0, 0.0, 0.0], -0.5), ([7.0, 0.0, 0.0], 3.5), ([-9.0, 0.0, 0.0], 5.5)]);
+    }
+
+    #[test]
+    fn mirrors() {
+        // The side the normal points to is kept and reflected on the other side
+        let mirrored = CompositDescriptor::MIRROR(Box::new(sphere_at([2.0, 0.0, 0.0], 1.0)), [2.0, 0.0, 0.0], 0.0);
+        assert_distances(&mirrored, &[([2.0, 0.0, 0.0], -1.0), ([-2.0, 0.0, 0.0], -1.0), ([0.0, 0.0, 0.0], 1.0)]);
+        // The points are folded on the kept side, where a subtree on the other side is never reached
+        let hidden = CompositDescriptor::MIRROR(Box::new(sphere_at([-2.0, 0.0, 0.0], 1.0)), [1.0, 0.0, 0.0], 0.0);
+        assert_distances(&hidden, &[([-2.0, 0.0, 0.0], 3.0), ([2.0, 0.0, 0.0], 3.0)]);
+        // Plane at y = 1
+        let offset = CompositDescriptor::MIRROR(Box::new(sphere_at([0.0, 3.0, 0.0], 1.0)), [0.0, 1.0, 0.0], 1.0);
+        let d = distances(&offset, &[[0.5, 1.7, 0.2], [0.5, 0.3, 0.2]]);
+        assert!((d[0] - d[1]).abs() < EPSILON, "{:?}", d);
+        assert_distances(&offset, &[([0.0, -1.0, 0.0], -1.0)]);
+    }
+
+    #[test]
+    fn polar_repetitions() {
+        let polar = CompositDescriptor::POLAR(Box::new(sphere_at([2.0, 0.0, 0.0], 0.5)), 4);
+        assert_distances(&polar, &[
+            ([2.0, 0.0, 0.0], -0.5), ([0.0, 0.0, 2.0], -0.5), ([-2.0, 0.0, 0.0], -0.5), ([0.0, 0.0, -2.0], -0.5), ([0.0, 0.0, 0.0], 1.5)
+        ]);
+        // Halfway between two copies, on a circle through their centers
+        let halfway = [2.0 * FRAC_PI_4.cos(), 0.0, 2.0 * FRAC_PI_4.sin()];
+        assert_distances(&polar, &[(halfway, 2.0 * 2.0 * (PI / 8.0).sin() - 0.5)]);
+        // A single copy is the subtree itself
+        let single = CompositDescriptor::POLAR(Box::new(sphere_at([2.0, 0.0, 0.0], 0.5)), 1);
+        assert_distances(&single, &[([-2.0, 0.0, 0.0], 3.5)]);
+    }
+
+    #[test]
+    fn distance_modifiers() {
+        let child = || sphere_at([0.5, 0.0, 0.0], 1.0);
+        let points = [[0.5, 0.0, 0.0], [3.0, 0.2, -0.4], [-1.0, 2.0, 1.0], [0.9, 0.1, 0.0]];
+        let d = distances(&child(), &points);
+        let rounded = distances(&CompositDescriptor::ROUND(Box::new(child()), 0.25), &points);
+        let hollowed = distances(&CompositDescriptor::ONION(Box::new(child()), 0.25), &points);
+        for (i, point) in points.iter().enumerate() {
+            assert!((rounded[i] - (d[i] - 0.25)).abs() < EPSILON, "{:?}: {} rounded to {}", point, d[i], rounded[i]);
+            assert!((hollowed[i] - (d[i].abs() - 0.25)).abs() < EPSILON, "{:?}: {} hollowed to {}", point, d[i], hollowed[i]);
+        }
+    }
+
+    #[test]
+    fn elongations() {
+        let elongated = CompositDescriptor::ELONGATE(Box::new(sphere_at([0.0;3], 1.0)), [1.0, 0.0, 0.0]);
+        assert_distances(&elongated, &[
+            ([2.0, 0.0, 0.0], 0.0), ([1.5, 0.0, 0.0], -0.5), ([-3.0, 0.0, 0.0], 1.0), ([0.5, 2.0, 0.0], 1.0), ([0.0, 0.0, 0.0], -1.0)
+        ]);
+    }
+
+    #[test]
+    fn deformations() {
+        let child = || sphere_at([1.0, 0.5, 0.0], 0.5);
+        let points = [[1.0, 0.5, 0.0], [2.0, -1.0, 0.5], [-0.5, 1.5, 2.0]];
+        let d = distances(&child(), &points);
+        // Without a rate, twists and bends leave the subtree as it is
+        for deformed in [CompositDescriptor::TWIST(Box::new(child()), 0.0, 2.0), CompositDescriptor::BEND(Box::new(child()), 0.0, 2.0)] {
+            assert_eq!(distances(&deformed, &points), d);
+        }
+        // A twist only turns the points around the y axis, a sphere on the axis stays the same
+        let centered = || sphere_at([0.0, 0.5, 0.0], 1.0);
+        let twisted = CompositDescriptor::TWIST(Box::new(centered()), 1.5, 1.0);
+        assert_eq!(distances(&twisted, &points), distances(&centered(), &points));
+        // Which is not true of a bend
+        let bent = CompositDescriptor::BEND(Box::new(centered()), 1.5, 2.0);
+        assert!(distances(&bent, &points[1..]) != distances(&centered(), &points[1..]));
+        // The bound of the shrinking of the distances goes through to the ray marching
+        let mut collection = ShapeCollection::without_device();
+        let handle = collection.create_composite(&twisted).unwrap();
+        let index = collection.index_of(handle).unwrap();
+        let lipschitz = collection.shape_distance(&PrimitiveRegistry::builtin(), Vector3::new(1.0, 0.0, 0.0), index, -1).lipschitz;
+        assert!((lipschitz - twist_lipschitz(1.5, 1.0)).abs() < EPSILON && lipschitz > 1.0, "{}", lipschitz);
+    }
+
+    #[test]
+    fn displacements() {
+        let child = || sphere_at([0.0;3], 1.0);
+        let points: Vec<[f32;3]> = (0..20).map(|i| [i as f32 * 0.37 - 3.0, (i as f32 * 1.3).sin(), i as f32 * 0.11]).collect();
+        let d = distances(&child(), &points);
+        assert_eq!(distances(&CompositDescriptor::DISPLACE(Box::new(child()), 0.0, 4.0), &points), d);
+        let displaced = distances(&CompositDescriptor::DISPLACE(Box::new(child()), 0.2, 4.0), &points);
+        assert!(displaced != d);
+        for (i, point) in points.iter().enumerate() {
+            assert!((displaced[i] - d[i]).abs() <= 0.2 + EPSILON, "{:?}: {} displaced to {}", point, d[i], displaced[i]);
+        }
+    }
+
+    #[test]
+    fn smooth_operators() {
+        let a = || sphere_at([-0.5, 0.0, 0.0], 1.0);
+        let b = || sphere_at([0.5, 0.0, 0.0], 1.0);
+        let points: Vec<[f32;3]> = (0..25).map(|i| [i as f32 * 0.25 - 3.0, (i as f32 * 0.7).sin() * 0.5, 0.1]).collect();
+        let (da, db) = (distances(&a(), &points), distances(&b(), &points));
+        let union = distances(&CompositDescriptor::SMOOTH_UNION(Box::new(a()), Box::new(b()), 0.5), &points);
+        let intersection = distances(&CompositDescriptor::SMOOTH_INTERSECTION(Box::new(a()), Box::new(b()), 0.5), &points);
+        let difference = distances(&CompositDescriptor::SMOOTH_DIFFERENCE(Box::new(a()), Box::new(b()), 0.5), &points);
+        for (i, point) in points.iter().enumerate() {
+            assert!(union[i] <= da[i].min(db[i]) + EPSILON, "{:?}: union {} above {} and {}", point, union[i], da[i], db[i]);
+            assert!(intersection[i] >= da[i].max(db[i]) - EPSILON, "{:?}: intersection {} below {} and {}", point, intersection[i], da[i], db[i]);
+            assert!(difference[i] >= db[i].max(-da[i]) - EPSILON, "{:?}: difference {} below {} and {}", point, difference[i], db[i], -da[i]);
+            // Past the radius from the seam, the operators are the sharp ones
+            if (da[i] - db[i]).abs() >= 0.5 {
+                assert!((union[i] - da[i].min(db[i])).abs() < EPSILON, "{:?}: union {}", point, union[i]);
+                assert!((intersection[i] - da[i].max(db[i])).abs() < EPSILON, "{:?}: intersection {}", point, intersection[i]);
+            }
+        }
+        // On the seam, the distance moves by a quarter of the radius
+        let union = CompositDescriptor::SMOOTH_UNION(Box::new(sphere_at([-1.0, 0.0, 0.0], 0.5)), Box::new(sphere_at([1.0, 0.0, 0.0], 0.5)), 0.4);
+        assert_distances(&union, &[([0.0, 0.0, 0.0], 0.4), ([0.0, 1.0, 0.0], 2f32.sqrt() - 0.6), ([-1.5, 0.0, 0.0], 0.0)]);
+        let intersection = CompositDescriptor::SMOOTH_INTERSECTION(Box::new(a()), Box::new(b()), 0.4);
+        assert_distances(&intersection, &[([0.0, 0.0, 0.0], -0.4)]);
+    }
+
+    fn colored_sphere(position: [f32;3], color: Color) -> CompositDescriptor {
+        CompositDescriptor::SPHERE(Sphere::new(position, 1.0), ShapeProperties{ color, reflectivity: 0.0, visible: false })
+    }
+
+    /// Colors of the tree of `desc` at `points`, computed by the CPU reference of the shader
+    fn colors(desc: &CompositDescriptor, points: &[[f32;3]]) -> Vec<Vector3<f32>> {
+        let mut collection = ShapeCollection::without_device();
+        let handle = collection.create_composite(desc).unwrap();
+        let index = collection.index_of(handle).unwrap();
+        let registry = PrimitiveRegistry::builtin();
+        points.iter().map(|point| collection.shape_surface(&registry, Vector3::from(*point), index).color).collect()
+    }
+
+    #[test]
+    fn materials() {
+        let red = Vector3::new(1.0, 0.0, 0.0);
+        let blue = Vector3::new(0.0, 0.0, 1.0);
+        let spheres = || CompositDescriptor::UNION(
+            Box::new(colored_sphere([-1.0, 0.0, 0.0], Color(1.0, 0.0, 0.0))),
+            Box::new(colored_sphere([1.0, 0.0, 0.0], Color(0.0, 0.0, 1.0)))
+        );
+        let points = [[-2.0, 0.0, 0.0], [0.0, 0.0, 0.0], [2.0, 0.0, 0.0]];
+        // Each child keeps its own material, switching sharply at the seam
+        let sharp = colors(&spheres(), &points);
+        assert_eq!([sharp[0], sharp[2]], [red, blue]);
+        assert!(sharp[1] == red || sharp[1] == blue, "{:?}", sharp[1]);
+        // The material of the node replaces the ones of the whole subtree
+        let green = ShapeProperties{ color: Color(0.0, 1.0, 0.0), reflectivity: 0.5, visible: false };
+        let painted = CompositDescriptor::MATERIAL(Box::new(spheres()), green);
+        assert!(colors(&painted, &points).iter().all(|color| *color == Vector3::new(0.0, 1.0, 0.0)));
+        // Blended materials mix halfway on the seam, and not past the width of the blend
+        let blended = colors(&CompositDescriptor::MATERIAL_BLEND(Box::new(spheres()), 0.5), &points);
+        assert_eq!([blended[0], blended[2]], [red, blue]);
+        assert!((blended[1] - Vector3::new(0.5, 0.0, 0.5)).magnitude() < EPSILON, "{:?}", blended[1]);
+    }
+}
@@ -1,9 +1,12 @@
 use std::sync::Mutex;
 use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix, Vector3, Zero};
 use lazy_static::lazy_static;
 use rand_pcg::Lcg128Xsl64;
 use rand::distributions::{Distribution, Uniform};
 use rand::SeedableRng;
+use crate::shapes::{rotation_matrix, shader_matrix};
+use crate::shapes::specialize::{wgsl_mat3, wgsl_vec3};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -21,12 +24,7 @@ lazy_static!{
 
 impl Cuboid {
     pub fn new(position: [f32;3], scaling: [f32;3], euler: [f32;3])-> Self{
-        let [a,b,c] = euler;
-        let rotation = [
-            [b.cos()*c.cos(), a.sin()*b.sin()*c.cos()-a.cos()*c.sin(), a.cos()*b.sin()*c.cos()+a.sin()*c.sin() ,0.0],
-            [b.cos()*c.sin(), a.sin()*b.sin()*c.sin()+a.cos()*c.cos(), a.cos()*b.sin()*c.sin()-a.sin()*c.cos() ,0.0],
-            [-b.sin(), a.sin()*b.cos(),a.cos()*b.cos(),0.0],
-        ];
+        let rotation = rotation_matrix(euler);
         Self{
             position,
             scaling,
@@ -45,4 +43,44 @@ impl Cuboid {
         let sz = Uniform::new(c[2], d[2]).sample(rng);
         Self::new([x,y,z], [sx,sy,sz], [0.0,0.0,0.0])
     }
+
+    // Point in the frame of the cuboid, same as `a_centered*b.rotation` in the shader
+    fn local_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        shader_matrix(self.rotation).transpose() * (point - Vector3::from(self.position))
+    }
+
+    /// CPU reference of `cube_distance`, `point` being in the frame of the shape
+    pub fn distance(&self, point: Vector3<f32>) -> f32 {
+        let local = self.local_point(point);
+        let half_size = Vector3::from(self.scaling) / 2.0;
+        let offset = Vector3::new(local.x.abs() - half_size.x, local.y.abs() - half_size.y, local.z.abs() - half_size.z);
+        if offset.x < 0.0 && offset.y < 0.0 && offset.z < 0.0 {
+            -offset.magnitude()
+        } else {
+            Vector3::new(offset.x.max(0.0), offset.y.max(0.0), offset.z.max(0.0)).magnitude()
+        }
+    }
+
+    /// CPU reference of `cube_normal` : the axis of the closest face, in the frame of the cuboid like the shader
+    pub fn normal(&self, point: Vector3<f32>) -> Vector3<f32> {
+        let local = self.local_point(point);
+        let half_size = Vector3::from(self.scaling) / 2.0;
+        let scaled = Vector3::new(local.x / half_size.x, local.y / half_size.y, local.z / half_size.z);
+        let faces = [Vector3::unit_x(), -Vector3::unit_x(), Vector3::unit_y(), -Vector3::unit_y(), Vector3::unit_z(), -Vector3::unit_z()];
+        let mut magnet = Vector3::zero();
+        let mut distmag = 9999999.0;
+        for face in faces {
+            let d = (scaled - face).magnitude();
+            if distmag > d {
+                magnet = face;
+                distmag = d;
+            }
+        }
+        magnet
+    }
+
+    /// `Cuboid` constructor with the values of the cuboid
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Cuboid({}, {}, {})", wgsl_vec3(self.position), wgsl_vec3(self.scaling), wgsl_mat3(self.rotation))
+    }
 }
\ No newline at end of file
@@ -1,18 +1,37 @@
 pub mod sphere;
 pub mod cuboid;
+pub mod torus;
+pub mod primitives;
+pub mod registry;
 pub mod composit;
+pub mod transform;
+pub mod buffer;
+pub mod program;
+pub mod specialize;
+pub mod reference;
 
-use std::borrow::Borrow;
+use std::ops::Range;
+use cgmath::{InnerSpace, Matrix3, Vector3};
+use thiserror::Error;
 use bytemuck::{Pod, Zeroable};
-use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferDescriptor, BufferSize, BufferUsages, Device, Queue, ShaderStages};
+use wgpu::{BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutEntry, BindingType, Buffer, BufferBindingType, BufferSize, BufferUsages, Device, Queue, ShaderStages};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 use crate::color::Color;
-use crate::shapes::composit::{Composit, CompositDescriptor};
+use crate::shapes::buffer::{BufferStats, GrowableBuffer, PendingUpload, ShapeArray};
+use crate::shapes::composit::{deepest_first, displacement_lipschitz, twist_lipschitz, Composit, CompositDescriptor, StackUsage};
 use crate::shapes::cuboid::Cuboid;
 use crate::shapes::sphere::Sphere;
+use crate::shapes::torus::Torus;
+use crate::shapes::primitives::{Primitive, PrimitiveRecord};
+use crate::shapes::transform::Transform;
+use crate::shapes::program::{compile, Instruction, OP_END};
 
 
-const SHAPE_CAPACITY: u64 = 64;
+/// Number of elements the buffers are created with, they grow when more shapes are added
+const INITIAL_CAPACITY: u64 = 64;
+
+/// Size of the evaluation stacks of the shader, the `STACK_DEPTH` define of `src/shaders/raymarcher2.wgsl.json`
+pub const STACK_DEPTH: u32 = 20;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -22,7 +41,8 @@ pub struct Shape {
     shape_type: u32,
     reflectivity: f32,
     visible:u32,
-    _pad:[f32;1]
+    _pad:[f32;1],
+    transform: Transform
 }
 
 #[repr(C)]
@@ -37,7 +57,8 @@ impl Shape {
             index,
             reflectivity,
             visible,
-            _pad: [0.0;1]
+            _pad: [0.0;1],
+            transform: Transform::identity()
         }
     }
 
@@ -46,6 +67,22 @@ impl Shape {
     }
 }
 
+/// Rotation matrix from euler angles, rows padded to the std430 layout of `mat3x3<f32>`
+pub(crate) fn rotation_matrix(euler: [f32;3]) -> [[f32;4];3] {
+    let [a,b,c] = euler;
+    [
+        [b.cos()*c.cos(), a.sin()*b.sin()*c.cos()-a.cos()*c.sin(), a.cos()*b.sin()*c.cos()+a.sin()*c.sin() ,0.0],
+        [b.cos()*c.sin(), a.sin()*b.sin()*c.sin()+a.cos()*c.cos(), a.cos()*b.sin()*c.sin()-a.sin()*c.cos() ,0.0],
+        [-b.sin(), a.sin()*b.cos(),a.cos()*b.cos(),0.0],
+    ]
+}
+
+/// Matrix the shader reads from rows padded by [`rotation_matrix`], they are its columns
+pub(crate) fn shader_matrix(rows: [[f32;4];3]) -> Matrix3<f32> {
+    let column = |i:usize| Vector3::new(rows[i][0], rows[i][1], rows[i][2]);
+    Matrix3::from_cols(column(0), column(1), column(2))
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct ShapeProperties{
     pub color:Color,
@@ -55,182 +92,752 @@ pub struct ShapeProperties{
 
 
 
+/// Stable reference to a shape of a [`ShapeCollection`].
+/// Shapes move in the buffers when others are removed, their handle stays the same.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ShapeHandle{
+    id: u32,
+    generation: u32
+}
+
+#[derive(Copy, Clone, Debug)]
+struct HandleSlot{
+    // Incremented when the shape is removed so that the old handles are rejected
+    generation: u32,
+    index: Option<u32>
+}
+
+#[derive(Debug, Error)]
+pub enum ShapeError {
+    #[error("The shape {0:?} was removed")]
+    InvalidHandle(ShapeHandle),
+    #[error("The shape {0:?} has the type {1}, not {2}")]
+    WrongType(ShapeHandle, u32, u32),
+    #[error("The shape {0:?} is part of a composite, remove the composite instead")]
+    InUse(ShapeHandle),
+    #[error("The composite needs stacks of {0} slots to be evaluated but the shader only has {1}")]
+    TooDeep(u32, u32),
+    #[error("A union or intersection of all the shapes of an empty list")]
+    EmptyGroup,
+    #[error("The mirror normal {0:?} has no direction")]
+    ZeroNormal([f32;3]),
+}
+
+/// Array of the shape buffer a shape type points into
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Storage {
+    Sphere,
+    Cuboid,
+    Torus,
+    Composite,
+    Primitive
+}
+
+impl Storage {
+    fn of(shape_type: u32) -> Self {
+        match shape_type {
+            0 => Storage::Sphere,
+            1 => Storage::Cuboid,
+            2 => Storage::Torus,
+            9 => Storage::Composite,
+            _ => Storage::Primitive
+        }
+    }
+}
+
 pub struct ShapeCollection {
-    shapes: Vec<Shape>,
-    spheres: Vec<Sphere>,
-    cuboids: Vec<Cuboid>,
-    composits: Vec<Composit>,
+    shapes: ShapeArray<Shape>,
+    spheres: ShapeArray<Sphere>,
+    cuboids: ShapeArray<Cuboid>,
+    tori: ShapeArray<Torus>,
+    primitives: ShapeArray<PrimitiveRecord>,
+    composits: ShapeArray<Composit>,
+    // Children of the n-ary composites, each one owns a contiguous range. Only the CPU needs them,
+    // the programs list the children in the order they are evaluated.
+    child_lists: Vec<u32>,
+    // Program of every visible composite, each one ends with OP_END
+    programs: ShapeArray<Instruction>,
+    // The shape count changed
     dirty: bool,
 
-    count_uniform: wgpu::Buffer,
-    shapes_buffer: wgpu::Buffer,
-    spheres_buffer: wgpu::Buffer,
-    cuboids_buffer: wgpu::Buffer,
-    composits_buffer: wgpu::Buffer,
+    handles: Vec<HandleSlot>,
+    free_handles: Vec<u32>,
+    // Handle id of every shape, in the order of the shape buffer
+    shape_handles: Vec<u32>,
+
+    // None until the first upload for the collections created without a device
+    bindings: Option<ShapeBindings>
+}
 
+/// Uniform and bind group of the buffers of a [`ShapeCollection`]
+struct ShapeBindings {
+    count_uniform: wgpu::Buffer,
+    bind_group_layout: BindGroupLayout,
     bind_group: wgpu::BindGroup
 }
 
 impl ShapeCollection {
     pub fn new(device: &Device)->Self{
-        let (count_uniform,shapes_buffer,spheres_buffer, cuboids_buffer, composits_buffer) = Self::create_buffers(device);
+        let shapes = ShapeArray::new(device, "ShapeBuffer", INITIAL_CAPACITY);
+        let spheres = ShapeArray::new(device, "SphereBuffer", INITIAL_CAPACITY);
+        let cuboids = ShapeArray::new(device, "CuboidBuffer", INITIAL_CAPACITY);
+        let tori = ShapeArray::new(device, "TorusBuffer", INITIAL_CAPACITY);
+        let primitives = ShapeArray::new(device, "PrimitiveBuffer", INITIAL_CAPACITY);
+        let composits = ShapeArray::new(device, "CompositBuffer", INITIAL_CAPACITY);
+        let programs = ShapeArray::new(device, "ProgramBuffer", INITIAL_CAPACITY);
 
-        let bind_group_layout = Self::bind_group_layout(device);
-        let bind_group = device.create_bind_group(&BindGroupDescriptor{
-            label: Some("ShapesBindGroup"),
-            layout: &bind_group_layout,
-            entries: &[
-                BindGroupEntry{
-                    binding: 0,
-                    resource: count_uniform.as_entire_binding()
-                },
-                BindGroupEntry{
-                    binding: 1,
-                    resource: shapes_buffer.as_entire_binding()
-                },
-                BindGroupEntry{
-                    binding: 2,
-                    resource: spheres_buffer.as_entire_binding()
-                },
-                BindGroupEntry{
-                    binding: 3,
-                    resource: cuboids_buffer.as_entire_binding()
-                },
-                BindGroupEntry{
-                    binding: 4,
-                    resource: composits_buffer.as_entire_binding()
-                }
-            ]
+        let bindings = Some(Self::create_bindings(device, 0, [shapes.buffer(), spheres.buffer(), cuboids.buffer(), composits.buffer(), tori.buffer(), primitives.buffer(), programs.buffer()]));
+        Self{ shapes, spheres, cuboids, tori, primitives, composits, child_lists: vec![], programs, dirty: false, handles: vec![], free_handles: vec![], shape_handles: vec![], bindings }
+    }
+
+    /// Collection living on the CPU only, for the [`reference`](crate::reference) renderer or the tests.
+    /// Its GPU buffers are created by the first [`ShapeCollection::update_buffers`].
+    pub fn without_device()->Self{
+        Self{
+            shapes: ShapeArray::unallocated("ShapeBuffer", INITIAL_CAPACITY),
+            spheres: ShapeArray::unallocated("SphereBuffer", INITIAL_CAPACITY),
+            cuboids: ShapeArray::unallocated("CuboidBuffer", INITIAL_CAPACITY),
+            tori: ShapeArray::unallocated("TorusBuffer", INITIAL_CAPACITY),
+            primitives: ShapeArray::unallocated("PrimitiveBuffer", INITIAL_CAPACITY),
+            composits: ShapeArray::unallocated("CompositBuffer", INITIAL_CAPACITY),
+            child_lists: vec![],
+            programs: ShapeArray::unallocated("ProgramBuffer", INITIAL_CAPACITY),
+            dirty: false,
+            handles: vec![],
+            free_handles: vec![],
+            shape_handles: vec![],
+            bindings: None
+        }
+    }
+
+    /// Count uniform holding `count` and bind group of `buffers`, the storage buffers in the order of their binding
+    fn create_bindings(device: &Device, count: u32, buffers: [&GrowableBuffer; 7]) -> ShapeBindings {
+        let count_uniform = device.create_buffer_init(&BufferInitDescriptor{
+            label: Some("CountUniform"),
+            contents: bytemuck::cast_slice(&[ShapeCount(count)]),
+            usage: BufferUsages::UNIFORM|BufferUsages::COPY_DST
         });
+        let bind_group_layout = Self::bind_group_layout(device);
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &count_uniform, buffers);
+        ShapeBindings{ count_uniform, bind_group_layout, bind_group }
+    }
 
-        Self{ shapes: vec![], spheres: vec![], cuboids: vec![], composits: vec![], dirty: false, count_uniform, shapes_buffer, spheres_buffer, cuboids_buffer, composits_buffer, bind_group }
+    /// `buffers` are the storage buffers in the order of their binding
+    fn create_bind_group(device: &Device, layout: &BindGroupLayout, count_uniform: &Buffer, buffers: [&GrowableBuffer; 7]) -> BindGroup {
+        let mut entries = vec![
+            BindGroupEntry{
+                binding: 0,
+                resource: count_uniform.as_entire_binding()
+            }
+        ];
+        for (binding, buffer) in buffers.iter().enumerate() {
+            entries.push(BindGroupEntry{
+                binding: binding as u32 + 1,
+                resource: buffer.buffer().as_entire_binding()
+            });
+        }
+        device.create_bind_group(&BindGroupDescriptor{
+            label: Some("ShapesBindGroup"),
+            layout,
+            entries: &entries
+        })
     }
 
-    pub fn add_sphere(&mut self, sphere:Sphere, props:ShapeProperties)->u32{
-        let index = self.spheres.len() as u32;
-        self.spheres.push(sphere);
-        self.shapes.push(Shape::from_prop(props, 0, index));
+    /// Remove every shape, the handles given so far become invalid
+    pub fn clear(&mut self){
+        self.shapes.clear();
+        self.spheres.clear();
+        self.cuboids.clear();
+        self.tori.clear();
+        self.primitives.clear();
+        self.composits.clear();
+        self.child_lists.clear();
+        self.programs.clear();
+        self.shape_handles.clear();
+        for (id, slot) in self.handles.iter_mut().enumerate() {
+            if slot.index.take().is_some() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free_handles.push(id as u32);
+            }
+        }
         self.dirty = true;
-        (self.shapes.len()-1) as u32
     }
 
-    pub fn add_cube(&mut self, cuboid:Cuboid, props:ShapeProperties)->u32{
-        let index = self.cuboids.len() as u32;
-        self.cuboids.push(cuboid);
-        self.shapes.push(Shape::from_prop(props, 1, index));
+    /// Append a shape and give it a handle, returns its index in the shape buffer
+    fn push_shape(&mut self, shape: Shape) -> u32 {
+        let index = self.shapes.push(shape) as u32;
+        let id = match self.free_handles.pop() {
+            Some(id) => {
+                self.handles[id as usize].index = Some(index);
+                id
+            }
+            None => {
+                self.handles.push(HandleSlot{ generation: 0, index: Some(index) });
+                (self.handles.len() - 1) as u32
+            }
+        };
+        self.shape_handles.push(id);
         self.dirty = true;
-        (self.shapes.len()-1) as u32
+        if shape.visible != 0 {
+            self.ensure_program(index);
+        }
+        index
     }
 
-    pub fn create_composite(&mut self, desc:&CompositDescriptor)-> u32{
-        self.dirty = true;
-        self.generate_composite(desc,true)
+    /// Compile the program of the composite at `index` if it doesn't have one, its children are already in the buffers.
+    /// The shader only runs the programs of the visible shapes, the composites inside a tree get theirs
+    /// when [`ShapeCollection::set_visible`] draws them on their own.
+    fn ensure_program(&mut self, index: u32) {
+        let shape = self.shapes.as_slice()[index as usize];
+        if shape.shape_type != 9 || self.composits.as_slice()[shape.index as usize].program().is_some() {
+            return;
+        }
+        let program = compile(self.shapes.as_slice(), self.composits.as_slice(), &self.child_lists, index);
+        let start = self.programs.len() as u32;
+        for instruction in program {
+            self.programs.push(instruction);
+        }
+        let slot = shape.index as usize;
+        let mut composit = self.composits.as_slice()[slot];
+        composit.set_program(start);
+        self.composits.set(slot, composit);
+    }
+
+    fn handle_at(&self, index: u32) -> ShapeHandle {
+        let id = self.shape_handles[index as usize];
+        ShapeHandle{ id, generation: self.handles[id as usize].generation }
+    }
+
+    /// Current index of the shape in the shape buffer
+    fn index_of(&self, handle: ShapeHandle) -> Result<u32, ShapeError> {
+        match self.handles.get(handle.id as usize) {
+            Some(HandleSlot{ generation, index: Some(index) }) if *generation == handle.generation => Ok(*index),
+            _ => Err(ShapeError::InvalidHandle(handle))
+        }
+    }
+
+    fn shape(&self, handle: ShapeHandle) -> Result<(u32, Shape), ShapeError> {
+        let index = self.index_of(handle)?;
+        Ok((index, self.shapes.as_slice()[index as usize]))
+    }
+
+    /// Index in its own array of a shape of type `shape_type`
+    fn typed_index(&self, handle: ShapeHandle, shape_type: u32) -> Result<usize, ShapeError> {
+        let (_, shape) = self.shape(handle)?;
+        if shape.shape_type != shape_type {
+            return Err(ShapeError::WrongType(handle, shape.shape_type, shape_type));
+        }
+        Ok(shape.index as usize)
+    }
+
+    pub fn contains(&self, handle: ShapeHandle) -> bool {
+        self.index_of(handle).is_ok()
+    }
+
+    fn push_sphere(&mut self, sphere:Sphere, props:ShapeProperties)->u32{
+        let index = self.spheres.push(sphere) as u32;
+        self.push_shape(Shape::from_prop(props, 0, index))
+    }
+
+    fn push_cube(&mut self, cuboid:Cuboid, props:ShapeProperties)->u32{
+        let index = self.cuboids.push(cuboid) as u32;
+        self.push_shape(Shape::from_prop(props, 1, index))
+    }
+
+    fn push_torus(&mut self, torus:Torus, props:ShapeProperties)->u32{
+        let index = self.tori.push(torus) as u32;
+        self.push_shape(Shape::from_prop(props, 2, index))
+    }
+
+    fn push_primitive_record(&mut self, record:PrimitiveRecord, shape_type:u32, props:ShapeProperties)->u32{
+        let index = self.primitives.push(record) as u32;
+        self.push_shape(Shape::from_prop(props, shape_type, index))
+    }
+
+    pub fn add_sphere(&mut self, sphere:Sphere, props:ShapeProperties)->ShapeHandle{
+        let index = self.push_sphere(sphere, props);
+        self.handle_at(index)
+    }
+
+    pub fn add_cube(&mut self, cuboid:Cuboid, props:ShapeProperties)->ShapeHandle{
+        let index = self.push_cube(cuboid, props);
+        self.handle_at(index)
+    }
+
+    pub fn add_torus(&mut self, torus:Torus, props:ShapeProperties)->ShapeHandle{
+        let index = self.push_torus(torus, props);
+        self.handle_at(index)
+    }
+
+    /// Add a primitive, its type must be registered in the [`PrimitiveRegistry`](crate::shapes::registry::PrimitiveRegistry)
+    /// of the shader for it to be drawn
+    pub fn add_primitive<T:Primitive>(&mut self, primitive:&T, props:ShapeProperties)->ShapeHandle{
+        self.add_primitive_record(primitive.record(), T::SHAPE_TYPE, props)
+    }
+
+    pub fn add_primitive_record(&mut self, record:PrimitiveRecord, shape_type:u32, props:ShapeProperties)->ShapeHandle{
+        let index = self.push_primitive_record(record, shape_type, props);
+        self.handle_at(index)
+    }
+
+    /// Add the tree of `desc`, its children are hidden and only drawn through it.
+    /// The children are evaluated in the order needing the smallest stacks, the tree is refused
+    /// if it still needs more than the [`STACK_DEPTH`] slots of the shader or is not valid.
+    pub fn create_composite(&mut self, desc:&CompositDescriptor)-> Result<ShapeHandle, ShapeError>{
+        desc.validate()?;
+        // The compiled program needs the same depth (see the tests of program.rs), checking the descriptor
+        // refuses the tree before anything is pushed
+        let depth = desc.stack_usage().depth();
+        if depth > STACK_DEPTH {
+            return Err(ShapeError::TooDeep(depth, STACK_DEPTH));
+        }
+        let index = self.generate_composite(desc,true);
+        Ok(self.handle_at(index))
     }
 
     fn generate_composite(&mut self, desc:&CompositDescriptor, root:bool)->u32{
         match desc {
-            CompositDescriptor::CUBOID(cuboid, props) => {
-                dbg!(props);
-                self.add_cube(cuboid.clone(), props.clone())
+            CompositDescriptor::CUBOID(cuboid, props) => self.push_cube(*cuboid, *props),
+            CompositDescriptor::SPHERE(sphere, props) => self.push_sphere(*sphere, *props),
+            CompositDescriptor::TORUS(torus, props) => self.push_torus(*torus, *props),
+            CompositDescriptor::PRIMITIVE(shape_type, record, props) => self.push_primitive_record(*record, *shape_type, *props),
+            CompositDescriptor::UNION(_, _) | CompositDescriptor::UNION_ALL(_) => self.push_nary_node(desc, false, root),
+            CompositDescriptor::INTERSECTION(_, _) | CompositDescriptor::INTERSECTION_ALL(_) => self.push_nary_node(desc, true, root),
+            CompositDescriptor::DIFFERENCE(a, b) => self.push_binary_node(a, b, 2, 1.0, root),
+            CompositDescriptor::BLEND(a, b, alpha) => self.push_binary_node(a, b, 3, *alpha, root),
+            CompositDescriptor::SMOOTH_UNION(a, b, radius) => self.push_binary_node(a, b, 14, *radius, root),
+            CompositDescriptor::SMOOTH_INTERSECTION(a, b, radius) => self.push_binary_node(a, b, 15, *radius, root),
+            CompositDescriptor::SMOOTH_DIFFERENCE(a, b, radius) => self.push_binary_node(a, b, 16, *radius, root),
+            CompositDescriptor::TRANSLATE(child, position) => self.push_transform_node(child, Transform::from_translation(*position), root),
+            CompositDescriptor::ROTATE(child, euler) => self.push_transform_node(child, Transform::from_rotation(*euler), root),
+            CompositDescriptor::SCALE(child, scale) => self.push_transform_node(child, Transform::from_scale(*scale), root),
+            CompositDescriptor::TRANSFORM(child, transform) => self.push_transform_node(child, *transform, root),
+            CompositDescriptor::REPEAT(child, size, limit) => {
+                let [x, y, z] = *size;
+                let limit = match limit {
+                    Some([lx, ly, lz]) => [*lx as f32, *ly as f32, *lz as f32, 1.0],
+                    None => [0.0;4]
+                };
+                self.push_domain_node(child, 5, [x, y, z, 0.0], limit, root)
+            },
+            CompositDescriptor::MIRROR(child, normal, offset) => {
+                let normal = Vector3::from(*normal).normalize();
+                self.push_domain_node(child, 6, [normal.x, normal.y, normal.z, *offset], [0.0;4], root)
+            },
+            CompositDescriptor::POLAR(child, count) => self.push_domain_node(child, 7, [(*count).max(1) as f32, 0.0, 0.0, 0.0], [0.0;4], root),
+            CompositDescriptor::TWIST(child, rate, radius) => self.push_deformation_node(child, 8, [*rate, 0.0, 0.0, 0.0], twist_lipschitz(*rate, *radius), root),
+            CompositDescriptor::BEND(child, rate, radius) => self.push_deformation_node(child, 9, [*rate, 0.0, 0.0, 0.0], twist_lipschitz(*rate, *radius), root),
+            CompositDescriptor::DISPLACE(child, amplitude, frequency) => self.push_deformation_node(child, 10, [*amplitude, *frequency, 0.0, 0.0], displacement_lipschitz(*amplitude, *frequency), root),
+            CompositDescriptor::ROUND(child, radius) => self.push_deformation_node(child, 11, [*radius, 0.0, 0.0, 0.0], 1.0, root),
+            CompositDescriptor::ONION(child, thickness) => self.push_deformation_node(child, 12, [*thickness, 0.0, 0.0, 0.0], 1.0, root),
+            CompositDescriptor::ELONGATE(child, size) => {
+                let [x, y, z] = *size;
+                self.push_deformation_node(child, 13, [x, y, z, 0.0], 1.0, root)
             },
-            CompositDescriptor::SPHERE(sphere, props) => self.add_sphere(sphere.clone(),props.clone()),
-            CompositDescriptor::BLEND(a, b, alpha) => {
-                let ai = self.generate_composite(a.borrow(), false);
-                let bi = self.generate_composite(b.borrow(), false);
-                let u = Composit::new(ai, bi, 3, *alpha);
-                let cindex = self.composits.len() as u32;
-                self.composits.push(u);
-                let index = self.shapes.len() as u32;
-                self.shapes.push(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32));
-                index
+            CompositDescriptor::MATERIAL(child, props) => {
+                let ci = self.generate_composite(child, root);
+                let mut shape = self.shapes.as_slice()[ci as usize];
+                shape.color = props.color;
+                shape.reflectivity = props.reflectivity;
+                self.shapes.set(ci as usize, shape);
+                // A leaf already uses its own material, a composite has to replace the ones of its children
+                if shape.shape_type == 9 {
+                    let mut composit = self.composits.as_slice()[shape.index as usize];
+                    composit.set_override_material(true);
+                    self.composits.set(shape.index as usize, composit);
+                }
+                ci
+            },
+            CompositDescriptor::MATERIAL_BLEND(child, width) => {
+                let ci = self.generate_composite(child, root);
+                let shape = self.shapes.as_slice()[ci as usize];
+                if shape.shape_type == 9 {
+                    let mut composit = self.composits.as_slice()[shape.index as usize];
+                    composit.set_seam(*width);
+                    self.composits.set(shape.index as usize, composit);
+                }
+                ci
             }
-            r => match r {
-                CompositDescriptor::DIFFERENCE(a, b)
-                | CompositDescriptor::INTERSECTION(a, b)
-                | CompositDescriptor::UNION(a, b) => {
-                    let ai = self.generate_composite(a.borrow(), false);
-                    let bi = self.generate_composite(b.borrow(), false);
-                    let u = Composit::new(ai, bi, match r {
-                        CompositDescriptor::UNION(_, _) => {0}
-                        CompositDescriptor::INTERSECTION(_, _) => {1}
-                        CompositDescriptor::DIFFERENCE(_, _) => {2}
-                        _ => panic!()
-                    },1.0);
-                    dbg!(ai);
-                    dbg!(bi);
-                    let cindex = self.composits.len() as u32;
-                    self.composits.push(u);
-                    let index = self.shapes.len() as u32;
-                    self.shapes.push(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32));
-                    index
-                },
-                _ => {panic!()}
+        }
+    }
+
+    /// Composite of two children combined by `comp_type`, the deepest one evaluated first
+    fn push_binary_node(&mut self, a:&CompositDescriptor, b:&CompositDescriptor, comp_type:u32, alpha:f32, root:bool)->u32{
+        let ai = self.generate_composite(a, false);
+        let bi = self.generate_composite(b, false);
+        let mut composit = Composit::new(ai, bi, comp_type, alpha);
+        composit.set_a_first(deepest_first(&[a.stack_usage(), b.stack_usage()])[0] == 0);
+        let cindex = self.composits.push(composit) as u32;
+        self.push_shape(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32))
+    }
+
+    /// Union or intersection of every operand of `desc`, nested nodes of the same type included.
+    /// More than two operands make a single n-ary node, its children are added to the child lists
+    /// in the order they are evaluated.
+    fn push_nary_node(&mut self, desc:&CompositDescriptor, intersection:bool, root:bool)->u32{
+        let operands = desc.operands(intersection);
+        match operands[..] {
+            [] => unreachable!("Empty groups are refused by create_composite"),
+            [only] => return self.generate_composite(only, root),
+            _ => {}
+        }
+        let usages: Vec<StackUsage> = operands.iter().map(|operand| operand.stack_usage()).collect();
+        let order = deepest_first(&usages);
+        let comp_type = intersection as u32;
+        let composit = if let [a, b] = operands[..] {
+            let mut composit = Composit::new(self.generate_composite(a, false), self.generate_composite(b, false), comp_type, 1.0);
+            composit.set_a_first(order[0] == 0);
+            composit
+        } else {
+                let children: Vec<u32> = order.iter().map(|k| self.generate_composite(operands[*k], false)).collect();
+            let start = self.child_lists.len() as u32;
+            self.child_lists.extend(&children);
+            Composit::nary(start, children.len() as u32, 17 + comp_type)
+        };
+        let cindex = self.composits.push(composit) as u32;
+        self.push_shape(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32))
+    }
+
+    /// Composite with a single child evaluated at a remapped point
+    fn push_domain_node(&mut self, child:&CompositDescriptor, comp_type:u32, domain:[f32;4], limit:[f32;4], root:bool)->u32{
+        let ci = self.generate_composite(child, false);
+        let cindex = self.composits.push(Composit::domain(ci, comp_type, domain, limit)) as u32;
+        self.push_shape(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32))
+    }
+
+    fn push_deformation_node(&mut self, child:&CompositDescriptor, comp_type:u32, params:[f32;4], lipschitz:f32, root:bool)->u32{
+        let ci = self.generate_composite(child, false);
+        let cindex = self.composits.push(Composit::deformation(ci, comp_type, params, lipschitz)) as u32;
+        self.push_shape(Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32))
+    }
+
+    /// Composite with a single child, moved by the transform of the composite shape
+    fn push_transform_node(&mut self, child:&CompositDescriptor, transform:Transform, root:bool)->u32{
+        let ci = self.generate_composite(child, false);
+        let cindex = self.composits.push(Composit::new(ci, ci, 4, 0.0)) as u32;
+        let mut shape = Shape::new(Color(0.0, 0.0, 1.0), 9, cindex, 0.0, root as u32);
+        shape.transform = transform;
+        self.push_shape(shape)
+    }
+
+    pub fn update_sphere(&mut self, handle:ShapeHandle, sphere:Sphere)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, 0)?;
+        self.spheres.set(index, sphere);
+        Ok(())
+    }
+
+    pub fn update_cube(&mut self, handle:ShapeHandle, cuboid:Cuboid)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, 1)?;
+        self.cuboids.set(index, cuboid);
+        Ok(())
+    }
+
+    pub fn update_torus(&mut self, handle:ShapeHandle, torus:Torus)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, 2)?;
+        self.tori.set(index, torus);
+        Ok(())
+    }
+
+    pub fn update_primitive<T:Primitive>(&mut self, handle:ShapeHandle, primitive:&T)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, T::SHAPE_TYPE)?;
+        self.primitives.set(index, primitive.record());
+        Ok(())
+    }
+
+    /// Replace the color, reflectivity and visibility of a shape.
+    /// The children of a composite are hidden, making one visible draws it on its own as well.
+    pub fn set_properties(&mut self, handle:ShapeHandle, props:ShapeProperties)->Result<(), ShapeError>{
+        let (index, shape) = self.shape(handle)?;
+        let mut updated = Shape::from_prop(props, shape.shape_type, shape.index);
+        updated.transform = shape.transform;
+        self.shapes.set(index as usize, updated);
+        if props.visible {
+            self.ensure_program(index);
+        }
+        Ok(())
+    }
+
+    /// Draw or hide a shape on its own, a composite inside a tree gets its program the first time it is drawn
+    pub fn set_visible(&mut self, handle:ShapeHandle, visible:bool)->Result<(), ShapeError>{
+        let (index, mut shape) = self.shape(handle)?;
+        shape.visible = visible as u32;
+        self.shapes.set(index as usize, shape);
+        if visible {
+            self.ensure_program(index);
+        }
+        Ok(())
+    }
+
+    /// Move, rotate and scale a shape, the transform of a composite applies to its whole tree
+    pub fn set_transform(&mut self, handle:ShapeHandle, transform:Transform)->Result<(), ShapeError>{
+        let (index, mut shape) = self.shape(handle)?;
+        shape.transform = transform;
+        self.shapes.set(index as usize, shape);
+        Ok(())
+    }
+
+    pub fn transform(&self, handle:ShapeHandle)->Result<Transform, ShapeError>{
+        Ok(self.shape(handle)?.1.transform)
+    }
+
+    /// Mix the materials of the children of a composite over `width` on each side of their seam,
+    /// 0 switches back to the material of the closest child
+    pub fn set_material_blend(&mut self, handle:ShapeHandle, width:f32)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, 9)?;
+        let mut composit = self.composits.as_slice()[index];
+        composit.set_seam(width);
+        self.composits.set(index, composit);
+        Ok(())
+    }
+
+    /// Paint the whole tree of a composite with the color and reflectivity of the composite,
+    /// see [`ShapeCollection::set_properties`]
+    pub fn set_material_override(&mut self, handle:ShapeHandle, override_material:bool)->Result<(), ShapeError>{
+        let index = self.typed_index(handle, 9)?;
+        let mut composit = self.composits.as_slice()[index];
+        composit.set_override_material(override_material);
+        self.composits.set(index, composit);
+        Ok(())
+    }
+
+    /// Remove a shape, along with its children when it is a composite.
+    /// The last shapes of the buffers are moved in the freed slots, so the removal doesn't leave holes.
+    pub fn remove(&mut self, handle:ShapeHandle)->Result<(), ShapeError>{
+        let index = self.index_of(handle)?;
+        if self.composits.as_slice().iter().any(|composit| composit.children(&self.child_lists).contains(&index)) {
+            return Err(ShapeError::InUse(handle));
+        }
+        let mut subtree = vec![];
+        self.collect_subtree(index, &mut subtree);
+        for handle in subtree {
+            let index = self.index_of(handle)?;
+            self.remove_at(index);
+        }
+        Ok(())
+    }
+
+    fn collect_subtree(&self, index:u32, handles:&mut Vec<ShapeHandle>){
+        handles.push(self.handle_at(index));
+        let shape = self.shapes.as_slice()[index as usize];
+        if Storage::of(shape.shape_type) == Storage::Composite {
+            for child in self.composits.as_slice()[shape.index as usize].children(&self.child_lists) {
+                self.collect_subtree(child, handles);
             }
         }
     }
 
-    fn create_buffers(device: &Device) -> (Buffer, Buffer, Buffer, Buffer, Buffer) {
-        let count_uniform = device.create_buffer_init(&BufferInitDescriptor{
-            label: Some("CountUniform"),
-            contents: bytemuck::cast_slice(&[ShapeCount(0)]),
-            usage: BufferUsages::UNIFORM|BufferUsages::COPY_DST
-        });
-        let shapes_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("ShapeBuffer"),
-            size:std::mem::size_of::<Shape>() as u64 * SHAPE_CAPACITY,
-            usage: BufferUsages::STORAGE|BufferUsages::COPY_DST,
-            mapped_at_creation: false
-        });
-        let spheres_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("SphereBuffer"),
-            size:std::mem::size_of::<Sphere>() as u64 * SHAPE_CAPACITY,
-            usage: BufferUsages::STORAGE|BufferUsages::COPY_DST,
-            mapped_at_creation: false
-        });
-        let cuboids_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("CuboidBuffer"),
-            size:std::mem::size_of::<Cuboid>() as u64 * SHAPE_CAPACITY,
-            usage: BufferUsages::STORAGE|BufferUsages::COPY_DST,
-            mapped_at_creation: false
-        });
-        let composit_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("CuboidBuffer"),
-            size:std::mem::size_of::<Composit>() as u64 * SHAPE_CAPACITY,
-            usage: BufferUsages::STORAGE|BufferUsages::COPY_DST,
-            mapped_at_creation: false
-        });
-        (count_uniform, shapes_buffer,spheres_buffer, cuboids_buffer,composit_buffer)
+    fn remove_at(&mut self, index:u32){
+        let shape = self.shapes.as_slice()[index as usize];
+        let storage = Storage::of(shape.shape_type);
+        let slot = shape.index as usize;
+        if storage == Storage::Composite {
+            let composit = self.composits.as_slice()[slot];
+            if let Some(range) = composit.child_range() {
+                self.remove_child_list(range);
+            }
+            if let Some(start) = composit.program() {
+                self.remove_program(start as usize);
+            }
+        }
+        let moved = match storage {
+            Storage::Sphere => self.spheres.swap_remove(slot),
+            Storage::Cuboid => self.cuboids.swap_remove(slot),
+            Storage::Torus => self.tori.swap_remove(slot),
+            Storage::Composite => self.composits.swap_remove(slot),
+            Storage::Primitive => self.primitives.swap_remove(slot)
+        };
+        // The shape owning the moved element has to point to its new slot
+        if let Some(from) = moved {
+            let owner = self.shapes.as_slice().iter()
+                .position(|other| Storage::of(other.shape_type) == storage && other.index == from as u32);
+            if let Some(owner) = owner {
+                let mut other = self.shapes.as_slice()[owner];
+                other.index = shape.index;
+                self.shapes.set(owner, other);
+            }
+        }
+
+        let id = self.shape_handles.swap_remove(index as usize);
+        let slot = &mut self.handles[id as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.index = None;
+        self.free_handles.push(id);
+
+        // Same for the shape moved in the shape buffer and the composite pointing to it
+        if let Some(from) = self.shapes.swap_remove(index as usize) {
+            self.handles[self.shape_handles[index as usize] as usize].index = Some(index);
+            self.replace_child(from as u32, index);
+            self.replace_in_programs(from as u32, index);
+        }
+        self.dirty = true;
+    }
+
+    /// Remove the program starting at `start`, the programs after it move back
+    fn remove_program(&mut self, start:usize){
+        let len = self.programs.as_slice()[start..].iter().position(|instruction| instruction.op == OP_END).unwrap() + 1;
+        self.programs.remove_range(start..start + len);
+        for slot in 0..self.composits.len() {
+            let mut composit = self.composits.as_slice()[slot];
+            if let Some(program) = composit.program().filter(|program| *program as usize > start) {
+                composit.set_program(program - len as u32);
+                self.composits.set(slot, composit);
+            }
+        }
     }
 
-    pub fn update_buffers(&mut self, queue:&Queue){
+    /// Point the instructions using the shape at index `old` to `new`
+    fn replace_in_programs(&mut self, old:u32, new:u32){
+        for position in 0..self.programs.len() {
+            let mut instruction = self.programs.as_slice()[position];
+            if instruction.op != OP_END && instruction.shape == old {
+                instruction.shape = new;
+                self.programs.set(position, instruction);
+            }
+        }
+    }
+
+    /// Point the composite with the shape at index `old` as a child to `new`
+    fn replace_child(&mut self, old:u32, new:u32){
+        for slot in 0..self.composits.len() {
+            let mut composit = self.composits.as_slice()[slot];
+            if let Some(range) = composit.child_range() {
+                let position = self.child_lists[range.clone()].iter().position(|child| *child == old);
+                if let Some(position) = position {
+                    self.child_lists[range.start + position] = new;
+                    return;
+                }
+            } else if composit.replace_child(old, new) {
+                self.composits.set(slot, composit);
+                return;
+            }
+        }
+    }
+
+    /// Remove the children of an n-ary composite from the child lists, the lists after it move back
+    fn remove_child_list(&mut self, range:Range<usize>){
+        self.child_lists.drain(range.clone());
+        for slot in 0..self.composits.len() {
+            let mut composit = self.composits.as_slice()[slot];
+            if let Some(other) = composit.child_range() {
+                if other.start > range.start {
+                    composit.set_child_start((other.start - range.len()) as u32);
+                    self.composits.set(slot, composit);
+                }
+            }
+        }
+    }
+
+    /// Upload what changed since the last update, growing the buffers that are too small.
+    /// Each buffer only gets its dirty ranges written, unless it had to be reallocated.
+    pub fn update_buffers(&mut self, device:&Device, queue:&Queue){
+        // Every buffer has to be uploaded, a short-circuiting || would skip some of them
+        let reallocated = [
+            self.shapes.upload(device, queue),
+            self.spheres.upload(device, queue),
+            self.cuboids.upload(device, queue),
+            self.tori.upload(device, queue),
+            self.primitives.upload(device, queue),
+            self.composits.upload(device, queue),
+            self.programs.upload(device, queue),
+        ];
+        let buffers = [self.shapes.buffer(), self.spheres.buffer(), self.cuboids.buffer(), self.composits.buffer(), self.tori.buffer(), self.primitives.buffer(), self.programs.buffer()];
+        let bindings = match self.bindings.take() {
+            Some(mut bindings) => {
+                if reallocated.contains(&true) {
+                    bindings.bind_group = Self::create_bind_group(device, &bindings.bind_group_layout, &bindings.count_uniform, buffers);
+                }
+                bindings
+            }
+            None => Self::create_bindings(device, self.shapes.len() as u32, buffers)
+        };
         if self.dirty {
-            queue.write_buffer(&self.count_uniform, 0 , bytemuck::bytes_of(&ShapeCount(self.shapes.len() as u32)));
-            queue.write_buffer(&self.shapes_buffer, 0 , self.shapes_bytes().as_slice());
-            queue.write_buffer(&self.spheres_buffer, 0 , self.sphere_bytes().as_slice());
-            queue.write_buffer(&self.cuboids_buffer, 0 , self.cuboids_bytes().as_slice());
-            queue.write_buffer(&self.composits_buffer, 0 , self.composit_bytes().as_slice());
+            queue.write_buffer(&bindings.count_uniform, 0 , bytemuck::bytes_of(&ShapeCount(self.shapes.len() as u32)));
             self.dirty = false;
         }
+        self.bindings = Some(bindings);
+    }
+
+    /// Write every buffer entirely on the next update
+    pub fn mark_all_dirty(&mut self){
+        self.shapes.mark_all();
+        self.spheres.mark_all();
+        self.cuboids.mark_all();
+        self.tori.mark_all();
+        self.primitives.mark_all();
+        self.composits.mark_all();
+        self.programs.mark_all();
+        self.dirty = true;
+    }
+
+    /// Writes the next [`ShapeCollection::update_buffers`] will make, the shape count excluded
+    pub fn pending_upload(&self) -> PendingUpload {
+        [
+            self.shapes.pending(),
+            self.spheres.pending(),
+            self.cuboids.pending(),
+            self.tori.pending(),
+            self.primitives.pending(),
+            self.composits.pending(),
+            self.programs.pending(),
+        ].iter().fold(PendingUpload::default(), |total, pending| PendingUpload{
+            writes: total.writes + pending.writes,
+            bytes: total.bytes + pending.bytes
+        })
+    }
+
+    /// Number of shapes, composites included
+    pub fn len(&self) -> usize {
+        self.shapes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shapes.is_empty()
+    }
+
+    /// Usage of every GPU buffer, the capacities are only updated by [`ShapeCollection::update_buffers`]
+    pub fn stats(&self) -> Vec<BufferStats> {
+        vec![
+            self.shapes.stats(),
+            self.spheres.stats(),
+            self.cuboids.stats(),
+            self.tori.stats(),
+            self.primitives.stats(),
+            self.composits.stats(),
+            self.programs.stats(),
+        ]
     }
 
     pub fn shapes_bytes(&self) -> Vec<u8>{
-        self.shapes.iter().flat_map(|x|bytemuck::bytes_of(x)).map(|x|*x).collect::<Vec<_>>()
+        bytemuck::cast_slice(self.shapes.as_slice()).to_vec()
     }
 
     pub fn sphere_bytes(&self) -> Vec<u8>{
-        self.spheres.iter().flat_map(|x|bytemuck::bytes_of(x)).map(|x|*x).collect::<Vec<_>>()
+        bytemuck::cast_slice(self.spheres.as_slice()).to_vec()
     }
 
     pub fn cuboids_bytes(&self) -> Vec<u8>{
-        self.cuboids.iter().flat_map(|x|bytemuck::bytes_of(x)).map(|x|*x).collect::<Vec<_>>()
+        bytemuck::cast_slice(self.cuboids.as_slice()).to_vec()
+    }
+
+    pub fn tori_bytes(&self) -> Vec<u8>{
+        bytemuck::cast_slice(self.tori.as_slice()).to_vec()
+    }
+
+    pub fn primitives_bytes(&self) -> Vec<u8>{
+        bytemuck::cast_slice(self.primitives.as_slice()).to_vec()
     }
 
     pub fn composit_bytes(&self) -> Vec<u8>{
-        self.composits.iter().flat_map(|x|bytemuck::bytes_of(x)).map(|x|*x).collect::<Vec<_>>()
+        bytemuck::cast_slice(self.composits.as_slice()).to_vec()
+    }
+
+    pub fn programs_bytes(&self) -> Vec<u8>{
+        bytemuck::cast_slice(self.programs.as_slice()).to_vec()
     }
 
+    /// Panics if the collection was created without a device and never uploaded
     pub fn bind_group(&self) -> &BindGroup{
-        &self.bind_group
+        &self.bindings.as_ref().expect("The shapes must be uploaded before they are bound").bind_group
     }
 
     pub fn bind_group_layout(device:&Device) -> wgpu::BindGroupLayout{
@@ -287,8 +894,236 @@ impl ShapeCollection {
                     },
                     count: None
                 },
+                BindGroupLayoutEntry{
+                    binding: 5,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size:BufferSize::new(std::mem::size_of::<Torus>() as u64)
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry{
+                    binding: 6,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size:BufferSize::new(std::mem::size_of::<PrimitiveRecord>() as u64)
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry{
+                    binding: 7,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size:BufferSize::new(std::mem::size_of::<Instruction>() as u64)
+                    },
+                    count: None
+                },
             ]
         };
         device.create_bind_group_layout(&bind_group_layout)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::sphere::Sphere;
+
+    fn sphere(x: f32) -> CompositDescriptor {
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: false};
+        CompositDescriptor::SPHERE(Sphere::new([x, 0.0, 0.0], 1.0), props)
+    }
+
+    fn group(shapes: Vec<CompositDescriptor>) -> CompositDescriptor {
+        CompositDescriptor::UNION_ALL(shapes)
+    }
+
+    /// Tree of the shape at `index`, leaves named by the x coordinate of their sphere
+    fn describe(collection: &ShapeCollection, index: u32) -> String {
+        let shape = collection.shapes.as_slice()[index as usize];
+        if shape.shape_type == 0 {
+            let [x, _, _, _]: [f32; 4] = bytemuck::cast(collection.spheres.as_slice()[shape.index as usize]);
+            return format!("{}", x);
+        }
+        let composit = collection.composits.as_slice()[shape.index as usize];
+        let children: Vec<String> = composit.children(&collection.child_lists).into_iter()
+            .map(|child| describe(collection, child))
+            .collect();
+        format!("{}({})", composit.comp_type(), children.join(","))
+    }
+
+    /// Check the handles, child indices, child lists and programs all agree with the shape buffer
+    fn check_consistent(collection: &ShapeCollection) {
+        assert_eq!(collection.shape_handles.len(), collection.len());
+        for index in 0..collection.len() as u32 {
+            assert_eq!(collection.index_of(collection.handle_at(index)).unwrap(), index);
+        }
+        let mut program_len = 0;
+        let mut child_list_len = 0;
+        for index in 0..collection.len() as u32 {
+            let shape = collection.shapes.as_slice()[index as usize];
+            if shape.shape_type != 9 {
+                continue;
+            }
+            let composit = collection.composits.as_slice()[shape.index as usize];
+            child_list_len += composit.child_range().map_or(0, |range| range.len());
+            let start = match composit.program() {
+                Some(start) => start as usize,
+                None => {
+                    assert_eq!(shape.visible, 0, "visible composite without a program");
+                    continue;
+                }
+            };
+            let program = compile(collection.shapes.as_slice(), collection.composits.as_slice(), &collection.child_lists, index);
+            assert_eq!(&collection.programs.as_slice()[start..start + program.len()], &program[..]);
+            program_len += program.len();
+        }
+        assert_eq!(collection.programs.len(), program_len);
+        assert_eq!(collection.child_lists.len(), child_list_len);
+    }
+
+    /// Build a few leaves and composites, the nested composites and n-ary nodes sharing the buffers
+    fn populate(collection: &mut ShapeCollection) -> Vec<ShapeHandle> {
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: true};
+        let nested = CompositDescriptor::TRANSLATE(
+            Box::new(CompositDescriptor::DIFFERENCE(Box::new(sphere(12.0)), Box::new(sphere(13.0)))), [1.0, 0.0, 0.0]);
+        vec![
+            collection.add_sphere(Sphere::new([1.0, 0.0, 0.0], 1.0), props),
+            collection.add_sphere(Sphere::new([2.0, 0.0, 0.0], 1.0), props),
+            collection.create_composite(&group(vec![sphere(10.0), sphere(11.0), nested, sphere(14.0)])).unwrap(),
+            collection.add_sphere(Sphere::new([3.0, 0.0, 0.0], 1.0), props),
+            collection.create_composite(&CompositDescriptor::DIFFERENCE(
+                Box::new(sphere(20.0)), Box::new(CompositDescriptor::ROTATE(Box::new(sphere(21.0)), [0.0, 1.0, 0.0])))).unwrap(),
+            collection.create_composite(&group(vec![sphere(30.0), sphere(31.0), sphere(32.0)])).unwrap(),
+        ]
+    }
+
+    fn describe_all(collection: &ShapeCollection, handles: &[ShapeHandle]) -> Vec<String> {
+        handles.iter().map(|handle| describe(collection, collection.index_of(*handle).unwrap())).collect()
+    }
+
+    #[test]
+    fn removal_keeps_the_other_shapes() {
+        let mut collection = ShapeCollection::without_device();
+        let mut handles = populate(&mut collection);
+        check_consistent(&collection);
+        let mut expected = describe_all(&collection, &handles);
+        // The deepest child is evaluated first
+        assert_eq!(expected[2], "17(4(2(12,13)),10,11,14)");
+
+        // A leaf in the middle of the buffers, then a composite with nested nodes and a child list
+        for position in [1, 1] {
+            let removed = handles.remove(position);
+            expected.remove(position);
+            collection.remove(removed).unwrap();
+            assert!(!collection.contains(removed));
+            check_consistent(&collection);
+            assert_eq!(describe_all(&collection, &handles), expected);
+        }
+        assert_eq!(collection.len(), 1 + 1 + 4 + 4);
+    }
+
+    /// Number of composites with a program
+    fn compiled(collection: &ShapeCollection) -> usize {
+        collection.composits.as_slice().iter().filter(|composit| composit.program().is_some()).count()
+    }
+
+    #[test]
+    fn inner_composites_are_compiled_when_shown() {
+        let mut collection = ShapeCollection::without_device();
+        let handles = populate(&mut collection);
+        assert_eq!(compiled(&collection), 3);
+        let root = collection.shape(handles[2]).unwrap().1;
+        let nested = collection.composits.as_slice()[root.index as usize].children(&collection.child_lists).into_iter()
+            .find(|child| collection.shapes.as_slice()[*child as usize].shape_type == 9)
+            .unwrap();
+        let nested = collection.handle_at(nested);
+        collection.set_visible(nested, true).unwrap();
+        assert_eq!(compiled(&collection), 4);
+        check_consistent(&collection);
+        // Hiding it again keeps the program, removing the tree removes both
+        collection.set_visible(nested, false).unwrap();
+        collection.set_visible(nested, true).unwrap();
+        assert_eq!(compiled(&collection), 4);
+        collection.remove(handles[2]).unwrap();
+        assert_eq!(compiled(&collection), 2);
+        check_consistent(&collection);
+        collection.remove(handles[4]).unwrap();
+        check_consistent(&collection);
+    }
+
+    #[test]
+    fn stale_handles_are_rejected() {
+        let mut collection = ShapeCollection::without_device();
+        let handles = populate(&mut collection);
+        collection.remove(handles[1]).unwrap();
+        // The id of the removed shape is given again, with another generation
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: true};
+        let reused = collection.add_sphere(Sphere::new([4.0, 0.0, 0.0], 1.0), props);
+        assert_eq!(reused.id, handles[1].id);
+        assert_ne!(reused.generation, handles[1].generation);
+        assert!(matches!(collection.remove(handles[1]), Err(ShapeError::InvalidHandle(_))));
+        assert!(matches!(collection.set_visible(handles[1], false), Err(ShapeError::InvalidHandle(_))));
+        assert!(collection.contains(reused));
+        check_consistent(&collection);
+    }
+
+    #[test]
+    fn shapes_in_use_are_not_removed() {
+        let mut collection = ShapeCollection::without_device();
+        let handles = populate(&mut collection);
+        let len = collection.len();
+        let composit = collection.composits.as_slice()[collection.shape(handles[4]).unwrap().1.index as usize];
+        for child in composit.children(&collection.child_lists) {
+            let handle = collection.handle_at(child);
+            assert!(matches!(collection.remove(handle), Err(ShapeError::InUse(_))));
+        }
+        assert_eq!(collection.len(), len);
+        check_consistent(&collection);
+    }
+
+    #[test]
+    fn empty_groups_are_refused() {
+        let mut collection = ShapeCollection::without_device();
+        let nested = CompositDescriptor::DIFFERENCE(Box::new(sphere(0.0)), Box::new(CompositDescriptor::INTERSECTION_ALL(vec![])));
+        for desc in [CompositDescriptor::UNION_ALL(vec![]), nested] {
+            assert!(matches!(collection.create_composite(&desc), Err(ShapeError::EmptyGroup)));
+        }
+        assert_eq!(collection.len(), 0);
+        assert!(collection.create_composite(&CompositDescriptor::UNION_ALL(vec![sphere(0.0)])).is_ok());
+    }
+
+    #[test]
+    fn mirrors_need_a_normal() {
+        let mut collection = ShapeCollection::without_device();
+        let mirror = |normal| CompositDescriptor::MIRROR(Box::new(sphere(1.0)), normal, 0.0);
+        for normal in [[0.0;3], [f32::NAN, 0.0, 0.0]] {
+            assert!(matches!(collection.create_composite(&mirror(normal)), Err(ShapeError::ZeroNormal(_))));
+        }
+        assert_eq!(collection.len(), 0);
+        assert!(collection.create_composite(&mirror([0.0, 0.0, 2.0])).is_ok());
+    }
+
+    #[test]
+    fn deep_trees_are_refused() {
+        let mut collection = ShapeCollection::without_device();
+        let handles = populate(&mut collection);
+        let len = collection.len();
+        // Every transform keeps the frame of its parent on the stack
+        let mut desc = sphere(1.0);
+        for _ in 0..STACK_DEPTH {
+            desc = CompositDescriptor::TRANSLATE(Box::new(desc), [1.0, 0.0, 0.0]);
+        }
+        assert!(matches!(collection.create_composite(&desc), Err(ShapeError::TooDeep(depth, STACK_DEPTH)) if depth > STACK_DEPTH));
+        // Nothing of the tree is left behind
+        assert_eq!(collection.len(), len);
+        check_consistent(&collection);
+        assert!(handles.iter().all(|handle| collection.contains(*handle)));
+    }
+}
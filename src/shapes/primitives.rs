@@ -0,0 +1,390 @@
+//! Primitives sharing the [`PrimitiveRecord`] GPU layout.
+//!
+//! A primitive is described by the [`Primitive`] trait : its shape type, its WGSL distance function
+//! and a CPU reference of that distance. New primitives are made available to the shader by
+//! registering them in a [`PrimitiveRegistry`](crate::shapes::registry::PrimitiveRegistry),
+//! as long as they fit a [`PrimitiveRecord`].
+
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix, Vector2, Vector3};
+use crate::shapes::{rotation_matrix, shader_matrix};
+use crate::shapes::composit::wgsl_clamp;
+use crate::shapes::specialize::{wgsl_mat3, wgsl_vec3, wgsl_vec4};
+
+/// GPU record shared by the primitives, `Primitive` in the shader.
+/// Every primitive is centered on `position` and oriented by `rotation`, its main axis being y,
+/// and has four parameters whose meaning depends on its type.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct PrimitiveRecord{
+    position: [f32;3],
+    _pad1:[f32;1],
+    rotation:[[f32;4];3],
+    params: [f32;4]
+}
+
+impl PrimitiveRecord {
+    pub fn new(position: [f32;3], euler: [f32;3], params: [f32;4]) -> Self {
+        Self{ position, _pad1:[0.0], rotation: rotation_matrix(euler), params }
+    }
+
+    pub fn params(&self) -> [f32;4] {
+        self.params
+    }
+
+    /// `point` in the frame of the primitive, same as `(a-b.pos)*b.rotation` in the shader
+    pub fn local_point(&self, point: [f32;3]) -> Vector3<f32> {
+        shader_matrix(self.rotation).transpose() * (Vector3::from(point) - Vector3::from(self.position))
+    }
+
+    /// `Primitive` constructor with the values of the record
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Primitive({}, {}, {})", wgsl_vec3(self.position), wgsl_mat3(self.rotation), wgsl_vec4(self.params))
+    }
+}
+
+/// Shape that can be stored in the primitives buffer.
+///
+/// The buffers and bindings of the shader are fixed, registering a primitive only adds it to the
+/// `primitive_distance` switch. Its data must therefore fit a [`PrimitiveRecord`] : a position, a rotation
+/// and four parameters. A shape needing more has to be added to the shader like the sphere, cuboid and torus.
+pub trait Primitive {
+    /// Value of `shape_type` in the shape buffer, must not be one of
+    /// [`RESERVED_SHAPE_TYPES`](crate::shapes::registry::RESERVED_SHAPE_TYPES)
+    const SHAPE_TYPE: u32;
+    /// Name of the WGSL function computing the distance, `fn name(a: vec3<f32>, b: Primitive)->f32`
+    const DISTANCE_FUNCTION: &'static str;
+    /// WGSL source defining [`Primitive::DISTANCE_FUNCTION`]
+    const WGSL: &'static str;
+
+    /// GPU layout of the primitive
+    fn record(&self) -> PrimitiveRecord;
+
+    /// CPU reference of the WGSL distance, `point` being in the frame of the primitive
+    fn local_distance(params: [f32;4], point: Vector3<f32>) -> f32;
+
+    fn distance(&self, point: [f32;3]) -> f32 {
+        let record = self.record();
+        Self::local_distance(record.params(), record.local_point(point))
+    }
+}
+
+fn max2(a: Vector2<f32>, b: f32) -> Vector2<f32> {
+    Vector2::new(a.x.max(b), a.y.max(b))
+}
+
+fn max3(a: Vector3<f32>, b: f32) -> Vector3<f32> {
+    Vector3::new(a.x.max(b), a.y.max(b), a.z.max(b))
+}
+
+fn abs3(a: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(a.x.abs(), a.y.abs(), a.z.abs())
+}
+
+/// `sign` of WGSL, 0 at 0 where `f32::signum` gives 1
+fn wgsl_sign(x: f32) -> f32 {
+    if x == 0.0 { 0.0 } else { x.signum() }
+}
+
+/// Infinite plane going through `position`, its normal is y before rotation
+#[derive(Copy, Clone, Debug)]
+pub struct Plane{
+    pub position: [f32;3],
+    pub rotation: [f32;3]
+}
+
+impl Plane {
+    pub fn new(position: [f32;3], rotation: [f32;3]) -> Self {
+        Self{ position, rotation }
+    }
+}
+
+impl Primitive for Plane {
+    const SHAPE_TYPE: u32 = 3;
+    const DISTANCE_FUNCTION: &'static str = "plane_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/plane.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        PrimitiveRecord::new(self.position, self.rotation, [0.0;4])
+    }
+
+    fn local_distance(_params: [f32;4], p: Vector3<f32>) -> f32 {
+        p.y
+    }
+}
+
+/// Segment of length `height` along y, with rounded ends of `radius`
+#[derive(Copy, Clone, Debug)]
+pub struct Capsule{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub height: f32,
+    pub radius: f32
+}
+
+impl Capsule {
+    pub fn new(position: [f32;3], rotation: [f32;3], height: f32, radius: f32) -> Self {
+        Self{ position, rotation, height, radius }
+    }
+}
+
+impl Primitive for Capsule {
+    const SHAPE_TYPE: u32 = 4;
+    const DISTANCE_FUNCTION: &'static str = "capsule_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/capsule.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        PrimitiveRecord::new(self.position, self.rotation, [self.height / 2.0, self.radius, 0.0, 0.0])
+    }
+
+    fn local_distance(params: [f32;4], mut p: Vector3<f32>) -> f32 {
+        let [half_height, radius, _, _] = params;
+        // The sizes come from the scenes, a negative height must not panic like `f32::clamp`
+        p.y -= wgsl_clamp(p.y, -half_height, half_height);
+        p.magnitude() - radius
+    }
+}
+
+/// Cylinder along y with flat caps
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub height: f32,
+    pub radius: f32
+}
+
+impl Cylinder {
+    pub fn new(position: [f32;3], rotation: [f32;3], height: f32, radius: f32) -> Self {
+        Self{ position, rotation, height, radius }
+    }
+}
+
+impl Primitive for Cylinder {
+    const SHAPE_TYPE: u32 = 5;
+    const DISTANCE_FUNCTION: &'static str = "cylinder_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/cylinder.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        PrimitiveRecord::new(self.position, self.rotation, [self.height / 2.0, self.radius, 0.0, 0.0])
+    }
+
+    fn local_distance(params: [f32;4], p: Vector3<f32>) -> f32 {
+        let [half_height, radius, _, _] = params;
+        let d = Vector2::new(Vector2::new(p.x, p.z).magnitude() - radius, p.y.abs() - half_height);
+        d.x.max(d.y).min(0.0) + max2(d, 0.0).magnitude()
+    }
+}
+
+/// Cone along y, cut at `bottom_radius` and `top_radius`, a top radius of 0 gives a pointy cone
+#[derive(Copy, Clone, Debug)]
+pub struct Cone{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub height: f32,
+    pub bottom_radius: f32,
+    pub top_radius: f32
+}
+
+impl Cone {
+    pub fn new(position: [f32;3], rotation: [f32;3], height: f32, bottom_radius: f32, top_radius: f32) -> Self {
+        Self{ position, rotation, height, bottom_radius, top_radius }
+    }
+}
+
+impl Primitive for Cone {
+    const SHAPE_TYPE: u32 = 6;
+    const DISTANCE_FUNCTION: &'static str = "cone_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/cone.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        PrimitiveRecord::new(self.position, self.rotation, [self.height / 2.0, self.bottom_radius, self.top_radius, 0.0])
+    }
+
+    fn local_distance(params: [f32;4], p: Vector3<f32>) -> f32 {
+        let [h, r1, r2, _] = params;
+        let q = Vector2::new(Vector2::new(p.x, p.z).magnitude(), p.y);
+        let k1 = Vector2::new(r2, h);
+        let k2 = Vector2::new(r2 - r1, 2.0 * h);
+        let cap_radius = if q.y < 0.0 { r1 } else { r2 };
+        let ca = Vector2::new(q.x - q.x.min(cap_radius), q.y.abs() - h);
+        let cb = q - k1 + k2 * ((k1 - q).dot(k2) / k2.magnitude2()).clamp(0.0, 1.0);
+        let inside = if cb.x < 0.0 && ca.y < 0.0 { -1.0 } else { 1.0 };
+        inside * ca.magnitude2().min(cb.magnitude2()).sqrt()
+    }
+}
+
+/// Ellipsoid with its three radii, the distance is a bound rather than exact
+#[derive(Copy, Clone, Debug)]
+pub struct Ellipsoid{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub radii: [f32;3]
+}
+
+impl Ellipsoid {
+    pub fn new(position: [f32;3], rotation: [f32;3], radii: [f32;3]) -> Self {
+        Self{ position, rotation, radii }
+    }
+}
+
+impl Primitive for Ellipsoid {
+    const SHAPE_TYPE: u32 = 7;
+    const DISTANCE_FUNCTION: &'static str = "ellipsoid_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/ellipsoid.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        let [x, y, z] = self.radii;
+        PrimitiveRecord::new(self.position, self.rotation, [x, y, z, 0.0])
+    }
+
+    fn local_distance(params: [f32;4], p: Vector3<f32>) -> f32 {
+        let [x, y, z, _] = params;
+        let k0 = Vector3::new(p.x / x, p.y / y, p.z / z).magnitude();
+        let k1 = Vector3::new(p.x / (x * x), p.y / (y * y), p.z / (z * z)).magnitude();
+        k0 * (k0 - 1.0) / k1
+    }
+}
+
+/// Box of `size` with its edges rounded by `radius`
+#[derive(Copy, Clone, Debug)]
+pub struct RoundedBox{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub size: [f32;3],
+    pub radius: f32
+}
+
+impl RoundedBox {
+    pub fn new(position: [f32;3], rotation: [f32;3], size: [f32;3], radius: f32) -> Self {
+        Self{ position, rotation, size, radius }
+    }
+}
+
+impl Primitive for RoundedBox {
+    const SHAPE_TYPE: u32 = 8;
+    const DISTANCE_FUNCTION: &'static str = "rounded_box_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/rounded_box.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        let [x, y, z] = self.size;
+        PrimitiveRecord::new(self.position, self.rotation, [x / 2.0, y / 2.0, z / 2.0, self.radius])
+    }
+
+    fn local_distance(params: [f32;4], p: Vector3<f32>) -> f32 {
+        let [x, y, z, radius] = params;
+        let q = abs3(p) - Vector3::new(x, y, z) + Vector3::new(radius, radius, radius);
+        max3(q, 0.0).magnitude() + q.x.max(q.y.max(q.z)).min(0.0) - radius
+    }
+}
+
+/// Hexagonal prism along y, `radius` is the distance from the axis to the faces
+#[derive(Copy, Clone, Debug)]
+pub struct HexPrism{
+    pub position: [f32;3],
+    pub rotation: [f32;3],
+    pub height: f32,
+    pub radius: f32
+}
+
+impl HexPrism {
+    pub fn new(position: [f32;3], rotation: [f32;3], height: f32, radius: f32) -> Self {
+        Self{ position, rotation, height, radius }
+    }
+}
+
+impl Primitive for HexPrism {
+    const SHAPE_TYPE: u32 = 10;
+    const DISTANCE_FUNCTION: &'static str = "hex_prism_distance";
+    const WGSL: &'static str = include_str!("../shaders/primitives/hex_prism.wgsl");
+
+    fn record(&self) -> PrimitiveRecord {
+        PrimitiveRecord::new(self.position, self.rotation, [self.radius, self.height / 2.0, 0.0, 0.0])
+    }
+
+    fn local_distance(params: [f32;4], p: Vector3<f32>) -> f32 {
+        let [radius, half_height, _, _] = params;
+        let p = abs3(p);
+        let k = Vector3::new(-0.8660254, 0.5, 0.57735);
+        let k_xy = Vector2::new(k.x, k.y);
+        let mut hex = Vector2::new(p.x, p.z);
+        hex -= k_xy * (2.0 * k_xy.dot(hex).min(0.0));
+        let edge = Vector2::new(wgsl_clamp(hex.x, -k.z * radius, k.z * radius), radius);
+        let d = Vector2::new((hex - edge).magnitude() * wgsl_sign(hex.y - radius), p.y - half_height);
+        d.x.max(d.y).min(0.0) + max2(d, 0.0).magnitude()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_distances<T: Primitive>(primitive: &T, expected: &[([f32;3], f32)]) {
+        for (point, distance) in expected {
+            let actual = primitive.distance(*point);
+            assert!((actual - distance).abs() < 1e-5, "{:?}: {} instead of {}", point, actual, distance);
+        }
+    }
+
+    #[test]
+    fn plane() {
+        assert_distances(&Plane::new([0.0, 1.0, 0.0], [0.0;3]), &[([3.0, 1.0, -2.0], 0.0), ([3.0, 4.0, 5.0], 3.0), ([0.0, -1.0, 0.0], -2.0)]);
+        // Turned upside down
+        assert_distances(&Plane::new([0.0;3], [std::f32::consts::PI, 0.0, 0.0]), &[([0.0, 2.0, 0.0], -2.0)]);
+    }
+
+    #[test]
+    fn capsule() {
+        assert_distances(&Capsule::new([1.0, 0.0, 0.0], [0.0;3], 2.0, 0.5), &[
+            ([1.0, 0.0, 0.0], -0.5), ([1.5, 0.0, 0.0], 0.0), ([1.0, 1.5, 0.0], 0.0), ([1.0, -3.0, 0.0], 1.5), ([3.0, 0.5, 0.0], 1.5)
+        ]);
+    }
+
+    #[test]
+    fn cylinder() {
+        assert_distances(&Cylinder::new([0.0;3], [0.0;3], 2.0, 1.0), &[
+            ([0.0, 0.0, 0.0], -1.0), ([1.0, 0.0, 0.0], 0.0), ([0.0, 1.0, 0.5], 0.0), ([0.0, 0.0, 3.0], 2.0), ([2.0, 2.0, 0.0], 2f32.sqrt())
+        ]);
+    }
+
+    #[test]
+    fn cone() {
+        assert_distances(&Cone::new([0.0;3], [0.0;3], 2.0, 1.0, 0.0), &[
+            ([0.0, -1.0, 0.0], 0.0), ([0.0, 1.0, 0.0], 0.0), ([0.5, 0.0, 0.0], 0.0), ([0.0, -3.0, 0.0], 2.0), ([0.0, 2.0, 0.0], 1.0)
+        ]);
+    }
+
+    #[test]
+    fn ellipsoid() {
+        assert_distances(&Ellipsoid::new([0.0;3], [0.0;3], [1.0, 2.0, 3.0]), &[
+            ([1.0, 0.0, 0.0], 0.0), ([0.0, -2.0, 0.0], 0.0), ([0.0, 0.0, 3.0], 0.0)
+        ]);
+        // Exact for a sphere
+        assert_distances(&Ellipsoid::new([0.0;3], [0.0;3], [1.0, 1.0, 1.0]), &[([2.0, 0.0, 0.0], 1.0), ([0.0, 0.5, 0.0], -0.5)]);
+    }
+
+    #[test]
+    fn rounded_box() {
+        assert_distances(&RoundedBox::new([0.0;3], [0.0;3], [2.0, 2.0, 2.0], 0.25), &[
+            ([0.0, 0.0, 0.0], -1.0), ([1.0, 0.0, 0.0], 0.0), ([0.0, 0.0, 2.0], 1.0), ([1.0, 1.0, 0.0], 0.25 * 2f32.sqrt() - 0.25)
+        ]);
+    }
+
+    #[test]
+    fn hex_prism() {
+        assert_distances(&HexPrism::new([0.0;3], [0.0;3], 2.0, 1.0), &[
+            ([0.0, 0.0, 0.0], -1.0), ([0.0, 0.0, 1.0], 0.0), ([0.0, 1.0, 0.0], 0.0), ([0.0, 0.0, -3.0], 2.0), ([0.0, 4.0, 0.0], 3.0)
+        ]);
+        assert_eq!(wgsl_sign(0.0), 0.0);
+    }
+
+    #[test]
+    fn degenerate_sizes() {
+        // Without height the capsule is a sphere
+        assert_distances(&Capsule::new([0.0;3], [0.0;3], 0.0, 0.5), &[([2.0, 0.0, 0.0], 1.5), ([0.0, -1.0, 0.0], 0.5)]);
+        // Inverted bounds are clamped like in WGSL instead of panicking
+        assert_distances(&Capsule::new([0.0;3], [0.0;3], -2.0, 0.5), &[([0.0, 0.0, 0.0], 0.5)]);
+        assert!(HexPrism::new([0.0;3], [0.0;3], 2.0, -1.0).distance([0.5, 0.0, 0.5]).is_finite());
+        assert!(HexPrism::new([0.0;3], [0.0;3], 0.0, 0.0).distance([0.5, 0.0, 0.5]).is_finite());
+    }
+}
@@ -0,0 +1,209 @@
+//! Programs evaluating the trees of the composites in the shader.
+//!
+//! Each composite is compiled into a postfix program of its tree when it is created, so `shape_distance`
+//! and `shape_surface` run a flat loop over the instructions instead of walking the tree. The programs
+//! only hold the shape indices, the parameters of the shapes are read from their buffers and can change
+//! without compiling the program again.
+
+use bytemuck::{Pod, Zeroable};
+use crate::shapes::Shape;
+use crate::shapes::composit::Composit;
+
+/// Last instruction of every program
+pub const OP_END: u32 = 0;
+/// Push the distance of a shape that isn't a composite, evaluated in the frame on top of the frame stack
+pub const OP_LEAF: u32 = 1;
+/// Push the frame of a composite with several children
+pub const OP_PUSH: u32 = 2;
+/// Push the frame of a composite with a single child, with the point remapped by the composite
+pub const OP_PUSH_DOMAIN: u32 = 3;
+/// Replace the two distances on top of the result stack by their combination by the composite
+pub const OP_COMBINE: u32 = 4;
+/// Pop the frame pushed by [`OP_PUSH`]
+pub const OP_POP: u32 = 5;
+/// Pop the frame pushed by [`OP_PUSH_DOMAIN`] and apply the deformation of the composite to the distance of its child
+pub const OP_APPLY: u32 = 6;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct Instruction{
+    pub op: u32,
+    /// Index in the shape buffer
+    pub shape: u32
+}
+
+impl Instruction {
+    pub fn new(op: u32, shape: u32) -> Self {
+        Self{ op, shape }
+    }
+}
+
+/// Program evaluating the tree of the shape at `index`, ended by [`OP_END`]
+pub(crate) fn compile(shapes: &[Shape], composits: &[Composit], child_lists: &[u32], index: u32) -> Vec<Instruction> {
+    let mut program = vec![];
+    compile_node(shapes, composits, child_lists, index, &mut program);
+    program.push(Instruction::new(OP_END, 0));
+    program
+}
+
+fn compile_node(shapes: &[Shape], composits: &[Composit], child_lists: &[u32], index: u32, program: &mut Vec<Instruction>) {
+    let shape = &shapes[index as usize];
+    if shape.shape_type != 9 {
+        program.push(Instruction::new(OP_LEAF, index));
+        return;
+    }
+    let composit = &composits[shape.index as usize];
+    let children = composit.evaluation_order(child_lists);
+    if composit.single_child() {
+        program.push(Instruction::new(OP_PUSH_DOMAIN, index));
+        compile_node(shapes, composits, child_lists, children[0], program);
+        program.push(Instruction::new(OP_APPLY, index));
+    } else {
+        program.push(Instruction::new(OP_PUSH, index));
+        compile_node(shapes, composits, child_lists, children[0], program);
+        for child in &children[1..] {
+            compile_node(shapes, composits, child_lists, *child, program);
+            program.push(Instruction::new(OP_COMBINE, index));
+        }
+        program.push(Instruction::new(OP_POP, index));
+    }
+}
+
+/// Size the frame and result stacks of the shader must have to run `program`, up to its [`OP_END`]
+pub fn stack_depth(program: &[Instruction]) -> u32 {
+    // The point of the ray is the first frame
+    let (mut frames, mut results) = (1, 0);
+    let mut depth = 1;
+    for instruction in program {
+        match instruction.op {
+            OP_LEAF => results += 1,
+            OP_PUSH | OP_PUSH_DOMAIN => frames += 1,
+            OP_COMBINE => results -= 1,
+            OP_POP | OP_APPLY => frames -= 1,
+            OP_END => break,
+            _ => {}
+        }
+        depth = depth.max(frames).max(results);
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::shapes::{ShapeCollection, ShapeProperties};
+    use crate::shapes::composit::CompositDescriptor;
+    use crate::shapes::cuboid::Cuboid;
+    use crate::shapes::primitives::Capsule;
+    use crate::shapes::sphere::Sphere;
+    use crate::shapes::torus::Torus;
+    use crate::shapes::transform::Transform;
+
+    fn sphere() -> CompositDescriptor {
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: false};
+        CompositDescriptor::SPHERE(Sphere::new([0.0, 0.0, 0.0], 1.0), props)
+    }
+
+    fn difference(a: CompositDescriptor, b: CompositDescriptor) -> CompositDescriptor {
+        CompositDescriptor::DIFFERENCE(Box::new(a), Box::new(b))
+    }
+
+    /// Depth of the compiled program of `desc`, and the one [`CompositDescriptor::stack_usage`] expects
+    fn depths(desc: &CompositDescriptor) -> (u32, u32) {
+        let mut collection = ShapeCollection::without_device();
+        let handle = collection.create_composite(desc).unwrap();
+        let index = collection.index_of(handle).unwrap();
+        let program = compile(collection.shapes.as_slice(), collection.composits.as_slice(), &collection.child_lists, index);
+        (stack_depth(&program), desc.stack_usage().depth())
+    }
+
+    #[test]
+    fn deep_trees() {
+        let (mut left, mut right) = (sphere(), sphere());
+        for _ in 0..6 {
+            left = difference(left, sphere());
+            right = difference(sphere(), right);
+        }
+        let balanced = difference(difference(sphere(), sphere()), difference(sphere(), difference(sphere(), sphere())));
+        for desc in [left, right, balanced] {
+            let (program, usage) = depths(&desc);
+            assert_eq!(program, usage);
+        }
+    }
+
+    #[test]
+    fn nary_trees() {
+        let inner = CompositDescriptor::INTERSECTION_ALL(vec![sphere(), sphere(), difference(sphere(), sphere())]);
+        let desc = CompositDescriptor::UNION_ALL(vec![sphere(), difference(sphere(), inner), sphere(), sphere()]);
+        let (program, usage) = depths(&desc);
+        assert_eq!(program, usage);
+        // Nested unions are flattened, so five shapes still only need two results
+        let nested = CompositDescriptor::UNION(Box::new(CompositDescriptor::UNION_ALL(vec![sphere(), sphere(), sphere()])),
+            Box::new(CompositDescriptor::UNION(Box::new(sphere()), Box::new(sphere()))));
+        assert_eq!(depths(&nested), (2, 2));
+    }
+
+    #[test]
+    fn domain_trees() {
+        let mut desc = difference(sphere(), sphere());
+        desc = CompositDescriptor::REPEAT(Box::new(desc), [2.0, 0.0, 2.0], None);
+        desc = CompositDescriptor::TWIST(Box::new(desc), 0.5, 1.0);
+        desc = difference(sphere(), CompositDescriptor::TRANSLATE(Box::new(desc), [0.0, 1.0, 0.0]));
+        desc = CompositDescriptor::MIRROR(Box::new(desc), [1.0, 0.0, 0.0], 0.0);
+        desc = CompositDescriptor::MATERIAL_BLEND(Box::new(desc), 0.1);
+        let (program, usage) = depths(&desc);
+        assert_eq!(program, usage);
+    }
+
+    /// Every kind of node over `child`, on each side of the binary ones
+    fn every_node(child: &dyn Fn() -> CompositDescriptor) -> Vec<CompositDescriptor> {
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: false};
+        let (a, b) = (|| Box::new(child()), || Box::new(sphere()));
+        vec![
+            CompositDescriptor::UNION(a(), b()), CompositDescriptor::UNION(b(), a()),
+            CompositDescriptor::INTERSECTION(a(), b()), CompositDescriptor::INTERSECTION(b(), a()),
+            CompositDescriptor::DIFFERENCE(a(), b()), CompositDescriptor::DIFFERENCE(b(), a()),
+            CompositDescriptor::BLEND(a(), b(), 0.5), CompositDescriptor::BLEND(b(), a(), 0.5),
+            CompositDescriptor::SMOOTH_UNION(a(), b(), 0.1), CompositDescriptor::SMOOTH_UNION(b(), a(), 0.1),
+            CompositDescriptor::SMOOTH_INTERSECTION(a(), b(), 0.1), CompositDescriptor::SMOOTH_INTERSECTION(b(), a(), 0.1),
+            CompositDescriptor::SMOOTH_DIFFERENCE(a(), b(), 0.1), CompositDescriptor::SMOOTH_DIFFERENCE(b(), a(), 0.1),
+            CompositDescriptor::UNION_ALL(vec![child(), sphere(), sphere()]),
+            CompositDescriptor::INTERSECTION_ALL(vec![sphere(), child(), sphere()]),
+            CompositDescriptor::TRANSLATE(a(), [1.0, 0.0, 0.0]),
+            CompositDescriptor::ROTATE(a(), [0.0, 1.0, 0.0]),
+            CompositDescriptor::SCALE(a(), [2.0;3]),
+            CompositDescriptor::TRANSFORM(a(), Transform::from_euler([1.0, 0.0, 0.0], [0.0;3], [1.0;3])),
+            CompositDescriptor::REPEAT(a(), [2.0, 0.0, 0.0], Some([1, 0, 0])),
+            CompositDescriptor::MIRROR(a(), [1.0, 0.0, 0.0], 0.0),
+            CompositDescriptor::POLAR(a(), 3),
+            CompositDescriptor::TWIST(a(), 0.5, 1.0),
+            CompositDescriptor::BEND(a(), 0.5, 1.0),
+            CompositDescriptor::DISPLACE(a(), 0.1, 2.0),
+            CompositDescriptor::ROUND(a(), 0.1),
+            CompositDescriptor::ONION(a(), 0.1),
+            CompositDescriptor::ELONGATE(a(), [0.5, 0.0, 0.0]),
+            CompositDescriptor::MATERIAL(a(), props),
+            CompositDescriptor::MATERIAL_BLEND(a(), 0.1),
+        ]
+    }
+
+    /// `create_composite` only checks the descriptor, so every node has to need as many slots as its program
+    #[test]
+    fn every_node_type() {
+        let props = ShapeProperties{color: Color(1.0, 1.0, 1.0), reflectivity: 0.0, visible: false};
+        let deep = || difference(sphere(), difference(sphere(), difference(sphere(), sphere())));
+        let nary = || CompositDescriptor::UNION_ALL(vec![sphere(), deep(), sphere()]);
+        let leaves = [
+            CompositDescriptor::CUBOID(Cuboid::new([0.0;3], [1.0;3], [0.0;3]), props),
+            CompositDescriptor::TORUS(Torus::new([0.0;3], 1.0, 0.2, [0.0;3]), props),
+            CompositDescriptor::primitive(&Capsule::new([0.0;3], [0.0;3], 1.0, 0.2), props),
+        ];
+        let children: [&dyn Fn() -> CompositDescriptor; 3] = [&sphere, &deep, &nary];
+        let trees = children.iter().flat_map(|child| every_node(child)).chain(leaves);
+        for (position, desc) in trees.enumerate() {
+            let (program, usage) = depths(&desc);
+            assert_eq!(program, usage, "tree {}", position);
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! CPU reference of `distance.wgsl` and `surface.wgsl`.
+//!
+//! [`ShapeCollection::shape_distance`] and [`ShapeCollection::shape_surface`] run the programs of the composites
+//! on the CPU copies of the shape buffers, with the same instructions and the same formulas as the shader, so
+//! their results can be compared with what the GPU computes for a pixel. The ray marching itself is in
+//! [`reference`](crate::reference).
+
+use std::borrow::Cow;
+use cgmath::{InnerSpace, Matrix3, SquareMatrix, Vector3, Vector4};
+use crate::shapes::{Shape, ShapeCollection, STACK_DEPTH};
+use crate::shapes::composit::Composit;
+use crate::shapes::primitives::PrimitiveRecord;
+use crate::shapes::program::{compile, Instruction, OP_APPLY, OP_COMBINE, OP_END, OP_LEAF, OP_POP, OP_PUSH, OP_PUSH_DOMAIN};
+use crate::shapes::registry::PrimitiveRegistry;
+use crate::shapes::transform::Transform;
+
+/// Distance returned by the shader for the skipped shapes and the unregistered primitives
+const FAR: f32 = 9999999.0;
+
+/// `DistRes` of the shader
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DistRes {
+    pub distance: f32,
+    /// Closest shape that isn't a composite
+    pub index: u32,
+    /// The distance may be overestimated by this factor because of the deformations
+    pub lipschitz: f32
+}
+
+/// `SurfaceInfo` of the shader
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SurfaceInfo {
+    pub normal: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub reflectivity: f32
+}
+
+/// `transform_point` of the shader, w is the factor the distances of the frame are scaled by
+fn transform_point(p: Vector4<f32>, transform: &Transform) -> Vector4<f32> {
+    let (local, scale) = transform.local_point(p.truncate().into());
+    local.extend(p.w * scale)
+}
+
+/// `binary_op` of the shader
+fn binary_op(comp_type: u32) -> u32 {
+    match comp_type {
+        17 => 0,
+        18 => 1,
+        op => op
+    }
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+fn mix3(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a * (1.0 - t) + b * t
+}
+
+fn smooth_max(a: f32, b: f32, alpha: f32) -> f32 {
+    (a * (a * alpha).exp2() + b * (b * alpha).exp2()) / ((a * alpha).exp2() + (b * alpha).exp2())
+}
+
+/// Polynomial smooth minimum of a and b over the radius k, and the weight of a in the result
+fn poly_smooth_min(a: f32, b: f32, k: f32) -> (f32, f32) {
+    let radius = k.max(0.000001);
+    let h = (0.5 + 0.5 * (b - a) / radius).clamp(0.0, 1.0);
+    (mix(b, a, h) - radius * h * (1.0 - h), h)
+}
+
+fn poly_smooth_max(a: f32, b: f32, k: f32) -> (f32, f32) {
+    let (d, h) = poly_smooth_min(-a, -b, k);
+    (-d, h)
+}
+
+/// `combine_distances` of the shader, unknown operators give 0
+fn combine_distances(a: f32, b: f32, c: &Composit, w: f32) -> f32 {
+    match binary_op(c.comp_type()) {
+        0 => a.min(b),
+        1 => a.max(b),
+        2 => b.max(-a),
+        3 => smooth_max(a, b, -c.alpha()),
+        14 => poly_smooth_min(a, b, c.alpha() * w).0,
+        15 => poly_smooth_max(a, b, c.alpha() * w).0,
+        16 => poly_smooth_max(b, -a, c.alpha() * w).0,
+        _ => 0.0
+    }
+}
+
+/// `combine_surfaces` of the shader, the weights being the ones of a
+fn combine_surfaces(a: SurfaceInfo, b: SurfaceInfo, normal_weight: f32, material_weight: f32) -> SurfaceInfo {
+    SurfaceInfo{
+        normal: mix3(b.normal, a.normal, normal_weight).normalize(),
+        color: mix3(b.color, a.color, material_weight),
+        reflectivity: mix(b.reflectivity, a.reflectivity, material_weight)
+    }
+}
+
+/// `combine_children` of the shader, returns the distance and the surface of the composite
+fn combine_children(ad: f32, mut a_surface: SurfaceInfo, bd: f32, b_surface: SurfaceInfo, c: &Composit, w: f32) -> (f32, SurfaceInfo) {
+    let op = binary_op(c.comp_type());
+    // How much closer to the surface of the composite a is than b, and the weight of a in the normal
+    let (d, x, mut normal_weight) = match op {
+        0 => (ad.min(bd), bd - ad, 0.0),
+        1 => (ad.max(bd), ad - bd, 0.0),
+        2 => {
+            a_surface.normal = -a_surface.normal;
+            (bd.max(-ad), -ad - bd, 0.0)
+        }
+        3 => (smooth_max(ad, bd, -c.alpha()), bd - ad, 0.0),
+        14 => {
+            let (d, h) = poly_smooth_min(ad, bd, c.alpha() * w);
+            (d, bd - ad, h)
+        }
+        15 => {
+            let (d, h) = poly_smooth_max(ad, bd, c.alpha() * w);
+            (d, ad - bd, h)
+        }
+        16 => {
+            let (d, h) = poly_smooth_max(bd, -ad, c.alpha() * w);
+            a_surface.normal = -a_surface.normal;
+            (d, -ad - bd, 1.0 - h)
+        }
+        _ => (0.0, 0.0, 0.0)
+    };
+    if op < 4 {
+        normal_weight = if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    let mut material_weight = normal_weight;
+    if c.seam() > 0.0 {
+        material_weight = (0.5 + 0.5 * x / (c.seam() * w)).clamp(0.0, 1.0);
+    }
+    (d, combine_surfaces(a_surface, b_surface, normal_weight, material_weight))
+}
+
+/// `primitive_distance` of the shader
+fn primitive_distance(registry: &PrimitiveRegistry, point: Vector3<f32>, shape_type: u32, record: &PrimitiveRecord) -> f32 {
+    match registry.get(shape_type) {
+        Some(primitive_type) => (primitive_type.distance)(record, point.into()),
+        None => FAR
+    }
+}
+
+/// `primitive_normal` of the shader, the gradient of the distance by central differences
+fn primitive_normal(registry: &PrimitiveRegistry, point: Vector3<f32>, shape_type: u32, record: &PrimitiveRecord) -> Vector3<f32> {
+    let e = 0.0001;
+    let derivative = |axis: Vector3<f32>| {
+        primitive_distance(registry, point + axis * e, shape_type, record) - primitive_distance(registry, point - axis * e, shape_type, record)
+    };
+    Vector3::new(derivative(Vector3::unit_x()), derivative(Vector3::unit_y()), derivative(Vector3::unit_z())).normalize()
+}
+
+impl ShapeCollection {
+    fn composit_of(&self, shape: &Shape) -> &Composit {
+        &self.composits.as_slice()[shape.index as usize]
+    }
+
+    /// Program of the composite at `root`, compiled here when the shader has none because the composite isn't visible
+    fn program_of(&self, root: u32) -> Cow<'_, [Instruction]> {
+        match self.composit_of(&self.shapes.as_slice()[root as usize]).program() {
+            Some(start) => Cow::Borrowed(&self.programs.as_slice()[start as usize..]),
+            None => Cow::Owned(compile(self.shapes.as_slice(), self.composits.as_slice(), &self.child_lists, root))
+        }
+    }
+
+    /// Whether the shape at `index` is drawn, the shader skips the hidden ones when marching
+    pub(crate) fn visible(&self, index: u32) -> bool {
+        self.shapes.as_slice()[index as usize].visible != 0
+    }
+
+    /// `leaf_distance` of the shader
+    fn leaf_distance(&self, registry: &PrimitiveRegistry, frame: Vector4<f32>, shape: &Shape) -> f32 {
+        let local = transform_point(frame, &shape.transform);
+        let point = local.truncate();
+        let slot = shape.index as usize;
+        let distance = match shape.shape_type {
+            0 => self.spheres.as_slice()[slot].distance(point),
+            1 => self.cuboids.as_slice()[slot].distance(point),
+            2 => self.tori.as_slice()[slot].distance(point),
+            shape_type => primitive_distance(registry, point, shape_type, &self.primitives.as_slice()[slot])
+        };
+        distance * local.w
+    }
+
+    /// `leaf_surface` of the shader
+    fn leaf_surface(&self, registry: &PrimitiveRegistry, frame: Vector4<f32>, normal_matrix: Matrix3<f32>, shape: &Shape) -> SurfaceInfo {
+        let local = transform_point(frame, &shape.transform).truncate();
+        let slot = shape.index as usize;
+        let normal = match shape.shape_type {
+            0 => self.spheres.as_slice()[slot].normal(local),
+            1 => self.cuboids.as_slice()[slot].normal(local),
+            2 => self.tori.as_slice()[slot].normal(local),
+            shape_type => primitive_normal(registry, local, shape_type, &self.primitives.as_slice()[slot])
+        };
+        let color = shape.color;
+        SurfaceInfo{
+            normal: (normal_matrix * shape.transform.normal_matrix() * normal).normalize(),
+            color: Vector3::new(color.0, color.1, color.2),
+            reflectivity: shape.reflectivity
+        }
+    }
+
+    /// `override_material` of the shader, applied to the surface on top of the stack
+    fn override_material(&self, shape: &Shape, surfaces: &mut [SurfaceInfo]) {
+        if self.composit_of(shape).overrides_material() {
+            let surface = surfaces.last_mut().unwrap();
+            surface.color = Vector3::new(shape.color.0, shape.color.1, shape.color.2);
+            surface.reflectivity = shape.reflectivity;
+        }
+    }
+
+    /// Distance from `point` to the shape at `root` as the shader's `shape_distance` computes it,
+    /// the shape at `skip` being ignored. Primitives missing from `registry` are never hit.
+    pub fn shape_distance(&self, registry: &PrimitiveRegistry, point: Vector3<f32>, root: u32, skip: i32) -> DistRes {
+        let shapes = self.shapes.as_slice();
+        let root_shape = &shapes[root as usize];
+        if root_shape.shape_type != 9 {
+            let mut distance = self.leaf_distance(registry, point.extend(1.0), root_shape);
+            if root as i32 == skip {
+                distance = FAR;
+            }
+            return DistRes{ distance, index: root, lipschitz: 1.0 };
+        }
+
+        let mut frames = Vec::with_capacity(STACK_DEPTH as usize);
+        let mut results = Vec::with_capacity(STACK_DEPTH as usize);
+        frames.push(point.extend(1.0));
+
+        let mut mdist = 99999999999.0;
+        let mut midx = 0;
+        let mut skip_sign = 1.0;
+        let mut lipschitz = 1.0;
+        let program = self.program_of(root);
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            if instruction.op == OP_END {
+                break;
+            }
+
+            let shape = &shapes[instruction.shape as usize];
+            let frame = *frames.last().unwrap();
+            match instruction.op {
+                OP_LEAF => {
+                    let mut d = self.leaf_distance(registry, frame, shape);
+                    if instruction.shape as i32 == skip {
+                        d = FAR * skip_sign;
+                    }
+                    if mdist > d.abs() {
+                        mdist = d.abs();
+                        midx = instruction.shape;
+                    }
+                    results.push(d);
+                }
+                OP_PUSH => {
+                    let c = self.composit_of(shape);
+                    frames.push(transform_point(frame, &shape.transform));
+                    if (c.comp_type() == 2 || c.comp_type() == 16) && c.children(&self.child_lists)[0] as i32 == skip {
+                        skip_sign = -1.0;
+                    }
+                }
+                OP_PUSH_DOMAIN => {
+                    let c = self.composit_of(shape);
+                    frames.push(c.domain_point(transform_point(frame, &shape.transform)));
+                    // Deformations can shrink distances, the product of their factors bounds the whole tree
+                    if c.comp_type() >= 8 {
+                        lipschitz *= c.alpha();
+                    }
+                }
+                OP_COMBINE => {
+                    let a = results.pop().unwrap();
+                    let b = results.pop().unwrap();
+                    let c = self.composit_of(shape);
+                    if c.a_first() {
+                        results.push(combine_distances(b, a, c, frame.w));
+                    } else {
+                        results.push(combine_distances(a, b, c, frame.w));
+                    }
+                }
+                OP_POP => {
+                    frames.pop();
+                }
+                OP_APPLY => {
+                    frames.pop();
+                    let local = transform_point(*frames.last().unwrap(), &shape.transform);
+                    let d = results.pop().unwrap();
+                    results.push(self.composit_of(shape).domain_distance(d, local));
+                }
+                _ => {}
+            }
+        }
+        DistRes{ distance: results.pop().unwrap(), index: midx, lipschitz }
+    }
+
+    /// Normal, color and reflectivity of the shape at `root` at `point`, as the shader's `shape_surface` computes them
+    pub fn shape_surface(&self, registry: &PrimitiveRegistry, point: Vector3<f32>, root: u32) -> SurfaceInfo {
+        let shapes = self.shapes.as_slice();
+        let root_shape = &shapes[root as usize];
+        if root_shape.shape_type != 9 {
+            return self.leaf_surface(registry, point.extend(1.0), Matrix3::identity(), root_shape);
+        }
+
+        // Point in the frame of each node and the matrix taking its normals to world space
+        let mut frames: Vec<(Vector4<f32>, Matrix3<f32>)> = Vec::with_capacity(STACK_DEPTH as usize);
+        let mut results = Vec::with_capacity(STACK_DEPTH as usize);
+        let mut surfaces = Vec::with_capacity(STACK_DEPTH as usize);
+        frames.push((point.extend(1.0), Matrix3::identity()));
+
+        let program = self.program_of(root);
+        let mut pc = 0;
+        loop {
+            let instruction = program[pc];
+            pc += 1;
+            if instruction.op == OP_END {
+                break;
+            }
+
+            let shape = &shapes[instruction.shape as usize];
+            let (frame, normal_matrix) = *frames.last().unwrap();
+            match instruction.op {
+                OP_LEAF => {
+                    results.push(self.leaf_distance(registry, frame, shape));
+                    surfaces.push(self.leaf_surface(registry, frame, normal_matrix, shape));
+                }
+                OP_PUSH => {
+                    frames.push((transform_point(frame, &shape.transform), normal_matrix * shape.transform.normal_matrix()));
+                }
+                OP_PUSH_DOMAIN => {
+                    let c = self.composit_of(shape);
+                    let local = transform_point(frame, &shape.transform);
+                    let local_normal_matrix = normal_matrix * shape.transform.normal_matrix();
+                    frames.push((c.domain_point(local), local_normal_matrix * c.domain_normal_matrix(local.truncate())));
+                }
+                OP_COMBINE => {
+                    let ad = results.pop().unwrap();
+                    let a_surface = surfaces.pop().unwrap();
+                    let bd = results.pop().unwrap();
+                    let b_surface = surfaces.pop().unwrap();
+                    let c = self.composit_of(shape);
+                    let (d, surface) = if c.a_first() {
+                        combine_children(bd, b_surface, ad, a_surface, c, frame.w)
+                    } else {
+                        combine_children(ad, a_surface, bd, b_surface, c, frame.w)
+                    };
+                    results.push(d);
+                    surfaces.push(surface);
+                }
+                OP_POP => {
+                    frames.pop();
+                    self.override_material(shape, &mut surfaces);
+                }
+                OP_APPLY => {
+                    frames.pop();
+                    let c = self.composit_of(shape);
+                    let (parent, parent_normal_matrix) = *frames.last().unwrap();
+                    let local = transform_point(parent, &shape.transform);
+                    let local_normal_matrix = parent_normal_matrix * shape.transform.normal_matrix();
+                    let d = results.pop().unwrap();
+                    let mut surface = surfaces.pop().unwrap();
+                    match c.comp_type() {
+                        10 => surface.normal = (surface.normal + local_normal_matrix * c.displacement_gradient(local.truncate())).normalize(),
+                        // The inside of the shell faces the other way
+                        12 if d < 0.0 => surface.normal = -surface.normal,
+                        _ => {}
+                    }
+                    results.push(c.domain_distance(d, local));
+                    surfaces.push(surface);
+                    self.override_material(shape, &mut surfaces);
+                }
+                _ => {}
+            }
+        }
+        surfaces.pop().unwrap()
+    }
+}
@@ -0,0 +1,144 @@
+use thiserror::Error;
+use crate::shapes::primitives::{Capsule, Cone, Cylinder, Ellipsoid, HexPrism, Plane, Primitive, PrimitiveRecord, RoundedBox};
+
+/// Shape types handled by the shader itself : sphere, cuboid, torus and composite
+pub const RESERVED_SHAPE_TYPES: [u32;4] = [0, 1, 2, 9];
+
+/// Start of the dispatch function replaced by [`PrimitiveRegistry::shader_source`]
+const DISPATCH_FUNCTION: &str = "fn primitive_distance(";
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("Shape type {0} is used by the builtin shapes")]
+    ReservedShapeType(u32),
+    #[error("Shape type {0} is already registered by {1}")]
+    DuplicateShapeType(u32, &'static str),
+    #[error("The shader has no primitive_distance function to replace")]
+    MissingDispatch,
+}
+
+/// Primitive known by the shader
+#[derive(Clone, Debug)]
+pub struct PrimitiveType {
+    pub shape_type: u32,
+    pub distance_function: &'static str,
+    pub wgsl: &'static str,
+    /// CPU reference of the distance
+    pub distance: fn(&PrimitiveRecord, [f32;3]) -> f32,
+    // Already part of the ray marcher generated by the build script
+    builtin: bool
+}
+
+fn record_distance<T: Primitive>(record: &PrimitiveRecord, point: [f32;3]) -> f32 {
+    T::local_distance(record.params(), record.local_point(point))
+}
+
+impl PrimitiveType {
+    pub fn of<T: Primitive>() -> Self {
+        Self{
+            shape_type: T::SHAPE_TYPE,
+            distance_function: T::DISTANCE_FUNCTION,
+            wgsl: T::WGSL,
+            distance: record_distance::<T>,
+            builtin: false
+        }
+    }
+}
+
+/// Set of primitives the ray marcher can draw besides the spheres, cuboids and tori.
+///
+/// Every primitive is stored in the same buffer as a [`PrimitiveRecord`], so registering one
+/// only generates the `primitive_distance` dispatch of the shader, see [`PrimitiveRegistry::shader_source`].
+/// The storage buffers and their bindings are not generated, a primitive whose data doesn't fit
+/// the record can't be registered. The spheres, cuboids, tori and composites keep their own buffers
+/// and cases in the shader, see [`RESERVED_SHAPE_TYPES`].
+#[derive(Clone, Debug)]
+pub struct PrimitiveRegistry {
+    types: Vec<PrimitiveType>
+}
+
+impl Default for PrimitiveRegistry {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl PrimitiveRegistry {
+    /// Registry without any primitive, not even the builtin ones
+    pub fn empty() -> Self {
+        Self{ types: vec![] }
+    }
+
+    /// Registry with the primitives of [`primitives`](crate::shapes::primitives)
+    pub fn builtin() -> Self {
+        let mut registry = Self::empty();
+        registry.register_builtin::<Plane>();
+        registry.register_builtin::<Capsule>();
+        registry.register_builtin::<Cylinder>();
+        registry.register_builtin::<Cone>();
+        registry.register_builtin::<Ellipsoid>();
+        registry.register_builtin::<RoundedBox>();
+        registry.register_builtin::<HexPrism>();
+        registry
+    }
+
+    fn register_builtin<T: Primitive>(&mut self) {
+        let mut primitive_type = PrimitiveType::of::<T>();
+        primitive_type.builtin = true;
+        self.types.push(primitive_type);
+    }
+
+    pub fn register<T: Primitive>(&mut self) -> Result<(), RegistryError> {
+        if RESERVED_SHAPE_TYPES.contains(&T::SHAPE_TYPE) {
+            return Err(RegistryError::ReservedShapeType(T::SHAPE_TYPE));
+        }
+        if let Some(registered) = self.get(T::SHAPE_TYPE) {
+            return Err(RegistryError::DuplicateShapeType(T::SHAPE_TYPE, registered.distance_function));
+        }
+        self.types.push(PrimitiveType::of::<T>());
+        Ok(())
+    }
+
+    pub fn get(&self, shape_type: u32) -> Option<&PrimitiveType> {
+        self.types.iter().find(|primitive_type| primitive_type.shape_type == shape_type)
+    }
+
+    pub fn types(&self) -> &[PrimitiveType] {
+        &self.types
+    }
+
+    /// WGSL of the primitives missing from the generated ray marcher, followed by the dispatch function
+    pub fn wgsl(&self) -> String {
+        let mut source = String::new();
+        for primitive_type in self.types.iter().filter(|primitive_type| !primitive_type.builtin) {
+            source.push_str(primitive_type.wgsl.trim_end());
+            source.push_str("\n\n");
+        }
+        source.push_str("fn primitive_distance(a: vec3<f32>, shape_type:u32, b:Primitive)->f32{\n");
+        source.push_str("    var ret = 9999999.0;\n");
+        source.push_str("    switch(shape_type){\n");
+        for primitive_type in self.types.iter() {
+            source.push_str(&format!("        case {}u:{{\n", primitive_type.shape_type));
+            source.push_str(&format!("            ret = {}(a, b);\n", primitive_type.distance_function));
+            source.push_str("        }\n");
+        }
+        source.push_str("        default:{}\n");
+        source.push_str("    }\n");
+        source.push_str("    return ret;\n");
+        source.push_str("};");
+        source
+    }
+
+    /// `shader` with its `primitive_distance` function replaced by the one of [`PrimitiveRegistry::wgsl`]
+    pub fn shader_source(&self, shader: &str) -> Result<String, RegistryError> {
+        let start = match shader.find(DISPATCH_FUNCTION) {
+            Some(start) => start,
+            None => return Err(RegistryError::MissingDispatch)
+        };
+        let end = match shader[start..].find("\n};") {
+            Some(end) => start + end + "\n};".len(),
+            None => return Err(RegistryError::MissingDispatch)
+        };
+        Ok(format!("{}{}{}", &shader[..start], self.wgsl(), &shader[end..]))
+    }
+}
@@ -0,0 +1,263 @@
+//! WGSL specialized for a static scene.
+//!
+//! [`scene_sdf`] writes the distance to the visible shapes of a [`ShapeCollection`] as a single WGSL function,
+//! the parameters of the shapes being constants and the trees of the composites being unrolled, so marching a
+//! ray no longer reads the shape buffers nor runs the programs of the composites. [`shader_source`] puts it in
+//! the `raymarcher2_specialized.wgsl` variant, which calls it instead of looping over the shapes.
+//! The surfaces are still computed from the buffers, and the function must be generated again when the shapes change.
+
+use std::fmt::Write;
+use thiserror::Error;
+use crate::shapes::ShapeCollection;
+use crate::shapes::registry::PrimitiveRegistry;
+
+/// Start of the function replaced by [`shader_source`]
+const SCENE_FUNCTION: &str = "fn scene_sdf(";
+
+#[derive(Debug, Error)]
+pub enum SpecializeError {
+    #[error("Shape type {0} isn't registered, its distance function is unknown")]
+    UnregisteredPrimitive(u32),
+    #[error("The shader has no scene_sdf function to replace")]
+    MissingSceneFunction,
+}
+
+/// WGSL literal of `value`, literals can't be infinite so the unbounded parameters are clamped
+pub(crate) fn wgsl_f32(value: f32) -> String {
+    let value = if value.is_nan() { 0.0 } else { value.clamp(-f32::MAX, f32::MAX) };
+    let literal = value.to_string();
+    if literal.contains('.') { literal } else { format!("{}.0", literal) }
+}
+
+pub(crate) fn wgsl_vec3(v: [f32;3]) -> String {
+    format!("vec3<f32>({}, {}, {})", wgsl_f32(v[0]), wgsl_f32(v[1]), wgsl_f32(v[2]))
+}
+
+pub(crate) fn wgsl_vec4(v: [f32;4]) -> String {
+    format!("vec4<f32>({}, {}, {}, {})", wgsl_f32(v[0]), wgsl_f32(v[1]), wgsl_f32(v[2]), wgsl_f32(v[3]))
+}
+
+/// `mat3x3<f32>` read from the padded rows the same way the shader reads the buffers
+pub(crate) fn wgsl_mat3(m: [[f32;4];3]) -> String {
+    let column = |i:usize| wgsl_vec3([m[i][0], m[i][1], m[i][2]]);
+    format!("mat3x3<f32>({}, {}, {})", column(0), column(1), column(2))
+}
+
+/// Writes the body of `scene_sdf`, every value gets its own `let`
+struct Generator<'a> {
+    collection: &'a ShapeCollection,
+    registry: &'a PrimitiveRegistry,
+    body: String,
+    // Number of values declared so far, used to name them
+    values: usize
+}
+
+impl<'a> Generator<'a> {
+    /// Declare `expression` and return its name
+    fn value(&mut self, prefix: &str, expression: String) -> String {
+        let name = format!("{}{}", prefix, self.values);
+        self.values += 1;
+        writeln!(self.body, "    let {} = {};", name, expression).unwrap();
+        name
+    }
+
+    /// Distance to the shape at `index`, `frame` being the point in the frame of its parent.
+    /// Same computations as `leaf_distance` and the instructions of `shape_distance`.
+    fn shape(&mut self, frame: &str, index: u32, lipschitz: &mut f32) -> Result<String, SpecializeError> {
+        let shape = self.collection.shapes.as_slice()[index as usize];
+        let local = self.value("p", format!("transform_point({}, {})", frame, shape.transform.wgsl()));
+        let slot = shape.index as usize;
+        let distance = match shape.shape_type {
+            0 => format!("sphere_distance({}.xyz, {})", local, self.collection.spheres.as_slice()[slot].wgsl()),
+            1 => format!("cube_distance({}.xyz, {})", local, self.collection.cuboids.as_slice()[slot].wgsl()),
+            2 => format!("torus_distance({}.xyz, {})", local, self.collection.tori.as_slice()[slot].wgsl()),
+            9 => return self.composite(&local, slot, lipschitz),
+            shape_type => match self.registry.get(shape_type) {
+                Some(primitive_type) => format!("{}({}.xyz, {})", primitive_type.distance_function, local, self.collection.primitives.as_slice()[slot].wgsl()),
+                None => return Err(SpecializeError::UnregisteredPrimitive(shape_type))
+            }
+        };
+        Ok(self.value("d", format!("{} * {}.w", distance, local)))
+    }
+
+    /// Distance to the composite at `slot` of the composite buffer, `local` being the point in its frame
+    fn composite(&mut self, local: &str, slot: usize, lipschitz: &mut f32) -> Result<String, SpecializeError> {
+        let composit = self.collection.composits.as_slice()[slot];
+        let children = composit.children(&self.collection.child_lists);
+        if composit.single_child() {
+            let constant = composit.wgsl();
+            let frame = self.value("p", format!("domain_point({}, {})", local, constant));
+            if composit.comp_type() >= 8 {
+                *lipschitz *= composit.alpha();
+            }
+            let distance = self.shape(&frame, children[0], lipschitz)?;
+            return Ok(self.value("d", format!("domain_distance({}, {}, {})", distance, local, constant)));
+        }
+
+        let mut distances = vec![];
+        for child in children {
+            distances.push(self.shape(local, child, lipschitz)?);
+        }
+        if composit.child_range().is_some() {
+            let operator = if composit.comp_type() == 18 { "max" } else { "min" };
+            let mut distance = distances[0].clone();
+            for other in &distances[1..] {
+                distance = self.value("d", format!("{}({}, {})", operator, other, distance));
+            }
+            return Ok(distance);
+        }
+
+        let (a, b) = (&distances[0], &distances[1]);
+        let alpha = wgsl_f32(composit.alpha());
+        let expression = match composit.comp_type() {
+            0 => format!("min({}, {})", a, b),
+            1 => format!("max({}, {})", a, b),
+            2 => format!("max({}, -{})", b, a),
+            3 => format!("smooth_max({}, {}, {})", a, b, wgsl_f32(-composit.alpha())),
+            14 => format!("poly_smooth_min({}, {}, {} * {}.w).x", a, b, alpha, local),
+            15 => format!("poly_smooth_max({}, {}, {} * {}.w).x", a, b, alpha, local),
+            16 => format!("poly_smooth_max({}, -{}, {} * {}.w).x", b, a, alpha, local),
+            // Unknown operators give 0 in the shader too
+            _ => "0.0".to_string()
+        };
+        Ok(self.value("d", expression))
+    }
+}
+
+/// `scene_sdf` function returning the distance to the closest visible shape of `collection` and its index,
+/// the distance being divided by the lipschitz factor of the shape. Primitives must be registered in `registry`.
+pub fn scene_sdf(collection: &ShapeCollection, registry: &PrimitiveRegistry) -> Result<String, SpecializeError> {
+    let mut generator = Generator{ collection, registry, body: String::new(), values: 0 };
+    for (index, shape) in collection.shapes.as_slice().iter().enumerate() {
+        if shape.visible == 0 {
+            continue;
+        }
+        writeln!(generator.body, "    // Shape {}", index).unwrap();
+        let mut lipschitz = 1.0;
+        let mut distance = generator.shape("p_ray", index as u32, &mut lipschitz)?;
+        if lipschitz != 1.0 {
+            distance = generator.value("d", format!("{} / {}", distance, wgsl_f32(lipschitz)));
+        }
+        writeln!(generator.body, "    if(res.distance > {}){{", distance).unwrap();
+        writeln!(generator.body, "        res.distance = {};", distance).unwrap();
+        writeln!(generator.body, "        res.index = {}u;", index).unwrap();
+        writeln!(generator.body, "    }}").unwrap();
+    }
+
+    let mut source = String::new();
+    source.push_str("fn scene_sdf(p: vec3<f32>)->DistRes{\n");
+    source.push_str("    var res: DistRes;\n");
+    source.push_str("    res.distance = 9999999999.0;\n");
+    source.push_str("    res.index = 0u;\n");
+    source.push_str("    res.lipschitz = 1.0;\n");
+    source.push_str("    let p_ray = vec4<f32>(p, 1.0);\n");
+    source.push_str(&generator.body);
+    source.push_str("    return res;\n");
+    source.push_str("};");
+    Ok(source)
+}
+
+/// `shader` with its `scene_sdf` function replaced by the one of [`scene_sdf`]
+pub fn shader_source(shader: &str, collection: &ShapeCollection, registry: &PrimitiveRegistry) -> Result<String, SpecializeError> {
+    let start = match shader.find(SCENE_FUNCTION) {
+        Some(start) => start,
+        None => return Err(SpecializeError::MissingSceneFunction)
+    };
+    let end = match shader[start..].find("\n};") {
+        Some(end) => start + end + "\n};".len(),
+        None => return Err(SpecializeError::MissingSceneFunction)
+    };
+    Ok(format!("{}{}{}", &shader[..start], scene_sdf(collection, registry)?, &shader[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::Color;
+    use crate::shader::RAYMARCHER_SPECIALIZED_SHADER;
+    use crate::shapes::{ShapeCollection, ShapeProperties};
+    use crate::shapes::composit::CompositDescriptor;
+    use crate::shapes::cuboid::Cuboid;
+    use crate::shapes::primitives::*;
+    use crate::shapes::sphere::Sphere;
+    use crate::shapes::torus::Torus;
+    use crate::shapes::transform::Transform;
+    use super::*;
+
+    fn props() -> ShapeProperties {
+        ShapeProperties{ color: Color(0.5, 0.5, 0.5), reflectivity: 0.0, visible: false }
+    }
+
+    fn sphere(x: f32) -> CompositDescriptor {
+        CompositDescriptor::SPHERE(Sphere::new([x, 0.0, 0.0], 0.5), props())
+    }
+
+    /// A tree with every kind of shape and node
+    fn every_node() -> CompositDescriptor {
+        let boxed = |desc: CompositDescriptor| Box::new(desc);
+        let leaves = vec![
+            sphere(0.0),
+            CompositDescriptor::CUBOID(Cuboid::new([1.0, 0.0, 0.0], [0.5;3], [0.0, 0.3, 0.0]), props()),
+            CompositDescriptor::TORUS(Torus::new([2.0, 0.0, 0.0], 0.5, 0.1, [0.0;3]), props()),
+            CompositDescriptor::primitive(&Plane::new([0.0, -1.0, 0.0], [0.0;3]), props()),
+            CompositDescriptor::primitive(&Capsule::new([3.0, 0.0, 0.0], [0.0;3], 1.0, 0.2), props()),
+            CompositDescriptor::primitive(&Cylinder::new([4.0, 0.0, 0.0], [0.0;3], 1.0, 0.2), props()),
+            CompositDescriptor::primitive(&Cone::new([5.0, 0.0, 0.0], [0.0;3], 1.0, 0.3, 0.1), props()),
+            CompositDescriptor::primitive(&Ellipsoid::new([6.0, 0.0, 0.0], [0.0;3], [0.5, 0.3, 0.2]), props()),
+            CompositDescriptor::primitive(&RoundedBox::new([7.0, 0.0, 0.0], [0.0;3], [0.5;3], 0.1), props()),
+            CompositDescriptor::primitive(&HexPrism::new([8.0, 0.0, 0.0], [0.0;3], 0.5, 0.3), props()),
+        ];
+        let operators = vec![
+            CompositDescriptor::UNION(boxed(sphere(0.0)), boxed(sphere(1.0))),
+            CompositDescriptor::INTERSECTION(boxed(sphere(0.0)), boxed(sphere(0.5))),
+            CompositDescriptor::INTERSECTION_ALL(vec![sphere(0.0), sphere(0.2), sphere(0.4)]),
+            CompositDescriptor::DIFFERENCE(boxed(sphere(0.0)), boxed(sphere(0.5))),
+            CompositDescriptor::BLEND(boxed(sphere(0.0)), boxed(sphere(1.0)), 0.5),
+            CompositDescriptor::SMOOTH_UNION(boxed(sphere(0.0)), boxed(sphere(1.0)), 0.3),
+            CompositDescriptor::SMOOTH_INTERSECTION(boxed(sphere(0.0)), boxed(sphere(0.5)), 0.3),
+            CompositDescriptor::SMOOTH_DIFFERENCE(boxed(sphere(0.0)), boxed(sphere(0.5)), 0.3),
+        ];
+        let domains = vec![
+            CompositDescriptor::TRANSFORM(boxed(sphere(0.0)), Transform::from_euler([0.0, 1.0, 0.0], [0.3, 0.0, 0.0], [2.0;3])),
+            CompositDescriptor::REPEAT(boxed(sphere(0.0)), [3.0, 0.0, 3.0], Some([2, 0, 1])),
+            CompositDescriptor::MIRROR(boxed(sphere(1.0)), [1.0, 0.0, 0.0], 0.5),
+            CompositDescriptor::POLAR(boxed(sphere(2.0)), 6),
+            CompositDescriptor::TWIST(boxed(sphere(0.0)), 0.5, 1.0),
+            CompositDescriptor::BEND(boxed(sphere(0.0)), 0.5, 1.0),
+            CompositDescriptor::DISPLACE(boxed(sphere(0.0)), 0.1, 2.0),
+            CompositDescriptor::ROUND(boxed(sphere(0.0)), 0.1),
+            CompositDescriptor::ONION(boxed(sphere(0.0)), 0.1),
+            CompositDescriptor::ELONGATE(boxed(sphere(0.0)), [0.5, 0.0, 0.0]),
+            CompositDescriptor::MATERIAL(boxed(sphere(0.0)), props()),
+            CompositDescriptor::MATERIAL_BLEND(boxed(CompositDescriptor::UNION(boxed(sphere(0.0)), boxed(sphere(1.0)))), 0.2),
+        ];
+        CompositDescriptor::MATERIAL(boxed(CompositDescriptor::UNION_ALL(vec![
+            CompositDescriptor::UNION_ALL(leaves),
+            CompositDescriptor::UNION_ALL(operators),
+            CompositDescriptor::UNION_ALL(domains),
+        ])), props())
+    }
+
+    #[test]
+    fn specialized_shaders_are_valid() {
+        let mut collection = ShapeCollection::without_device();
+        collection.create_composite(&every_node()).unwrap();
+        let registry = PrimitiveRegistry::builtin();
+        let source = registry.shader_source(RAYMARCHER_SPECIALIZED_SHADER).unwrap();
+        let source = shader_source(&source, &collection, &registry).unwrap();
+        let module = match naga::front::wgsl::parse_str(&source) {
+            Ok(module) => module,
+            Err(err) => panic!("{}", err.emit_to_string(&source))
+        };
+        let mut validator = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::empty());
+        if let Err(err) = validator.validate(&module) {
+            panic!("{:?}", err);
+        }
+    }
+
+    #[test]
+    fn unregistered_primitives_are_refused() {
+        let mut collection = ShapeCollection::without_device();
+        collection.create_composite(&every_node()).unwrap();
+        assert!(matches!(scene_sdf(&collection, &PrimitiveRegistry::empty()), Err(SpecializeError::UnregisteredPrimitive(_))));
+    }
+}
@@ -1,9 +1,11 @@
 use std::sync::Mutex;
 use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Vector3};
 use lazy_static::lazy_static;
 use rand_pcg::Lcg128Xsl64;
 use rand::distributions::{Distribution, Uniform};
 use rand::SeedableRng;
+use crate::shapes::specialize::{wgsl_f32, wgsl_vec3};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -34,4 +36,19 @@ impl Sphere {
             radius
         }
     }
+
+    /// CPU reference of `sphere_distance`, `point` being in the frame of the shape
+    pub fn distance(&self, point: Vector3<f32>) -> f32 {
+        (point - Vector3::from(self.position)).magnitude() - self.radius
+    }
+
+    /// CPU reference of `sphere_normal`
+    pub fn normal(&self, point: Vector3<f32>) -> Vector3<f32> {
+        (point - Vector3::from(self.position)).normalize()
+    }
+
+    /// `Sphere` constructor with the values of the sphere
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Sphere({}, {})", wgsl_vec3(self.position), wgsl_f32(self.radius))
+    }
 }
\ No newline at end of file
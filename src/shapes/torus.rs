@@ -0,0 +1,52 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix, Vector2, Vector3};
+use crate::shapes::{rotation_matrix, shader_matrix};
+use crate::shapes::specialize::{wgsl_f32, wgsl_mat3, wgsl_vec3};
+
+/// Torus lying in the xz plane before rotation
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Torus{
+    position: [f32;3],
+    /// Distance from the center to the center of the tube
+    radius: f32,
+    rotation:[[f32;4];3],
+    /// Radius of the tube
+    thickness: f32,
+    _pad:[f32;3]
+}
+
+impl Torus {
+    pub fn new(position: [f32;3], radius: f32, thickness: f32, euler: [f32;3])-> Self{
+        Self{
+            position,
+            radius,
+            rotation: rotation_matrix(euler),
+            thickness,
+            _pad:[0.0;3]
+        }
+    }
+
+    // Point in the frame of the torus, same as `(a-b.pos)*b.rotation` in the shader
+    fn local_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        shader_matrix(self.rotation).transpose() * (point - Vector3::from(self.position))
+    }
+
+    /// CPU reference of `torus_distance`, `point` being in the frame of the shape
+    pub fn distance(&self, point: Vector3<f32>) -> f32 {
+        let local = self.local_point(point);
+        Vector2::new(Vector2::new(local.x, local.z).magnitude() - self.radius, local.y).magnitude() - self.thickness
+    }
+
+    /// CPU reference of `torus_normal`
+    pub fn normal(&self, point: Vector3<f32>) -> Vector3<f32> {
+        let local = self.local_point(point);
+        let ring = Vector3::new(local.x, 0.0, local.z).normalize() * self.radius;
+        shader_matrix(self.rotation) * (local - ring).normalize()
+    }
+
+    /// `Torus` constructor with the values of the torus
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Torus({}, {}, {}, {})", wgsl_vec3(self.position), wgsl_f32(self.radius), wgsl_mat3(self.rotation), wgsl_f32(self.thickness))
+    }
+}
@@ -0,0 +1,101 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::{InnerSpace, Matrix3, Quaternion, Rad, Rotation, Rotation3, Vector3};
+use crate::shapes::specialize::{wgsl_vec3, wgsl_vec4};
+
+/// Position, rotation and scale carried by every shape, composites included.
+///
+/// The shader moves the sample point in the frame of the shape before computing its distance, so a
+/// transform on a composite moves its whole tree. The distance is then multiplied by the smallest
+/// scale factor, a non-uniform scale gives a bound of the distance rather than the exact one.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct Transform{
+    position: [f32;3],
+    _pad1: [f32;1],
+    /// Unit quaternion, `[x, y, z, w]`
+    rotation: [f32;4],
+    scale: [f32;3],
+    _pad2: [f32;1]
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Transform {
+    /// `rotation` is normalized, every scale factor must be strictly positive
+    pub fn new(position: [f32;3], rotation: Quaternion<f32>, scale: [f32;3]) -> Self {
+        let rotation = rotation.normalize();
+        Self{
+            position,
+            _pad1: [0.0],
+            rotation: [rotation.v.x, rotation.v.y, rotation.v.z, rotation.s],
+            scale,
+            _pad2: [0.0]
+        }
+    }
+
+    pub fn identity() -> Self {
+        Self::new([0.0;3], Quaternion::new(1.0, 0.0, 0.0, 0.0), [1.0;3])
+    }
+
+    /// Rotation from euler angles in radians, applied around x, then y, then z
+    pub fn from_euler(position: [f32;3], euler: [f32;3], scale: [f32;3]) -> Self {
+        let [a, b, c] = euler;
+        let rotation = Quaternion::from_angle_z(Rad(c)) * Quaternion::from_angle_y(Rad(b)) * Quaternion::from_angle_x(Rad(a));
+        Self::new(position, rotation, scale)
+    }
+
+    pub fn from_translation(position: [f32;3]) -> Self {
+        Self::from_euler(position, [0.0;3], [1.0;3])
+    }
+
+    pub fn from_rotation(euler: [f32;3]) -> Self {
+        Self::from_euler([0.0;3], euler, [1.0;3])
+    }
+
+    pub fn from_scale(scale: [f32;3]) -> Self {
+        Self::from_euler([0.0;3], [0.0;3], scale)
+    }
+
+    pub fn position(&self) -> [f32;3] {
+        self.position
+    }
+
+    pub fn rotation(&self) -> Quaternion<f32> {
+        let [x, y, z, w] = self.rotation;
+        Quaternion::new(w, x, y, z)
+    }
+
+    pub fn scale(&self) -> [f32;3] {
+        self.scale
+    }
+
+    /// `point` in the frame of the transform and the factor its distances are multiplied by,
+    /// same as `transform_point` in the shader
+    pub fn local_point(&self, point: [f32;3]) -> (Vector3<f32>, f32) {
+        let centered = Vector3::from(point) - Vector3::from(self.position);
+        let rotated = self.rotation().invert().rotate_vector(centered);
+        let [sx, sy, sz] = self.scale;
+        (Vector3::new(rotated.x / sx, rotated.y / sy, rotated.z / sz), sx.min(sy).min(sz))
+    }
+
+    /// Matrix taking the normals of the frame of the transform to the frame of its parent,
+    /// same as `transform_normal_matrix` in the shader
+    pub fn normal_matrix(&self) -> Matrix3<f32> {
+        let rotation = self.rotation();
+        let [sx, sy, sz] = self.scale;
+        Matrix3::from_cols(
+            rotation.rotate_vector(Vector3::unit_x()) / sx,
+            rotation.rotate_vector(Vector3::unit_y()) / sy,
+            rotation.rotate_vector(Vector3::unit_z()) / sz
+        )
+    }
+
+    /// `Transform` constructor with the values of the transform
+    pub(crate) fn wgsl(&self) -> String {
+        format!("Transform({}, {}, {})", wgsl_vec3(self.position), wgsl_vec4(self.rotation), wgsl_vec3(self.scale))
+    }
+}
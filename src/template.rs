@@ -0,0 +1,611 @@
+//! Assembly of `.wgsl.json` templates into a single shader.
+//!
+//! Shared between `build.rs`, that generates the shaders at compile time,
+//! and the shader hot-reload, that regenerates them at runtime.
+
+use std::error::Error as StdError;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Component, Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer_pretty};
+use thiserror::Error;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Template{
+    pub target: String,
+    /// Values of the defines for the main target, variants start from them
+    #[serde(default)]
+    pub defines: BTreeMap<String, String>,
+    /// Other shaders generated from the same components with different defines
+    #[serde(default)]
+    pub variants: Vec<TemplateVariant>,
+    pub components:Vec<TemplateComponent>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TemplateVariant{
+    pub name: String,
+    pub target: String,
+    /// Overrides of the template defines
+    #[serde(default)]
+    pub defines: BTreeMap<String, String>
+}
+
+/// Shader to generate from a template
+#[derive(Clone, Debug)]
+pub struct TemplateTarget{
+    pub name: String,
+    pub target: String,
+    pub defines: BTreeMap<String, String>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TemplateComponent{
+    pub title: String,
+    pub path: String
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("Fail to open a file : {0} \n {1}")]
+    IOError(PathBuf, std::io::Error),
+    #[error("Error parsing the template \"{0}\": \n {1}")]
+    ParseError(PathBuf, serde_json::Error),
+    #[error("Fail to write the line map \"{0}\": \n {1}")]
+    LineMapError(PathBuf, serde_json::Error),
+    #[error("Invalid shader \"{0}\": \n{1}")]
+    ValidationError(String, String),
+    #[error("Component {0} included from {1} can't be read : \n {2}")]
+    MissingComponent(PathBuf, PathBuf, std::io::Error),
+    #[error("{0}:{1}: {2}")]
+    DirectiveError(PathBuf, usize, String),
+    #[error("Include cycle : {}", .0.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(" -> "))]
+    DependencyCycle(Vec<PathBuf>),
+}
+
+/// Lines of the generated shader coming from one component
+#[derive(Clone, Debug, Serialize)]
+pub struct Section{
+    pub path: PathBuf,
+    /// First line of the component in the generated shader (1-based)
+    pub start: usize,
+    pub line_count: usize
+}
+
+/// Generated shader with the position of every component in it
+#[derive(Clone, Debug)]
+pub struct AssembledShader{
+    pub target: String,
+    pub source: String,
+    pub sections: Vec<Section>
+}
+
+fn section_title(title:&str) -> String {
+    format!("\n\
+        \n\
+        /////////////////////////////////////////////\n\
+        // {} \n\
+        /////////////////////////////////////////////\n\
+        \n\
+        ",title)
+}
+
+/// Component file with its directives removed
+#[derive(Clone, Debug)]
+struct ComponentSource{
+    content: String,
+    /// Components this one depends on, relative to the root
+    includes: Vec<PathBuf>
+}
+
+/// Components already read, shared between the templates so that common components are only parsed once
+#[derive(Default)]
+pub struct ComponentCache{
+    sources: HashMap<PathBuf, ComponentSource>
+}
+
+/// Resolve `.` and `..` so that a component reached through different includes has a single path
+fn normalize(path:&Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !res.pop() {
+                    res.push("..");
+                }
+            }
+            part => res.push(part)
+        }
+    }
+    res
+}
+
+impl ComponentCache {
+    fn load(&mut self, root:&Path, path:&Path, included_from:Option<&Path>) -> Result<&ComponentSource, TemplateError> {
+        if !self.sources.contains_key(path) {
+            let content = match fs::read_to_string(root.join(path)) {
+                Ok(ok) => ok,
+                Err(err) => return Err(match included_from {
+                    Some(parent) => TemplateError::MissingComponent(path.to_path_buf(), parent.to_path_buf(), err),
+                    None => TemplateError::IOError(path.to_path_buf(), err)
+                })
+            };
+            let source = Self::parse_directives(path, &content)?;
+            self.sources.insert(path.to_path_buf(), source);
+        }
+        Ok(&self.sources[path])
+    }
+
+    /// Extract the `#include "file.wgsl"` directives, paths are relative to the component.
+    /// Directive lines are blanked so that the line numbers are preserved.
+    fn parse_directives(path:&Path, content:&str) -> Result<ComponentSource, TemplateError> {
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut includes = vec![];
+        let mut lines = vec![];
+        for (number, line) in content.lines().enumerate() {
+            let directive = line.trim();
+            if let Some(argument) = directive.strip_prefix("#include") {
+                let argument = argument.trim();
+                if argument.len() < 2 || !argument.starts_with('"') || !argument.ends_with('"') {
+                    let message = format!("invalid directive \"{}\", expected #include \"path\"", directive);
+                    return Err(TemplateError::DirectiveError(path.to_path_buf(), number + 1, message));
+                }
+                includes.push(normalize(&directory.join(&argument[1..argument.len() - 1])));
+                lines.push("");
+            } else {
+                lines.push(line);
+            }
+        }
+        let mut content_without_directives = lines.join("\n");
+        if content.ends_with('\n') {
+            content_without_directives.push('\n');
+        }
+        Ok(ComponentSource{ content: content_without_directives, includes })
+    }
+}
+
+/// `#if` block being preprocessed
+struct Conditional{
+    line: usize,
+    parent_active: bool,
+    condition: bool,
+    in_else: bool
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.parent_active && self.condition != self.in_else
+    }
+}
+
+/// Value of a `#if` condition : `NAME` holds when the define is set to something else than `0` or `false`,
+/// `NAME == value` and `NAME != value` compare the define to a value. Undefined names are empty.
+fn evaluate_condition(condition:&str, defines:&BTreeMap<String, String>) -> Option<bool> {
+    let is_name = |name:&str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let value_of = |name:&str| defines.get(name).map(String::as_str).unwrap_or("");
+    for (operator, equal) in [("==", true), ("!=", false)] {
+        if let Some((name, value)) = condition.split_once(operator) {
+            let name = name.trim();
+            if !is_name(name) {
+                return None;
+            }
+            return Some((value_of(name) == value.trim()) == equal);
+        }
+    }
+    let (negated, name) = match condition.strip_prefix('!') {
+        Some(name) => (true, name.trim()),
+        None => (false, condition)
+    };
+    if !is_name(name) {
+        return None;
+    }
+    let value = value_of(name);
+    Some(!matches!(value, "" | "0" | "false") != negated)
+}
+
+/// Replace the words that are defines by their value
+fn substitute(line:&str, defines:&BTreeMap<String, String>) -> String {
+    let mut res = String::with_capacity(line.len());
+    let mut word_start = None;
+    for (index, c) in line.char_indices() {
+        let in_word = c.is_ascii_alphanumeric() || c == '_';
+        if in_word {
+            word_start.get_or_insert(index);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            let word = &line[start..index];
+            res.push_str(defines.get(word).map(String::as_str).unwrap_or(word));
+        }
+        res.push(c);
+    }
+    if let Some(start) = word_start {
+        let word = &line[start..];
+        res.push_str(defines.get(word).map(String::as_str).unwrap_or(word));
+    }
+    res
+}
+
+/// Apply the `#if/#else/#endif` blocks and substitute the defines.
+/// Directives and disabled lines are blanked so that the line numbers are preserved.
+fn preprocess(path:&Path, content:&str, defines:&BTreeMap<String, String>) -> Result<String, TemplateError> {
+    let error = |line:usize, message:String| TemplateError::DirectiveError(path.to_path_buf(), line, message);
+    let mut conditionals:Vec<Conditional> = vec![];
+    let mut lines = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let number = number + 1;
+        let active = conditionals.last().map_or(true, Conditional::active);
+        let directive = line.trim();
+        if !directive.starts_with('#') {
+            lines.push(if active { substitute(line, defines) } else { String::new() });
+            continue;
+        }
+
+        let (keyword, argument) = match directive.split_once(char::is_whitespace) {
+            Some((keyword, argument)) => (keyword, argument.trim()),
+            None => (directive, "")
+        };
+        match keyword {
+            "#if" => {
+                let condition = match evaluate_condition(argument, defines) {
+                    Some(condition) => condition,
+                    None => return Err(error(number, format!("invalid condition \"{}\", expected NAME, !NAME, NAME == value or NAME != value", argument)))
+                };
+                conditionals.push(Conditional{ line: number, parent_active: active, condition, in_else: false });
+            }
+            "#else" => match conditionals.last_mut() {
+                Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                Some(conditional) => return Err(error(number, format!("second #else for the #if at line {}", conditional.line))),
+                None => return Err(error(number, "#else without #if".to_string()))
+            },
+            "#endif" => if conditionals.pop().is_none() {
+                return Err(error(number, "#endif without #if".to_string()));
+            },
+            _ => return Err(error(number, format!("unknown directive \"{}\"", directive)))
+        }
+        lines.push(String::new());
+    }
+    if let Some(conditional) = conditionals.last() {
+        return Err(error(conditional.line, "#if without #endif".to_string()));
+    }
+
+    let mut res = lines.join("\n");
+    if content.ends_with('\n') {
+        res.push('\n');
+    }
+    Ok(res)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VisitState {
+    Visiting,
+    Done
+}
+
+impl Template {
+    pub fn load(path:&Path) -> Result<Self, TemplateError> {
+        let template_file = match File::open(path) {
+            Ok(ok) => ok,
+            Err(err) => {
+                return Err(TemplateError::IOError(path.to_path_buf(),err))
+            }
+        };
+        match from_reader(BufReader::new(template_file)) {
+            Ok(ok) => Ok(ok),
+            Err(err) => Err(TemplateError::ParseError(path.to_path_buf(), err))
+        }
+    }
+
+    /// Paths of the components and of their includes, relative to `root`
+    pub fn component_paths(&self, root:&Path) -> Vec<PathBuf> {
+        let components = match self.sorted_components(root, &mut ComponentCache::default()) {
+            Ok(ok) => ok.into_iter().map(|(_, path)| path).collect(),
+            Err(_) => self.components.iter().map(|component| normalize(Path::new(&component.path))).collect::<Vec<_>>()
+        };
+        components.into_iter().map(|path| root.join(path)).collect()
+    }
+
+    /// Components ordered so that every component comes after its includes, with their titles.
+    /// Included components missing from the template are titled with their file name.
+    fn sorted_components(&self, root:&Path, cache:&mut ComponentCache) -> Result<Vec<(String, PathBuf)>, TemplateError> {
+        let mut titles = HashMap::new();
+        for component in self.components.iter() {
+            titles.insert(normalize(Path::new(&component.path)), component.title.clone());
+        }
+
+        let mut states = HashMap::new();
+        let mut sorted = vec![];
+        for component in self.components.iter() {
+            let mut stack = vec![];
+            Self::visit(root, &normalize(Path::new(&component.path)), None, cache, &mut states, &mut stack, &mut sorted)?;
+        }
+
+        Ok(sorted.into_iter().map(|path| {
+            let title = match titles.get(&path) {
+                Some(title) => title.clone(),
+                None => path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+            };
+            (title, path)
+        }).collect())
+    }
+
+    // Depth first traversal of the includes, `stack` holds the current include chain to report cycles
+    fn visit(root:&Path, path:&Path, included_from:Option<&Path>, cache:&mut ComponentCache, states:&mut HashMap<PathBuf, VisitState>, stack:&mut Vec<PathBuf>, sorted:&mut Vec<PathBuf>) -> Result<(), TemplateError> {
+        match states.get(path) {
+            Some(VisitState::Done) => return Ok(()),
+            Some(VisitState::Visiting) => {
+                let start = stack.iter().position(|p| p == path).unwrap_or(0);
+                let mut cycle = stack[start..].to_vec();
+                cycle.push(path.to_path_buf());
+                return Err(TemplateError::DependencyCycle(cycle));
+            }
+            None => {}
+        }
+        states.insert(path.to_path_buf(), VisitState::Visiting);
+        stack.push(path.to_path_buf());
+
+        let includes = cache.load(root, path, included_from)?.includes.clone();
+        for include in includes.iter() {
+            Self::visit(root, include, Some(path), cache, states, stack, sorted)?;
+        }
+
+        stack.pop();
+        states.insert(path.to_path_buf(), VisitState::Done);
+        sorted.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// The main target, named `default`, followed by the variants with the template defines they don't override
+    pub fn targets(&self) -> Vec<TemplateTarget> {
+        let mut targets = vec![TemplateTarget{ name: "default".to_string(), target: self.target.clone(), defines: self.defines.clone() }];
+        for variant in self.variants.iter() {
+            let mut defines = self.defines.clone();
+            defines.extend(variant.defines.clone());
+            targets.push(TemplateTarget{ name: variant.name.clone(), target: variant.target.clone(), defines });
+        }
+        targets
+    }
+
+    /// Concatenate the components for the main target, the paths of the template are relative to `root`
+    pub fn assemble(&self, root:&Path) -> Result<AssembledShader, TemplateError> {
+        self.assemble_with(root, &mut ComponentCache::default())
+    }
+
+    /// Same as [`Template::assemble`] reusing the components already read by other templates
+    pub fn assemble_with(&self, root:&Path, cache:&mut ComponentCache) -> Result<AssembledShader, TemplateError> {
+        self.assemble_target(root, cache, &self.targets()[0])
+    }
+
+    /// Concatenate the components with the defines of `target`.
+    /// Includes are resolved before the `#if` blocks, so an include is always pulled in.
+    pub fn assemble_target(&self, root:&Path, cache:&mut ComponentCache, target:&TemplateTarget) -> Result<AssembledShader, TemplateError> {
+        let mut source = String::new();
+        let mut sections = vec![];
+        for (title, path) in self.sorted_components(root, cache)? {
+            source.push_str(&section_title(&title));
+            let content = preprocess(&path, &cache.load(root, &path, None)?.content, &target.defines)?;
+            sections.push(Section{
+                start: source.matches('\n').count() + 1,
+                line_count: content.lines().count(),
+                path
+            });
+            source.push_str(&content);
+        }
+        Ok(AssembledShader{ target: target.target.clone(), source, sections })
+    }
+}
+
+impl AssembledShader {
+    /// Component and line (1-based) that produced `line` of the generated shader
+    pub fn locate(&self, line:usize) -> Option<(&Path, usize)> {
+        self.sections.iter()
+            .find(|section| section.start <= line && line < section.start + section.line_count)
+            .map(|section| (section.path.as_path(), line - section.start + 1))
+    }
+
+    /// Line (1-based) of a byte offset in the generated shader
+    pub fn line_of(&self, offset:usize) -> usize {
+        self.source[..offset.min(self.source.len())].matches('\n').count() + 1
+    }
+
+    /// Position of a line of the generated shader in its components
+    fn describe_location(&self, line:usize, column:Option<usize>) -> String {
+        let (path, line) = match self.locate(line) {
+            Some((path, line)) => (path.display().to_string(), line),
+            None => (self.target.clone(), line)
+        };
+        match column {
+            Some(column) => format!("{}:{}:{}", path, line, column),
+            None => format!("{}:{}", path, line)
+        }
+    }
+
+    /// Parse and validate the shader with naga, errors point at the components rather than the generated shader
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        let module = match naga::front::wgsl::parse_str(&self.source) {
+            Ok(ok) => ok,
+            Err(err) => {
+                let (line, column) = err.location(&self.source);
+                let message = format!("{}: {}", self.describe_location(line, Some(column)), err);
+                return Err(TemplateError::ValidationError(self.target.clone(), message));
+            }
+        };
+
+        let mut validator = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::empty());
+        if let Err(err) = validator.validate(&module) {
+            let mut message = err.to_string();
+            let mut source = err.source();
+            while let Some(err) = source {
+                message.push_str(&format!(" : {}", err));
+                source = err.source();
+            }
+            for (span, description) in err.spans() {
+                if let Some(range) = span.to_range() {
+                    let line = self.line_of(range.start);
+                    message.push_str(&format!("\n    {}: {}", self.describe_location(line, None), description));
+                }
+            }
+            return Err(TemplateError::ValidationError(self.target.clone(), message));
+        }
+        Ok(())
+    }
+
+    /// Write the sections as json, so that lines of the generated shader can be traced back to the components
+    pub fn write_line_map(&self, path:&Path) -> Result<(), TemplateError> {
+        let file = match File::create(path) {
+            Ok(ok) => ok,
+            Err(err) => return Err(TemplateError::IOError(path.to_path_buf(), err))
+        };
+        match to_writer_pretty(BufWriter::new(file), &self.sections) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(TemplateError::LineMapError(path.to_path_buf(), err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Directory the tests never create, the components they use are already in the cache
+    const ROOT: &str = "/nonexistent/template/root";
+
+    fn template(paths: &[&str]) -> Template {
+        Template{
+            target: "out.wgsl".to_string(),
+            defines: BTreeMap::new(),
+            variants: vec![],
+            components: paths.iter().map(|path| TemplateComponent{ title: path.to_uppercase(), path: path.to_string() }).collect()
+        }
+    }
+
+    fn cache(files: &[(&str, &str)]) -> ComponentCache {
+        let mut cache = ComponentCache::default();
+        for (path, content) in files {
+            let path = normalize(Path::new(path));
+            let source = ComponentCache::parse_directives(&path, content).unwrap();
+            cache.sources.insert(path, source);
+        }
+        cache
+    }
+
+    fn sorted(template: &Template, cache: &mut ComponentCache) -> Result<Vec<String>, TemplateError> {
+        let components = template.sorted_components(Path::new(ROOT), cache)?;
+        Ok(components.into_iter().map(|(_, path)| path.display().to_string()).collect())
+    }
+
+    #[test]
+    fn includes_come_first() {
+        let mut cache = cache(&[
+            ("c/main.wgsl", "#include \"ray.wgsl\"\n#include \"../types.wgsl\"\nfn main(){};\n"),
+            ("c/ray.wgsl", "#include \"./../types.wgsl\"\nfn ray(){};\n"),
+            ("types.wgsl", "struct A { a: f32; };\n"),
+        ]);
+        // The order of the template is kept when it doesn't contradict the includes
+        let template = template(&["c/main.wgsl", "types.wgsl"]);
+        assert_eq!(sorted(&template, &mut cache).unwrap(), vec!["types.wgsl", "c/ray.wgsl", "c/main.wgsl"]);
+
+        let shader = template.assemble_with(Path::new(ROOT), &mut cache).unwrap();
+        assert!(shader.source.find("struct A").unwrap() < shader.source.find("fn ray").unwrap());
+        assert!(!shader.source.contains("#include"));
+        // Included components missing from the template are titled with their file name
+        assert!(shader.source.contains("// ray.wgsl"));
+        let main = &shader.sections[2];
+        assert_eq!(shader.locate(main.start + 2), Some((Path::new("c/main.wgsl"), 3)));
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let mut cache = cache(&[
+            ("a.wgsl", "#include \"b.wgsl\"\n"),
+            ("b.wgsl", "#include \"c.wgsl\"\n"),
+            ("c.wgsl", "#include \"a.wgsl\"\n"),
+        ]);
+        match sorted(&template(&["a.wgsl"]), &mut cache) {
+            Err(TemplateError::DependencyCycle(cycle)) => {
+                let cycle: Vec<_> = cycle.iter().map(|path| path.display().to_string()).collect();
+                assert_eq!(cycle, vec!["a.wgsl", "b.wgsl", "c.wgsl", "a.wgsl"]);
+            }
+            other => panic!("Expected a cycle, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn missing_components_name_their_includer() {
+        let mut cache = cache(&[("a.wgsl", "#include \"missing.wgsl\"\n")]);
+        match sorted(&template(&["a.wgsl"]), &mut cache) {
+            Err(TemplateError::MissingComponent(path, from, _)) => {
+                assert_eq!((path, from), (PathBuf::from("missing.wgsl"), PathBuf::from("a.wgsl")));
+            }
+            other => panic!("Expected a missing component, got {:?}", other)
+        }
+        // A component of the template itself is an IO error
+        assert!(matches!(sorted(&template(&["other.wgsl"]), &mut cache), Err(TemplateError::IOError(_, _))));
+    }
+
+    fn defines(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    /// Preprocessed lines of `content`, blank lines dropped
+    fn kept_lines(content: &str, pairs: &[(&str, &str)]) -> Vec<String> {
+        let res = preprocess(Path::new("a.wgsl"), content, &defines(pairs)).unwrap();
+        assert_eq!(res.lines().count(), content.lines().count());
+        res.lines().filter(|line| !line.is_empty()).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn nested_conditionals() {
+        let content = "#if A\na\n#if B\nab\n#else\na_not_b\n#endif\n#else\nnot_a\n#if B\nnot_a_b\n#endif\n#endif\nall";
+        assert_eq!(kept_lines(content, &[("A", "1"), ("B", "1")]), vec!["a", "ab", "all"]);
+        assert_eq!(kept_lines(content, &[("A", "1"), ("B", "0")]), vec!["a", "a_not_b", "all"]);
+        // Undefined names are false
+        assert_eq!(kept_lines(content, &[("B", "true")]), vec!["not_a", "not_a_b", "all"]);
+    }
+
+    #[test]
+    fn conditions() {
+        let defines = defines(&[("ON", "1"), ("OFF", "false"), ("MODE", "flat")]);
+        let cases = [
+            ("ON", true), ("OFF", false), ("UNDEFINED", false), ("!ON", false), ("! OFF", true),
+            ("MODE == flat", true), ("MODE==full", false), ("MODE != full", true), ("UNDEFINED == ", true)
+        ];
+        for (condition, expected) in cases {
+            assert_eq!(evaluate_condition(condition, &defines), Some(expected), "{}", condition);
+        }
+        for condition in ["", "A B", "!", "1 + 1 == 2"] {
+            assert_eq!(evaluate_condition(condition, &defines), None, "{}", condition);
+        }
+    }
+
+    #[test]
+    fn defines_replace_whole_words() {
+        let defines = defines(&[("DEPTH", "20u"), ("A", "b")]);
+        assert_eq!(substitute("var s: array<f32, DEPTH>; // DEPTH_MAX A_B", &defines), "var s: array<f32, 20u>; // DEPTH_MAX A_B");
+        assert_eq!(substitute("A+A*xA", &defines), "b+b*xA");
+    }
+
+    #[test]
+    fn unbalanced_directives_are_refused() {
+        let line_of = |content: &str| match preprocess(Path::new("a.wgsl"), content, &BTreeMap::new()) {
+            Err(TemplateError::DirectiveError(_, line, _)) => line,
+            other => panic!("Expected a directive error, got {:?}", other)
+        };
+        assert_eq!(line_of("a\n#endif"), 2);
+        assert_eq!(line_of("#else"), 1);
+        assert_eq!(line_of("#if A\n#else\n#else\n#endif"), 3);
+        assert_eq!(line_of("a\n#if A\nb"), 2);
+        assert_eq!(line_of("#if A B\n#endif"), 1);
+        assert_eq!(line_of("#define A 1"), 1);
+    }
+
+    #[test]
+    fn malformed_includes_are_refused() {
+        let result = ComponentCache::parse_directives(Path::new("a.wgsl"), "fn a(){};\n#include types.wgsl\n");
+        assert!(matches!(result, Err(TemplateError::DirectiveError(_, 2, _))));
+    }
+}
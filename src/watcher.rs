@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Minimum time between two checks of the watched files
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Watches files by polling their modification time
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    last_check: Instant
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let mut watcher = Self{ files: vec![], last_check: Instant::now() };
+        watcher.set_paths(paths);
+        watcher
+    }
+
+    fn modified(path: &PathBuf) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    /// Replace the watched files, their current state is the new reference
+    pub fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        self.files = paths.into_iter().map(|path| {
+            let modified = Self::modified(&path);
+            (path, modified)
+        }).collect();
+    }
+
+    /// Whether any of the files changed since the last call
+    pub fn poll(&mut self) -> bool {
+        if self.last_check.elapsed() < WATCH_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        let mut changed = false;
+        for (path, last_modified) in self.files.iter_mut() {
+            let modified = Self::modified(path);
+            // The file may briefly disappear while an editor saves it
+            if modified.is_some() && modified != *last_modified {
+                *last_modified = modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}